@@ -0,0 +1,64 @@
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tokio::time::Instant;
+
+/// The size of the buffer the files are read with while hashing.
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A file of a downloaded video.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// The absolute path of the file.
+    pub path: PathBuf,
+    /// The size in bytes.
+    pub size: u64,
+    /// The hex encoded SHA-256 of the file.
+    pub sha256: String,
+}
+
+impl FileInfo {
+    /// Gets the size and hash of the file.
+    pub async fn new(path: &Path) -> Result<Self> {
+        let path = &tokio::fs::canonicalize(path)
+            .await
+            .map_err(DownloadFileError::Canonicalization)?;
+        let size = tokio::fs::metadata(path)
+            .await
+            .map_err(DownloadFileError::Read)?
+            .len();
+        let sha256 = sha256_file(path).await?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size,
+            sha256,
+        })
+    }
+}
+
+/// Computes the SHA-256 of a file without reading it into memory at once.
+#[tracing::instrument]
+pub async fn sha256_file(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let start = Instant::now();
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(DownloadFileError::Read)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(DownloadFileError::Read)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    let hash = format!("{:x}", hasher.finalize());
+    debug!("hashed {:?} in {:?}", path, start.elapsed());
+    Ok(hash)
+}
@@ -1,101 +1,1860 @@
+use crate::checksum::FileInfo;
+use crate::config::{validate_download_folder, DownloadOrder, DownloaderConfig, OutputContainer};
+use crate::downloader::VideoDownloader;
+use crate::errors::{DownloadFileError, VideoIdError};
+use crate::metadata::DownloadMetadata;
+use crate::metrics::metrics;
+use crate::notifications::{Notification, Notifier};
 use crate::prelude::*;
-use crate::twitch::TwitchClient;
-use std::path::Path;
+use crate::state;
+use crate::storage::Storage;
+use crate::summary::{Outcome, RunSummary, VideoOutcome};
+use crate::twitch::bandwidth::ByteBudget;
+use crate::twitch::clip;
+use crate::twitch::progress::{format_bytes, format_duration, DownloadPhase};
+use crate::twitch::twitch_utils::parse_video_id;
+use crate::twitch::{existing_split_files, DownloadOptions, DownloadedVideo, TwitchClient};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::sea_query::Expr;
 use twba_local_db::re_exports::sea_orm::ActiveValue::Set;
 use twba_local_db::re_exports::sea_orm::{
     ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
-    QueryOrder, QuerySelect,
+    QueryOrder,
 };
 
+/// Everything that is known about the download of a single video.
+#[derive(Debug, Clone)]
+pub struct VideoStatus {
+    pub video: VideosModel,
+    /// Where the final video is or will be.
+    pub final_path: PathBuf,
+    pub final_exists: bool,
+    /// Where the parts are downloaded to.
+    pub parts_folder: PathBuf,
+    pub parts_folder_exists: bool,
+    pub attempts: u32,
+    pub permanently_failed: bool,
+}
+
+/// What was (or would be) removed by [`DownloaderClient::cleanup`].
+#[derive(Debug, Clone, Default)]
+pub struct CleanupReport {
+    pub removed: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+/// Downloads the videos that are queued in the database and keeps their status up to date.
 #[derive(Debug)]
-pub struct DownloaderClient {
+pub struct DownloaderClient<D: VideoDownloader = TwitchClient> {
     db: DatabaseConnection,
-    pub twitch_client: TwitchClient,
+    pub downloader: D,
+    cancel: CancellationToken,
+    notifier: Notifier,
+    /// The quality the queued videos are downloaded in.
+    quality: String,
+    /// Where the downloaded videos are uploaded to, if anywhere.
+    storage: Option<Storage>,
+    /// The bytes downloaded during this run, for `max_bytes_per_run`.
+    byte_budget: Arc<ByteBudget>,
+}
+
+/// Builds a [`DownloaderClient`] that downloads with a [`TwitchClient`].
+///
+/// The database has to be opened (and migrated) by the caller, the tables of
+/// the downloader itself are created by [`DownloaderClientBuilder::build`].
+pub struct DownloaderClientBuilder {
+    db: DatabaseConnection,
+    conf: Conf,
+    settings: Option<DownloaderConfig>,
+    output_folder: Option<PathBuf>,
+    quality: Option<String>,
+}
+
+/// The config contains secrets, so it is not shown.
+impl std::fmt::Debug for DownloaderClientBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DownloaderClientBuilder")
+            .field("output_folder", &self.output_folder)
+            .field("quality", &self.quality)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DownloaderClientBuilder {
+    pub fn new(db: DatabaseConnection, conf: Conf) -> Self {
+        Self {
+            db,
+            conf,
+            settings: None,
+            output_folder: None,
+            quality: None,
+        }
+    }
+
+    /// The downloader specific settings. Uses the defaults if not set.
+    pub fn settings(mut self, settings: DownloaderConfig) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Downloads into this folder instead of the one from the config.
+    pub fn output_folder(mut self, output_folder: impl Into<PathBuf>) -> Self {
+        self.output_folder = Some(output_folder.into());
+        self
+    }
+
+    /// The quality the queued videos are downloaded in (e.g. `1080p60`).
+    /// Uses the highest quality or the configured preferences if not set.
+    pub fn quality(mut self, quality: impl Into<String>) -> Self {
+        self.quality = Some(quality.into());
+        self
+    }
+
+    /// Checks the output folder and creates the client.
+    pub async fn build(self) -> Result<DownloaderClient> {
+        let mut conf = self.conf;
+        if let Some(output_folder) = self.output_folder {
+            validate_download_folder(&output_folder)?;
+            conf.download_folder_path = output_folder.to_string_lossy().to_string();
+        }
+        state::init(&self.db).await?;
+        let settings = self.settings.unwrap_or_default();
+        let storage = Storage::from_config(&settings.storage)?;
+        let twitch_client = TwitchClient::new(conf, settings)?;
+        let mut client = DownloaderClient::new(twitch_client, self.db);
+        client.storage = storage;
+        if let Some(quality) = self.quality {
+            client.quality = quality;
+        }
+        Ok(client)
+    }
 }
 
-impl DownloaderClient {
-    pub fn new(twitch_client: TwitchClient, db: DatabaseConnection) -> Self {
-        Self { twitch_client, db }
+impl<D: VideoDownloader> DownloaderClient<D> {
+    pub fn new(downloader: D, db: DatabaseConnection) -> Self {
+        let notifier = Notifier::new(&downloader.settings().notifications);
+        let max_bytes_per_run = downloader.settings().max_bytes_per_run;
+        if let Some(limit) = max_bytes_per_run {
+            info!("Downloading at most {} in this run", format_bytes(limit));
+        }
+        Self {
+            byte_budget: Arc::new(ByteBudget::new(max_bytes_per_run)),
+            downloader,
+            db,
+            cancel: CancellationToken::new(),
+            notifier,
+            quality: "max".to_string(),
+            storage: None,
+        }
+    }
+
+    pub fn db(&self) -> &DatabaseConnection {
+        &self.db
+    }
+
+    /// A token that stops the current download when cancelled.
+    ///
+    /// The video is reset to [`Status::NotStarted`] and no further videos are downloaded.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
     }
     #[tracing::instrument(skip(self))]
-    pub async fn download_not_downloaded_videos(&self) -> Result<()> {
+    pub async fn download_not_downloaded_videos(&self) -> Result<RunSummary> {
         info!("Downloading not downloaded videos");
+        self.download_queued_videos(None).await
+    }
+
+    /// Downloads the queued videos of a single user, like
+    /// [`Self::download_not_downloaded_videos`] does for all users.
+    #[tracing::instrument(skip(self))]
+    pub async fn download_videos_for_user(&self, user_id: i32) -> Result<RunSummary> {
+        info!("Downloading not downloaded videos of user {}", user_id);
+        self.download_queued_videos(Some(user_id)).await
+    }
+
+    /// Finds a user by its id or its login.
+    pub async fn find_user(&self, login_or_id: &str) -> Result<UsersModel> {
+        let user = match login_or_id.parse::<i32>() {
+            Ok(id) => Users::find_by_id(id).one(&self.db).await?,
+            Err(_) => {
+                Users::find()
+                    .filter(UsersColumn::TwitchName.eq(login_or_id))
+                    .one(&self.db)
+                    .await?
+            }
+        };
+        user.ok_or_else(|| DownloaderError::UnknownUser(login_or_id.to_string()))
+    }
+
+    /// Downloads the queued videos, only those of one user if `user_id` is set.
+    async fn download_queued_videos(&self, user_id: Option<i32>) -> Result<RunSummary> {
         let output_folder: &Path =
-            Path::new(self.twitch_client.config.download_folder_path.as_str());
+            Path::new(self.downloader.config().download_folder_path.as_str());
+        self.skip_too_old_videos().await?;
+        self.retry_pending_uploads().await?;
+        let (videos, permanently_failed) = self.get_videos_to_download(user_id).await?;
+        info!("Found {} videos to download", videos.len());
+        let mut summary = RunSummary::new();
+        summary.permanently_failed = permanently_failed;
+        let mut result = Ok(());
+
+        let mut videos = videos.into_iter();
+        for video in videos.by_ref() {
+            let id = video.id;
+            let title = video.name.clone();
+            let (quality, thread_count) = self.download_settings_for(&video).await?;
+            info!(
+                "Downloading video {} in quality {} with {} threads",
+                id, quality, thread_count
+            );
+            let success = self
+                .download_video(video, &quality, Some(thread_count), output_folder)
+                .await;
+            let mut stop = false;
+            let outcome = match success {
+                Ok(downloaded) => {
+                    info!("Downloaded video with id: {}", id);
+                    VideoOutcome {
+                        id,
+                        title,
+                        outcome: Outcome::Downloaded,
+                        bytes: files_size(&downloaded.files()).await,
+                        duration: Some(downloaded.info.total_duration),
+                        error: None,
+                    }
+                }
+                Err(err) => {
+                    let outcome = match &err {
+                        DownloaderError::File(DownloadFileError::InsufficientDiskSpace {
+                            ..
+                        }) => {
+                            warn!("Not enough disk space left. Not downloading any more videos.");
+                            stop = true;
+                            Outcome::Skipped
+                        }
+                        DownloaderError::Cancelled => {
+                            warn!("The download was cancelled. Not downloading any more videos.");
+                            stop = true;
+                            Outcome::Cancelled
+                        }
+                        DownloaderError::ByteCapReached { .. } => {
+                            info!("Reached the byte limit of this run. Not downloading any more videos.");
+                            stop = true;
+                            Outcome::Skipped
+                        }
+                        DownloaderError::StatusChanged(_) => {
+                            info!("Video with id: {} is handled by something else", id);
+                            Outcome::Skipped
+                        }
+                        err if err.is_retry_later() => {
+                            info!("Video with id: {} will be tried again next run", id);
+                            Outcome::Skipped
+                        }
+                        _ => {
+                            error!(
+                                "Could not download video with id: {} because of err: {:?}",
+                                id, err
+                            );
+                            Outcome::Failed
+                        }
+                    };
+                    VideoOutcome {
+                        id,
+                        title,
+                        outcome,
+                        bytes: None,
+                        duration: None,
+                        error: Some(err.to_string()),
+                    }
+                }
+            };
+            if outcome.outcome == Outcome::Cancelled {
+                result = Err(DownloaderError::Cancelled);
+            }
+            summary.push(outcome);
+            if !stop && self.byte_budget.is_reached() {
+                info!("Reached the byte limit of this run. Not downloading any more videos.");
+                stop = true;
+            }
+            if stop {
+                break;
+            }
+        }
+        for video in videos {
+            summary.push(VideoOutcome {
+                id: video.id,
+                title: video.name,
+                outcome: Outcome::Skipped,
+                bytes: None,
+                duration: None,
+                error: None,
+            });
+        }
+        summary.bytes_downloaded = self.byte_budget.used();
+        summary.max_bytes_per_run = self.byte_budget.limit();
+        summary.throttled_requests = self.downloader.throttled_requests();
+        summary.finish();
+        info!("Finished downloading videos");
+        summary.log();
+        if let Some(path) = &self.downloader.settings().summary_path {
+            if let Err(err) = summary.write(path).await {
+                warn!("Could not write the run summary to {:?}: {:?}", path, err);
+            }
+        }
+        self.notifier
+            .notify(&Notification::RunFinished {
+                downloaded: summary.count(Outcome::Downloaded),
+                failed: summary.count(Outcome::Failed),
+                skipped: summary.count(Outcome::Skipped) + summary.count(Outcome::Cancelled),
+                permanently_failed: summary.permanently_failed.len(),
+            })
+            .await;
+        result?;
+        if !summary.permanently_failed.is_empty() {
+            warn!(
+                "{} videos failed permanently or {} times and will not be retried: {:?}",
+                summary.permanently_failed.len(),
+                self.downloader.settings().max_download_attempts,
+                summary.permanently_failed
+            );
+        }
+
+        Ok(summary)
+    }
+
+    /// Fixes videos that are stuck in [`Status::Downloading`] because a previous run crashed.
+    ///
+    /// Every video is reset so it gets downloaded again. A final file that was
+    /// left behind is not trusted as it is: the next download checks it (and
+    /// finishes a split that was interrupted) and stores its checksums and
+    /// metadata like for any other download. The leftover folder is kept, the
+    /// next download resumes from whatever can still be used in it.
+    #[tracing::instrument(skip(self))]
+    pub async fn recover_stale_downloads(&self) -> Result<()> {
+        let output_folder = Path::new(self.downloader.config().download_folder_path.as_str());
         let videos = Videos::find()
-            .filter(VideosColumn::Status.eq(Status::NotStarted))
-            .order_by_asc(VideosColumn::CreatedAt)
-            .limit(self.twitch_client.config.max_items_to_process)
+            .filter(VideosColumn::Status.eq(Status::Downloading))
             .all(&self.db)
             .await?;
-        info!("Found {} videos to download", videos.len());
-
         for video in videos {
             let id = video.id;
-            let quality = "max";
-            let success = self.download_video(video, quality, output_folder).await;
-            if let Err(err) = success {
-                error!(
-                    "Could not download video with id: {} because of err: {:?}",
-                    id, err
+            let output_folder = &self.output_folder_for(&video, output_folder).await?;
+            let final_path = self.final_path(output_folder, id);
+            let folder_path = self
+                .downloader
+                .settings()
+                .parts_folder(output_folder, &id.to_string());
+            if final_path.exists() || !existing_split_files(&final_path).is_empty() {
+                info!(
+                    "Video {} was left in Downloading but {:?} exists, it is checked by the next download",
+                    id, final_path
+                );
+            } else if folder_path.exists() {
+                info!(
+                    "Video {} was left in Downloading, keeping {:?} to resume",
+                    id, folder_path
+                );
+            }
+            info!("Resetting video {} to NotStarted", id);
+            self.update_status(id, Status::Downloading, Status::NotStarted, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Updates the title and duration of the video with the current data from twitch.
+    ///
+    /// A video that does not exist anymore is marked as permanently failed.
+    async fn refresh_video_info(&self, video: VideosModel) -> Result<VideosModel> {
+        let info = match self.downloader.get_video_info(&video.twitch_id).await {
+            Ok(Some(info)) => info,
+            Ok(None) => return Ok(video),
+            Err(err @ DownloaderError::VideoNotFound(_)) => {
+                warn!("Video {} does not exist on twitch anymore", video.id);
+                state::mark_permanently_failed(&self.db, video.id).await?;
+                self.update_status(
+                    video.id,
+                    video.status,
+                    Status::Failed,
+                    Some(err.to_string()),
+                )
+                .await?;
+                metrics().videos_failed.inc();
+                return Err(err);
+            }
+            Err(err) => {
+                warn!(
+                    "Could not refresh the info of video {}, using the existing data: {:?}",
+                    video.id, err
                 );
+                return Ok(video);
+            }
+        };
+        debug!(
+            "refreshed video {}: {:?} by {}, created at {}",
+            video.id, info.title, info.user_login, info.created_at
+        );
+        state::set_value(&self.db, video.id, state::KEY_VIEW_COUNT, info.view_count).await?;
+        let mut video = video.into_active_model();
+        video.name = Set(info.title);
+        video.duration = Set(info.duration as i32);
+        Ok(video.update(&self.db).await?)
+    }
+
+    /// Changes the status of a video, but only if it is still `from` in the database.
+    ///
+    /// Returns false if something else changed the video since it was read, in that
+    /// case the row is left as it is and the conflict is logged.
+    /// Moves that are not allowed by [`can_transition`] fail without touching the row.
+    async fn update_status(
+        &self,
+        id: i32,
+        from: Status,
+        to: Status,
+        fail_reason: Option<String>,
+    ) -> Result<bool> {
+        self.update_status_and_duration(id, from, to, fail_reason, None)
+            .await
+    }
+
+    /// Changes the status like [`Self::update_status`] and stores the measured
+    /// duration (in seconds) of the video in the same update.
+    async fn update_status_with_duration(
+        &self,
+        id: i32,
+        from: Status,
+        to: Status,
+        duration: Option<f32>,
+    ) -> Result<bool> {
+        let duration = duration.map(|duration| duration.round() as i32);
+        self.update_status_and_duration(id, from, to, None, duration)
+            .await
+    }
+
+    async fn update_status_and_duration(
+        &self,
+        id: i32,
+        from: Status,
+        to: Status,
+        fail_reason: Option<String>,
+        duration: Option<i32>,
+    ) -> Result<bool> {
+        if !can_transition(from.clone(), to.clone()) {
+            return Err(DownloaderError::InvalidStatusTransition {
+                video_id: id,
+                from,
+                to,
+            });
+        }
+        let expected = format!("{:?}", from);
+        let wanted = format!("{:?}", to);
+        let mut update = Videos::update_many()
+            .col_expr(VideosColumn::Status, Expr::value(to))
+            .col_expr(VideosColumn::FailReason, Expr::value(fail_reason));
+        if let Some(duration) = duration {
+            update = update.col_expr(VideosColumn::Duration, Expr::value(duration));
+        }
+        let result = update
+            .filter(VideosColumn::Id.eq(id))
+            .filter(VideosColumn::Status.eq(from))
+            .exec(&self.db)
+            .await?;
+        if result.rows_affected > 0 {
+            trace!(
+                "changed the status of video {} from {} to {}",
+                id,
+                expected,
+                wanted
+            );
+            return Ok(true);
+        }
+        let current = Videos::find_by_id(id)
+            .one(&self.db)
+            .await?
+            .map(|video| format!("{:?}", video.status));
+        warn!(
+            "Not changing the status of video {} to {}: expected it to be {} but it is {}",
+            id,
+            wanted,
+            expected,
+            current.as_deref().unwrap_or("deleted")
+        );
+        Ok(false)
+    }
+
+    /// The folder the video should be downloaded to.
+    ///
+    /// This is `<download_folder>/<user_login>` if the downloads should be
+    /// organized by user and the download folder itself otherwise.
+    async fn output_folder_for(
+        &self,
+        video: &VideosModel,
+        download_folder: &Path,
+    ) -> Result<PathBuf> {
+        if !self.downloader.settings().organize_by_user {
+            return Ok(download_folder.to_path_buf());
+        }
+        let user = Users::find_by_id(video.user_id)
+            .one(&self.db)
+            .await?
+            .ok_or(DownloaderError::UserNotFound(video.user_id))?;
+        let folder = download_folder.join(&user.twitch_name);
+        if !folder.exists() {
+            tokio::fs::create_dir_all(&folder)
+                .await
+                .map_err(DownloadFileError::CouldNotCreateTargetFolder)?;
+        }
+        Ok(folder)
+    }
+
+    /// Where the video is saved, in the configured container.
+    fn final_path(&self, output_folder: &Path, id: i32) -> PathBuf {
+        let container = self.downloader.settings().twitch.output_container;
+        output_folder.join(format!("{}.{}", id, container.extension()))
+    }
+
+    /// Gets all videos that are waiting for, in or done with the download
+    /// (or failed it), oldest first.
+    pub async fn list_videos(&self) -> Result<Vec<VideosModel>> {
+        Ok(Videos::find()
+            .filter(VideosColumn::Status.is_in([
+                Status::NotStarted,
+                Status::Downloading,
+                Status::Downloaded,
+                Status::Failed,
+            ]))
+            .order_by_asc(VideosColumn::CreatedAt)
+            .all(&self.db)
+            .await?)
+    }
+
+    /// Gets the status of a single video, including whether its files exist.
+    pub async fn video_status(&self, id: i32) -> Result<Option<VideoStatus>> {
+        let Some(video) = Videos::find_by_id(id).one(&self.db).await? else {
+            return Ok(None);
+        };
+        let download_folder = Path::new(self.downloader.config().download_folder_path.as_str());
+        let output_folder = self.output_folder_for(&video, download_folder).await?;
+        let final_path = self.final_path(&output_folder, id);
+        let parts_folder = self
+            .downloader
+            .settings()
+            .parts_folder(&output_folder, &id.to_string());
+        Ok(Some(VideoStatus {
+            final_exists: final_path.is_file(),
+            final_path,
+            parts_folder_exists: parts_folder.is_dir(),
+            parts_folder,
+            attempts: state::get_attempts(&self.db, id).await?,
+            permanently_failed: state::is_permanently_failed(&self.db, id).await?,
+            video,
+        }))
+    }
+
+    /// Resets all failed videos, so they are downloaded again.
+    ///
+    /// This also resets their failed attempts and pending uploads. Returns the
+    /// amount of reset videos.
+    pub async fn reset_failed(&self) -> Result<usize> {
+        let videos = Videos::find()
+            .filter(VideosColumn::Status.eq(Status::Failed))
+            .all(&self.db)
+            .await?;
+        let count = videos.len();
+        for video in videos {
+            let id = video.id;
+            state::remove_value(&self.db, id, state::KEY_ATTEMPTS).await?;
+            state::remove_value(&self.db, id, state::KEY_PERMANENTLY_FAILED).await?;
+            // the video is downloaded again, which uploads it again as well
+            state::remove_value(&self.db, id, state::KEY_UPLOAD_PENDING).await?;
+            if self
+                .update_status(id, Status::Failed, Status::NotStarted, None)
+                .await?
+            {
+                debug!("reset video {}", id);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Checks that the files of a downloaded video still have the hashes
+    /// they had right after the download.
+    ///
+    /// Returns the files that are missing or changed.
+    #[tracing::instrument(skip(self))]
+    pub async fn verify(&self, id: i32) -> Result<Vec<PathBuf>> {
+        let files = state::get_value(&self.db, id, state::KEY_FILES)
+            .await?
+            .ok_or(DownloaderError::NoChecksums(id))?;
+        let files: Vec<FileInfo> =
+            serde_json::from_str(&files).map_err(|_| DownloaderError::NoChecksums(id))?;
+        let mut invalid = vec![];
+        for file in files {
+            let valid = match FileInfo::new(&file.path).await {
+                Ok(current) => current == file,
+                Err(err) => {
+                    warn!("Could not read {:?}: {:?}", file.path, err);
+                    false
+                }
+            };
+            if valid {
+                debug!("{:?} is valid", file.path);
             } else {
-                info!("Downloaded video with id: {}", id);
+                warn!("{:?} is missing or was changed", file.path);
+                invalid.push(file.path);
             }
         }
-        info!("Finished downloading videos");
+        Ok(invalid)
+    }
+
+    /// Removes leftover part folders and temporary files from the download
+    /// folder and the temp folder.
+    ///
+    /// Part folders are only removed if their video failed, was not started or
+    /// already has its final file. Folders of unknown videos and the final
+    /// videos themselves are never touched. With `dry_run` nothing is removed.
+    #[tracing::instrument(skip(self))]
+    pub async fn cleanup(&self, dry_run: bool) -> Result<CleanupReport> {
+        let download_folder = PathBuf::from(&self.downloader.config().download_folder_path);
+        let mut report = CleanupReport::default();
+        if let Some(temp_folder) = &self.downloader.settings().temp_folder_path {
+            if temp_folder.is_dir() {
+                self.cleanup_temp_folder(temp_folder, &download_folder, dry_run, &mut report)
+                    .await?;
+            }
+        }
+        let mut folders = vec![download_folder];
+        while let Some(folder) = folders.pop() {
+            let entries = std::fs::read_dir(&folder).map_err(DownloadFileError::Read)?;
+            for entry in entries {
+                let path = entry.map_err(DownloadFileError::Read)?.path();
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                if path.is_dir() {
+                    match name.parse::<i32>() {
+                        Ok(id) => {
+                            if self.is_removable_part_folder(id, &folder).await? {
+                                self.cleanup_path(path, dry_run, &mut report).await?;
+                            }
+                        }
+                        // the videos of a user are in a folder with the name of the user
+                        Err(_) if self.downloader.settings().organize_by_user => folders.push(path),
+                        Err(_) => {}
+                    }
+                } else if is_temporary_file(&name) {
+                    let id = name.split('.').next().and_then(|id| id.parse::<i32>().ok());
+                    let downloading = match id {
+                        Some(id) => Videos::find_by_id(id)
+                            .one(&self.db)
+                            .await?
+                            .is_some_and(|video| video.status == Status::Downloading),
+                        None => false,
+                    };
+                    if !downloading {
+                        self.cleanup_path(path, dry_run, &mut report).await?;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Removes the part folders from the temp folder that are not needed anymore.
+    async fn cleanup_temp_folder(
+        &self,
+        temp_folder: &Path,
+        download_folder: &Path,
+        dry_run: bool,
+        report: &mut CleanupReport,
+    ) -> Result<()> {
+        let entries = std::fs::read_dir(temp_folder).map_err(DownloadFileError::Read)?;
+        for entry in entries {
+            let path = entry.map_err(DownloadFileError::Read)?.path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_string_lossy().parse::<i32>().ok());
+            let Some(id) = id.filter(|_| path.is_dir()) else {
+                continue;
+            };
+            let Some(video) = Videos::find_by_id(id).one(&self.db).await? else {
+                debug!("keeping the folder of unknown video {}", id);
+                continue;
+            };
+            let output_folder = self.output_folder_for(&video, download_folder).await?;
+            if self.is_removable_part_folder(id, &output_folder).await? {
+                self.cleanup_path(path, dry_run, report).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the part folder of a video is not needed anymore.
+    ///
+    /// `folder` is the folder the final video of it is saved in.
+    async fn is_removable_part_folder(&self, id: i32, folder: &Path) -> Result<bool> {
+        let Some(video) = Videos::find_by_id(id).one(&self.db).await? else {
+            debug!("keeping the folder of unknown video {}", id);
+            return Ok(false);
+        };
+        Ok(match video.status {
+            Status::Failed | Status::NotStarted => true,
+            _ => self.final_path(folder, id).is_file(),
+        })
+    }
+
+    async fn cleanup_path(
+        &self,
+        path: PathBuf,
+        dry_run: bool,
+        report: &mut CleanupReport,
+    ) -> Result<()> {
+        let size = disk_usage(&path);
+        if dry_run {
+            info!("Would remove {:?} ({})", path, format_bytes(size));
+        } else {
+            info!("Removing {:?} ({})", path, format_bytes(size));
+            if path.is_dir() {
+                tokio::fs::remove_dir_all(&path)
+                    .await
+                    .map_err(DownloadFileError::Filesystem)?;
+            } else {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .map_err(DownloadFileError::Filesystem)?;
+            }
+        }
+        report.bytes += size;
+        report.removed.push(path);
+        Ok(())
+    }
+
+    /// Goes through the same steps as [`Self::download_not_downloaded_videos`]
+    /// without downloading anything or changing the database.
+    #[tracing::instrument(skip(self))]
+    pub async fn plan_downloads(&self) -> Result<()> {
+        let (videos, _) = self.get_videos_to_download(None).await?;
+        info!("Would download {} videos", videos.len());
+        for video in videos {
+            let (quality, _) = self.download_settings_for(&video).await?;
+            match self
+                .downloader
+                .inspect_video(&video.twitch_id, &quality)
+                .await
+            {
+                Ok(info) => info!(
+                    "video {} ({}): quality: {}, parts: {}, duration: {}, estimated size: {}",
+                    video.id,
+                    video.twitch_id,
+                    info.quality,
+                    info.part_count,
+                    format_duration(info.total_duration as u64),
+                    format_bytes(info.estimated_size(self.downloader.settings()))
+                ),
+                Err(err) => error!(
+                    "Could not plan the download of video {} ({}): {:?}",
+                    video.id, video.twitch_id, err
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks videos that are older than the configured maximum age as
+    /// permanently failed, since twitch has most likely deleted them already.
+    async fn skip_too_old_videos(&self) -> Result<()> {
+        let max_days = match self.downloader.settings().max_vod_age_days {
+            Some(max_days) if max_days > 0 => max_days,
+            _ => return Ok(()),
+        };
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(max_days as i64);
+        info!("Skipping videos created before {}", cutoff);
+        let candidates = Videos::find()
+            .filter(VideosColumn::Status.is_in([Status::NotStarted, Status::Failed]))
+            .all(&self.db)
+            .await?;
+        let mut skipped = 0;
+        for video in candidates {
+            let Some(created_at) = parse_created_at(&video.created_at) else {
+                warn!(
+                    "Could not parse the creation time of video {}: {:?}",
+                    video.id, video.created_at
+                );
+                continue;
+            };
+            if created_at >= cutoff {
+                continue;
+            }
+            let err = DownloaderError::VideoTooOld {
+                age_days: (chrono::Utc::now() - created_at).num_days(),
+                max_days,
+            };
+            debug!("skipping video {}: {}", video.id, err);
+            state::mark_permanently_failed(&self.db, video.id).await?;
+            if self
+                .update_status(
+                    video.id,
+                    video.status,
+                    Status::Failed,
+                    Some(err.to_string()),
+                )
+                .await?
+            {
+                skipped += 1;
+            }
+        }
+        if skipped > 0 {
+            info!("Skipped {} videos that are too old", skipped);
+        }
+        Ok(())
+    }
+
+    /// Gets the videos that should be downloaded next and the ids of the
+    /// failed videos that will not be retried anymore.
+    ///
+    /// Only the videos of one user are taken if `user_id` is set.
+    async fn get_videos_to_download(
+        &self,
+        user_id: Option<i32>,
+    ) -> Result<(Vec<VideosModel>, Vec<i32>)> {
+        let settings = self.downloader.settings();
+        let max_attempts = settings.max_download_attempts;
+        let mut query =
+            Videos::find().filter(VideosColumn::Status.is_in([Status::NotStarted, Status::Failed]));
+        if let Some(user_id) = user_id {
+            query = query.filter(VideosColumn::UserId.eq(user_id));
+        }
+        let query = match settings.download_order {
+            DownloadOrder::NewestFirst => query.order_by_desc(VideosColumn::CreatedAt),
+            DownloadOrder::OldestFirst | DownloadOrder::ByUserPriority => {
+                query.order_by_asc(VideosColumn::CreatedAt)
+            }
+        };
+        let mut candidates = query.all(&self.db).await?;
+        if !settings.download_inactive_users {
+            let active: HashSet<i32> = Users::find()
+                .filter(UsersColumn::Active.eq(true))
+                .all(&self.db)
+                .await?
+                .into_iter()
+                .map(|user| user.id)
+                .collect();
+            let count = candidates.len();
+            candidates.retain(|video| active.contains(&video.user_id));
+            if candidates.len() < count {
+                info!(
+                    "Not downloading {} videos of inactive or unknown users",
+                    count - candidates.len()
+                );
+            }
+        }
+        if settings.download_order == DownloadOrder::ByUserPriority {
+            let priorities: HashMap<i32, i32> = Users::find()
+                .all(&self.db)
+                .await?
+                .into_iter()
+                .map(|user| {
+                    let priority = settings
+                        .user_priorities
+                        .get(&user.twitch_name)
+                        .copied()
+                        .unwrap_or_default();
+                    (user.id, priority)
+                })
+                .collect();
+            // stable, so videos with the same priority stay oldest first
+            candidates.sort_by_key(|video| {
+                std::cmp::Reverse(priorities.get(&video.user_id).copied().unwrap_or_default())
+            });
+        }
+        let mut videos = vec![];
+        let mut permanently_failed = vec![];
+        for video in candidates {
+            if video.status == Status::Failed
+                && state::is_upload_pending(&self.db, video.id).await?
+            {
+                // the upload is retried on its own, without downloading again
+                continue;
+            }
+            if video.status == Status::Failed
+                && (state::is_permanently_failed(&self.db, video.id).await?
+                    || state::get_attempts(&self.db, video.id).await? >= max_attempts)
+            {
+                permanently_failed.push(video.id);
+                continue;
+            }
+            if (videos.len() as u64) < self.downloader.config().max_items_to_process {
+                videos.push(video);
+            }
+        }
+        Ok((videos, permanently_failed))
+    }
 
+    /// The quality and thread count a video is downloaded with, taking the
+    /// overrides of its user into account.
+    async fn download_settings_for(&self, video: &VideosModel) -> Result<(String, u64)> {
+        let overrides = &self.downloader.settings().user_overrides;
+        let mut quality = self.quality.clone();
+        let mut thread_count = self.downloader.config().twitch.downloader_thread_count;
+        if overrides.is_empty() {
+            return Ok((quality, thread_count));
+        }
+        let user = Users::find_by_id(video.user_id).one(&self.db).await?;
+        if let Some(user_override) = user.and_then(|user| overrides.get(&user.twitch_name)) {
+            if let Some(user_quality) = &user_override.quality {
+                quality = user_quality.clone();
+            }
+            if let Some(user_thread_count) = user_override.thread_count {
+                thread_count = user_thread_count;
+            }
+        }
+        Ok((quality, thread_count))
+    }
+
+    /// Uploads the videos again that were downloaded, but failed to upload.
+    async fn retry_pending_uploads(&self) -> Result<()> {
+        if self.storage.is_none() {
+            return Ok(());
+        }
+        let failed = Videos::find()
+            .filter(VideosColumn::Status.eq(Status::Failed))
+            .all(&self.db)
+            .await?;
+        for video in failed {
+            let id = video.id;
+            if !state::is_upload_pending(&self.db, id).await? {
+                continue;
+            }
+            let Some(files) = state::get_files(&self.db, id).await? else {
+                warn!(
+                    "The files of video {} are unknown, downloading it again",
+                    id
+                );
+                state::remove_value(&self.db, id, state::KEY_UPLOAD_PENDING).await?;
+                continue;
+            };
+            let paths: Vec<PathBuf> = files.into_iter().map(|file| file.path).collect();
+            match self.store_files(id, &paths).await {
+                Ok(keys) => {
+                    info!("Uploaded video {} as {:?}", id, keys);
+                    self.update_status(id, Status::Failed, Status::Downloaded, None)
+                        .await?;
+                }
+                Err(err) => warn!(
+                    "Could not upload video {}, trying again next run: {:?}",
+                    id, err
+                ),
+            }
+        }
         Ok(())
     }
 
+    /// Uploads the files of a video to the storage, if one is configured.
+    ///
+    /// Returns the keys of the uploaded files and removes the local files
+    /// afterwards if configured.
+    async fn store_files(&self, id: i32, files: &[PathBuf]) -> Result<Vec<String>> {
+        let Some(storage) = &self.storage else {
+            return Ok(vec![]);
+        };
+        let mut keys = vec![];
+        for file in files {
+            keys.push(storage.upload(file).await?);
+        }
+        let json = serde_json::to_string(&keys).unwrap_or_default();
+        state::set_value(&self.db, id, state::KEY_OBJECT_KEYS, json).await?;
+        state::remove_value(&self.db, id, state::KEY_UPLOAD_PENDING).await?;
+        if self.downloader.settings().storage.delete_local_files {
+            for file in files {
+                match tokio::fs::remove_file(file).await {
+                    Ok(()) => debug!("Removed uploaded file {:?}", file),
+                    Err(err) => warn!("Could not remove uploaded file {:?}: {:?}", file, err),
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Downloads a single video, even if it is not in the database.
+    ///
+    /// With `force` an existing download of the video is replaced instead of failing.
     pub async fn download_video_by_id<VideoId: DIntoString, Quality: DIntoString>(
         &self,
         video_id: VideoId,
         quality: Quality,
         output_folder: &Path,
+        force: bool,
     ) -> Result<()> {
-        let video_id = video_id.into();
+        let video_id: String = video_id.into();
+        let video_id = match parse_video_id(&video_id) {
+            Ok(video_id) => video_id,
+            // clips are downloaded by their slug
+            Err(VideoIdError::Clip { slug, .. }) => slug,
+            Err(err) => return Err(err.into()),
+        };
         let quality = quality.into();
 
         let video = Videos::find()
             .filter(VideosColumn::TwitchId.eq(&video_id))
             .one(&self.db)
-            .await?
-            .ok_or_else(|| DownloaderError::VideoNotFound(video_id))?;
+            .await?;
 
-        self.download_video(video, &quality, output_folder).await
+        match video {
+            Some(video) => {
+                let video = if force {
+                    if video.status == Status::Downloading {
+                        // the files belong to a download that is still running
+                        return Err(DownloaderError::StatusChanged(video.id));
+                    }
+                    let folder = self.output_folder_for(&video, output_folder).await?;
+                    // if the old files can't be removed, the video stays downloaded
+                    // instead of being queued with the old file still in place
+                    self.remove_previous_download(&video.id.to_string(), &folder)
+                        .await?;
+                    self.reset_downloaded(video).await?
+                } else {
+                    video
+                };
+                self.download_video(video, &quality, None, output_folder)
+                    .await
+                    .map(|_| ())
+            }
+            None => {
+                warn!(
+                    "Video with id: {} is not in the database, downloading it without tracking",
+                    video_id
+                );
+                if force {
+                    self.remove_previous_download(&video_id, output_folder)
+                        .await?;
+                }
+                let downloaded = self
+                    .downloader
+                    .download_video(
+                        &video_id,
+                        &video_id,
+                        &quality,
+                        output_folder,
+                        &self.cancel,
+                        DownloadOptions::default(),
+                    )
+                    .await?;
+                info!("Downloaded video to {:?}", downloaded.path);
+                Ok(())
+            }
+        }
     }
 
+    /// Downloads a video and keeps its status up to date.
+    ///
+    /// Downloads with the configured amount of threads if `thread_count` is not set.
     pub async fn download_video(
         &self,
         video: VideosModel,
         quality: &str,
+        thread_count: Option<u64>,
         output_folder: &Path,
-    ) -> Result<()> {
+    ) -> Result<DownloadedVideo> {
         let id = video.id;
         let video_id = video.twitch_id.clone();
-        let mut video = video.into_active_model();
-        video.status = Set(Status::Downloading);
-        video.clone().update(&self.db).await?;
-        let download_result = self
-            .twitch_client
-            .download_video(id, video_id, quality, output_folder)
-            .await;
-        match download_result {
-            Ok(path) => {
-                info!("Downloaded video to {:?}", path);
-                video.status = Set(Status::Downloaded);
-                video.clone().update(&self.db).await?;
-                Ok(())
+        let output_folder = &self.output_folder_for(&video, output_folder).await?;
+        info!("Downloading video {} to {:?}", id, output_folder);
+        let video = self.refresh_video_info(video).await?;
+        let model = video.clone();
+        if !self
+            .update_status(id, video.status, Status::Downloading, None)
+            .await?
+        {
+            return Err(DownloaderError::StatusChanged(id));
+        }
+        let (progress_sender, mut progress_receiver) = mpsc::channel(8);
+        let db = self.db.clone();
+        let progress_writer = tokio::spawn(async move {
+            while let Some(progress) = progress_receiver.recv().await {
+                if progress.phase != DownloadPhase::DownloadingParts {
+                    continue;
+                }
+                if let Err(err) = state::set_progress(&db, id, progress).await {
+                    warn!("Could not store the download progress: {:?}", err);
+                }
+            }
+        });
+        // a timeout only stops this video, not the whole run
+        let cancel = self.cancel.child_token();
+        let name = id.to_string();
+        let download = self.downloader.download_video(
+            &name,
+            &video_id,
+            quality,
+            output_folder,
+            &cancel,
+            DownloadOptions {
+                progress: Some(progress_sender),
+                thread_count,
+                byte_budget: Some(self.byte_budget.clone()),
+            },
+        );
+        let download_result = match self.downloader.settings().video_timeout_secs {
+            Some(timeout) => {
+                let timeout = Duration::from_secs(timeout);
+                tokio::pin!(download);
+                match tokio::time::timeout(timeout, &mut download).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        warn!("Giving up on video {} after {:?}", id, timeout);
+                        cancel.cancel();
+                        // let the download stop cleanly before removing what is left of it
+                        let result = download.await;
+                        if result.is_ok() {
+                            result
+                        } else {
+                            self.remove_parts_folder(id, output_folder).await;
+                            Err(DownloaderError::VideoTimeout(timeout))
+                        }
+                    }
+                }
+            }
+            None => download.await,
+        };
+        // the sender is dropped with the download, so this finishes after the last write
+        let _ = progress_writer.await;
+        // a successful download is only announced once it is finished
+        let failure_notification = match &download_result {
+            Ok(_) => None,
+            Err(err) => Some(Notification::Video {
+                id,
+                twitch_id: model.twitch_id.clone(),
+                status: match err {
+                    DownloaderError::Cancelled
+                    | DownloaderError::ByteCapReached { .. }
+                    | DownloaderError::File(DownloadFileError::InsufficientDiskSpace { .. }) => {
+                        format!("{:?}", Status::NotStarted)
+                    }
+                    DownloaderError::VideoTimeout(_)
+                        if self.downloader.settings().retry_timed_out_videos =>
+                    {
+                        format!("{:?}", Status::NotStarted)
+                    }
+                    err if err.is_retry_later() => format!("{:?}", Status::NotStarted),
+                    _ => format!("{:?}", Status::Failed),
+                },
+                path: None,
+                size: None,
+                duration: None,
+                error: Some(err.to_string()),
+            }),
+        };
+        let result = match download_result {
+            Ok(downloaded) => {
+                info!("Downloaded video to {:?}", downloaded.path);
+                if let Err(err) = self
+                    .finish_download(id, &model, &downloaded, output_folder)
+                    .await
+                {
+                    error!("Could not finish the download of video {}: {:?}", id, err);
+                    metrics().videos_failed.inc();
+                    let attempts = state::increment_attempts(&self.db, id).await?;
+                    debug!("video {} has failed {} times", id, attempts);
+                    self.update_status(
+                        id,
+                        Status::Downloading,
+                        Status::Failed,
+                        Some(err.to_string()),
+                    )
+                    .await?;
+                    self.notifier
+                        .notify(&Notification::Video {
+                            id,
+                            twitch_id: model.twitch_id.clone(),
+                            status: format!("{:?}", Status::Failed),
+                            path: Some(downloaded.path.clone()),
+                            size: files_size(&downloaded.files()).await,
+                            duration: Some(downloaded.info.total_duration),
+                            error: Some(err.to_string()),
+                        })
+                        .await;
+                    return Err(err);
+                }
+                if !self
+                    .update_status_with_duration(
+                        id,
+                        Status::Downloading,
+                        Status::Downloaded,
+                        downloaded.measured_duration,
+                    )
+                    .await?
+                {
+                    return Err(DownloaderError::StatusChanged(id));
+                }
+                metrics().videos_downloaded.inc();
+                self.notifier
+                    .notify(&Notification::Video {
+                        id,
+                        twitch_id: model.twitch_id.clone(),
+                        status: format!("{:?}", Status::Downloaded),
+                        path: Some(downloaded.path.clone()),
+                        size: files_size(&downloaded.files()).await,
+                        duration: Some(downloaded.info.total_duration),
+                        error: None,
+                    })
+                    .await;
+                Ok(downloaded)
+            }
+            Err(DownloaderError::File(err @ DownloadFileError::InsufficientDiskSpace { .. })) => {
+                warn!("Skipping video: {}", err);
+                self.update_status(id, Status::Downloading, Status::NotStarted, None)
+                    .await?;
+                Err(err.into())
+            }
+            Err(err) if err.is_retry_later() => {
+                info!("Not downloading video {} yet: {}", id, err);
+                self.update_status(id, Status::Downloading, Status::NotStarted, None)
+                    .await?;
+                Err(err)
+            }
+            Err(DownloaderError::Cancelled) => {
+                warn!("Download of video {} was cancelled", id);
+                self.update_status(id, Status::Downloading, Status::NotStarted, None)
+                    .await?;
+                Err(DownloaderError::Cancelled)
+            }
+            Err(err @ DownloaderError::VideoTimeout(_))
+                if self.downloader.settings().retry_timed_out_videos =>
+            {
+                warn!("Video {} will be tried again next run: {}", id, err);
+                self.update_status(id, Status::Downloading, Status::NotStarted, None)
+                    .await?;
+                Err(err)
+            }
+            Err(err @ DownloaderError::ByteCapReached { .. }) => {
+                info!("Stopped the download of video {}: {}", id, err);
+                self.update_status(id, Status::Downloading, Status::NotStarted, None)
+                    .await?;
+                Err(err)
             }
             Err(err) => {
                 error!("Could not download video: {:?}", err);
-                video.status = Set(Status::Failed);
-                video.fail_reason = Set(Some(err.to_string()));
-                video.clone().update(&self.db).await?;
+                metrics().videos_failed.inc();
+                if err.is_permanent() {
+                    state::mark_permanently_failed(&self.db, id).await?;
+                } else {
+                    let attempts = state::increment_attempts(&self.db, id).await?;
+                    debug!("video {} has failed {} times", id, attempts);
+                }
+                self.update_status(
+                    id,
+                    Status::Downloading,
+                    Status::Failed,
+                    Some(err.to_string()),
+                )
+                .await?;
                 Err(err)
             }
+        };
+        if let Some(notification) = failure_notification {
+            self.notifier.notify(&notification).await;
+        }
+        result
+    }
+
+    /// Everything that is left to do once the video itself is downloaded:
+    /// storing the hashes, uploading, writing the metadata and getting the
+    /// thumbnail and chat.
+    ///
+    /// If this fails the video can't count as downloaded.
+    async fn finish_download(
+        &self,
+        id: i32,
+        video: &VideosModel,
+        downloaded: &DownloadedVideo,
+        output_folder: &Path,
+    ) -> Result<()> {
+        let mut files = vec![];
+        for path in downloaded.files() {
+            files.push(FileInfo::new(&path).await?);
+        }
+        state::set_files(&self.db, id, &files).await?;
+        let mut metadata = DownloadMetadata::new(video, downloaded, files);
+        match self.store_files(id, &downloaded.files()).await {
+            Ok(keys) => metadata.object_keys = keys,
+            Err(err) => {
+                error!("Could not upload video {}: {:?}", id, err);
+                // the download itself is kept, only the upload is retried
+                state::set_value(&self.db, id, state::KEY_UPLOAD_PENDING, true).await?;
+                return Err(err);
+            }
+        }
+        let metadata_path = metadata.write(&downloaded.path).await?;
+        debug!("Wrote metadata to {:?}", metadata_path);
+        match downloaded.measured_duration {
+            Some(duration) => {
+                state::set_value(&self.db, id, state::KEY_MEASURED_DURATION, duration).await?
+            }
+            None => warn!(
+                "The duration of video {} could not be measured, keeping the one from twitch",
+                id
+            ),
+        }
+        if !downloaded.gaps.is_empty() {
+            warn!(
+                "Video {} is missing {} parts, see {:?}",
+                id,
+                downloaded.gaps.len(),
+                metadata_path
+            );
+            state::set_value(&self.db, id, state::KEY_HAS_GAPS, true).await?;
+        } else {
+            state::remove_value(&self.db, id, state::KEY_HAS_GAPS).await?;
+        }
+        if self.downloader.settings().download_thumbnails {
+            match self
+                .downloader
+                .download_thumbnail(&id.to_string(), &video.twitch_id, output_folder)
+                .await
+            {
+                Ok(path) => info!("Downloaded thumbnail to {:?}", path),
+                Err(err) => warn!("Could not download thumbnail: {:?}", err),
+            }
+        }
+        if self.downloader.settings().download_chat {
+            let chat_path = output_folder.join(format!("{}.chat.jsonl", id));
+            match self
+                .downloader
+                .download_chat(&video.twitch_id, &chat_path)
+                .await
+            {
+                Ok(count) => info!("Downloaded {} chat comments to {:?}", count, chat_path),
+                Err(err) => warn!("Could not download chat: {:?}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the final file (or the files it was split into) and the parts of
+    /// an earlier download of the video, so it is downloaded again from scratch.
+    async fn remove_previous_download(&self, id: &str, output_folder: &Path) -> Result<()> {
+        let extension = if clip::is_clip_id(id) {
+            "mp4"
+        } else {
+            self.downloader
+                .settings()
+                .twitch
+                .output_container
+                .extension()
+        };
+        let final_path = output_folder.join(format!("{}.{}", id, extension));
+        // a video that was split only has the split files left
+        let mut files = existing_split_files(&final_path);
+        if final_path.exists() {
+            files.push(final_path);
+        }
+        for file in files {
+            if self.downloader.settings().keep_replaced_downloads {
+                let mut backup = file.clone().into_os_string();
+                backup.push(".bak");
+                info!("Moving the existing {:?} to {:?}", file, backup);
+                tokio::fs::rename(&file, &backup)
+                    .await
+                    .map_err(DownloadFileError::Filesystem)?;
+            } else {
+                info!("Removing the existing {:?}", file);
+                tokio::fs::remove_file(&file)
+                    .await
+                    .map_err(DownloadFileError::Filesystem)?;
+            }
+        }
+        let folder = self.downloader.settings().parts_folder(output_folder, id);
+        if folder.exists() {
+            info!("Removing the leftover parts in {:?}", folder);
+            tokio::fs::remove_dir_all(&folder)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+        Ok(())
+    }
+
+    /// Queues a downloaded video again, so it can be downloaded anew.
+    async fn reset_downloaded(&self, mut video: VideosModel) -> Result<VideosModel> {
+        if video.status != Status::Downloaded {
+            return Ok(video);
+        }
+        if !self
+            .update_status(video.id, Status::Downloaded, Status::NotStarted, None)
+            .await?
+        {
+            return Err(DownloaderError::StatusChanged(video.id));
+        }
+        video.status = Status::NotStarted;
+        Ok(video)
+    }
+
+    /// Removes the part folder of a video that was given up on.
+    async fn remove_parts_folder(&self, id: i32, output_folder: &Path) {
+        let folder = self
+            .downloader
+            .settings()
+            .parts_folder(output_folder, &id.to_string());
+        if !folder.exists() {
+            return;
+        }
+        info!("Removing the parts of video {} in {:?}", id, folder);
+        if let Err(err) = tokio::fs::remove_dir_all(&folder).await {
+            warn!("Could not remove {:?}: {:?}", folder, err);
+        }
+    }
+}
+
+/// Whether the downloader may move a video from one status to the other.
+///
+/// Every status that is not handled here belongs to the later steps (splitting,
+/// uploading, ...), so the downloader never moves a video into or out of them.
+pub fn can_transition(from: Status, to: Status) -> bool {
+    use Status::*;
+    // no wildcards, so a new status has to be thought about here
+    match from {
+        NotStarted => match to {
+            Downloading | Failed => true,
+            NotStarted | Downloaded | Splitting | Split | Uploading | PartiallyUploaded
+            | Uploaded => false,
+        },
+        Downloading => match to {
+            Downloaded | Failed | NotStarted => true,
+            Downloading | Splitting | Split | Uploading | PartiallyUploaded | Uploaded => false,
+        },
+        Downloaded => match to {
+            // a forced download replaces the existing one
+            NotStarted => true,
+            Downloading | Downloaded | Failed | Splitting | Split | Uploading
+            | PartiallyUploaded | Uploaded => false,
+        },
+        Failed => match to {
+            Downloading | Failed | NotStarted => true,
+            // the download was kept, only the upload failed
+            Downloaded => true,
+            Splitting | Split | Uploading | PartiallyUploaded | Uploaded => false,
+        },
+        Splitting | Split | Uploading | PartiallyUploaded | Uploaded => false,
+    }
+}
+
+/// The size of a file or of all files in a folder.
+fn disk_usage(path: &Path) -> u64 {
+    if !path.is_dir() {
+        return std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| disk_usage(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Parses the creation time of a video, which is stored as text.
+fn parse_created_at(created_at: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(created_at) {
+        return Some(time.to_utc());
+    }
+    [
+        "%Y-%m-%d %H:%M:%S%.f UTC",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+    ]
+    .iter()
+    .find_map(|format| chrono::NaiveDateTime::parse_from_str(created_at, format).ok())
+    .map(|time| time.and_utc())
+}
+
+/// Whether the file only exists until a video (of any container) or its
+/// metadata is written completely.
+fn is_temporary_file(name: &str) -> bool {
+    name.ends_with(".json.tmp")
+        || OutputContainer::ALL
+            .iter()
+            .any(|container| name.ends_with(&format!(".{}.part", container.extension())))
+}
+
+/// The combined size of the files, if all of them exist.
+async fn files_size(files: &[PathBuf]) -> Option<u64> {
+    let mut size = 0;
+    for file in files {
+        size += tokio::fs::metadata(file).await.ok()?.len();
+    }
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{conf, database, get_video, insert_user, insert_video, MockDownloader};
+
+    #[test]
+    fn only_the_downloader_transitions_are_allowed() {
+        use twba_local_db::re_exports::sea_orm::Iterable;
+        use Status::*;
+        let allowed = [
+            (NotStarted, Downloading),
+            (NotStarted, Failed),
+            (Downloading, Downloaded),
+            (Downloading, Failed),
+            (Downloading, NotStarted),
+            (Downloaded, NotStarted),
+            (Failed, Downloading),
+            (Failed, Failed),
+            (Failed, NotStarted),
+            (Failed, Downloaded),
+        ];
+        let mut pairs = 0;
+        for from in Status::iter() {
+            for to in Status::iter() {
+                let expected = allowed.contains(&(from.clone(), to.clone()));
+                assert_eq!(
+                    can_transition(from.clone(), to.clone()),
+                    expected,
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+                pairs += 1;
+            }
+        }
+        assert_eq!(pairs, Status::iter().count().pow(2));
+    }
+
+    #[tokio::test]
+    async fn downloads_the_queued_videos() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::NotStarted).await;
+        insert_video(&db, 2, 1, Status::NotStarted).await;
+        let client = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        let summary = client.download_not_downloaded_videos().await.unwrap();
+
+        assert_eq!(summary.count(Outcome::Downloaded), 2);
+        assert_eq!(client.downloader.downloaded(), vec!["v1", "v2"]);
+        for id in [1, 2] {
+            let video = get_video(client.db(), id).await;
+            assert_eq!(video.status, Status::Downloaded);
+            assert_eq!(video.duration, 60);
+            assert_eq!(video.fail_reason, None);
+            assert!(folder.path().join(format!("{}.mp4", id)).is_file());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_download_marks_the_video_as_failed() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::NotStarted).await;
+        insert_video(&db, 2, 1, Status::NotStarted).await;
+        let downloader = MockDownloader::new(folder.path()).fail("v1");
+        let client = DownloaderClient::new(downloader, db);
+
+        let summary = client.download_not_downloaded_videos().await.unwrap();
+
+        assert_eq!(summary.count(Outcome::Failed), 1);
+        assert_eq!(summary.count(Outcome::Downloaded), 1);
+        let failed = get_video(client.db(), 1).await;
+        assert_eq!(failed.status, Status::Failed);
+        assert!(failed.fail_reason.is_some());
+        assert_eq!(state::get_attempts(client.db(), 1).await.unwrap(), 1);
+        assert_eq!(get_video(client.db(), 2).await.status, Status::Downloaded);
+    }
+
+    #[tokio::test]
+    async fn a_video_is_not_retried_after_the_max_attempts() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::NotStarted).await;
+        let downloader = MockDownloader::new(folder.path()).fail("v1");
+        let max_attempts = downloader.settings.max_download_attempts;
+        let client = DownloaderClient::new(downloader, db);
+
+        for _ in 0..max_attempts {
+            client.download_not_downloaded_videos().await.unwrap();
+        }
+        let summary = client.download_not_downloaded_videos().await.unwrap();
+
+        assert_eq!(summary.permanently_failed, vec![1]);
+        assert_eq!(client.downloader.downloaded().len(), max_attempts as usize);
+    }
+
+    #[tokio::test]
+    async fn only_one_of_two_racing_status_updates_wins() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::NotStarted).await;
+        let first = DownloaderClient::new(MockDownloader::new(folder.path()), db.clone());
+        let second = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        let (a, b) = tokio::join!(
+            first.update_status(1, Status::NotStarted, Status::Downloading, None),
+            second.update_status(1, Status::NotStarted, Status::Downloading, None),
+        );
+
+        let mut won = [a.unwrap(), b.unwrap()];
+        won.sort();
+        assert_eq!(won, [false, true]);
+        assert_eq!(get_video(first.db(), 1).await.status, Status::Downloading);
+    }
+
+    #[tokio::test]
+    async fn a_video_is_only_downloaded_by_one_of_two_racing_clients() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        let video = insert_video(&db, 1, 1, Status::NotStarted).await;
+        let first = DownloaderClient::new(MockDownloader::new(folder.path()), db.clone());
+        let second = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        let (a, b) = tokio::join!(
+            first.download_video(video.clone(), "max", None, folder.path()),
+            second.download_video(video, "max", None, folder.path()),
+        );
+
+        let (downloaded, skipped) = if a.is_ok() { (a, b) } else { (b, a) };
+        assert!(downloaded.is_ok());
+        assert!(matches!(skipped, Err(DownloaderError::StatusChanged(1))));
+        let attempts = first.downloader.downloaded().len() + second.downloader.downloaded().len();
+        assert_eq!(attempts, 1);
+        assert_eq!(get_video(first.db(), 1).await.status, Status::Downloaded);
+    }
+
+    #[tokio::test]
+    async fn a_status_change_during_the_download_is_kept() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        let video = insert_video(&db, 1, 1, Status::NotStarted).await;
+        let downloader = MockDownloader::new(folder.path())
+            .change_status_during_download(&db, Status::NotStarted);
+        let client = DownloaderClient::new(downloader, db);
+
+        let result = client
+            .download_video(video, "max", None, folder.path())
+            .await;
+
+        assert!(matches!(result, Err(DownloaderError::StatusChanged(1))));
+        assert_eq!(get_video(client.db(), 1).await.status, Status::NotStarted);
+    }
+
+    #[tokio::test]
+    async fn a_forced_download_replaces_the_downloaded_video() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::Downloaded).await;
+        let final_path = folder.path().join("1.mp4");
+        std::fs::write(&final_path, "old").unwrap();
+        let client = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        client
+            .download_video_by_id("v1", "max", folder.path(), true)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&final_path).unwrap(), "v1");
+        assert_eq!(get_video(client.db(), 1).await.status, Status::Downloaded);
+    }
+
+    #[tokio::test]
+    async fn plans_with_the_quality_the_videos_are_downloaded_in() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_user(&db, 2, "other").await;
+        insert_video(&db, 1, 1, Status::NotStarted).await;
+        insert_video(&db, 2, 2, Status::NotStarted).await;
+        let mut downloader = MockDownloader::new(folder.path());
+        downloader.settings.user_overrides.insert(
+            "streamer".to_string(),
+            crate::config::UserOverride {
+                quality: Some("480p".to_string()),
+                thread_count: None,
+            },
+        );
+        let mut client = DownloaderClient::new(downloader, db);
+        client.quality = "720p60".to_string();
+
+        client.plan_downloads().await.unwrap();
+
+        assert_eq!(
+            client.downloader.inspected(),
+            vec![
+                ("v1".to_string(), "480p".to_string()),
+                ("v2".to_string(), "720p60".to_string())
+            ]
+        );
+        assert!(client.downloader.downloaded().is_empty());
+        assert_eq!(get_video(client.db(), 1).await.status, Status::NotStarted);
+    }
+
+    #[tokio::test]
+    async fn downloads_at_most_max_items_to_process() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        for id in 1..=3 {
+            insert_video(&db, id, 1, Status::NotStarted).await;
+        }
+        let mut config = conf(folder.path());
+        config.max_items_to_process = 1;
+        let downloader = MockDownloader::new(folder.path()).with_config(config);
+        let client = DownloaderClient::new(downloader, db);
+
+        let summary = client.download_not_downloaded_videos().await.unwrap();
+
+        assert_eq!(summary.count(Outcome::Downloaded), 1);
+        assert_eq!(client.downloader.downloaded(), vec!["v1"]);
+        assert_eq!(get_video(client.db(), 2).await.status, Status::NotStarted);
+        assert_eq!(get_video(client.db(), 3).await.status, Status::NotStarted);
+    }
+
+    #[tokio::test]
+    async fn lists_the_videos_of_the_downloader_oldest_first() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 4, 1, Status::Failed).await;
+        insert_video(&db, 1, 1, Status::NotStarted).await;
+        insert_video(&db, 3, 1, Status::Downloaded).await;
+        insert_video(&db, 2, 1, Status::Downloading).await;
+        insert_video(&db, 5, 1, Status::Uploaded).await;
+        insert_video(&db, 6, 1, Status::Splitting).await;
+        let client = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        let videos = client.list_videos().await.unwrap();
+
+        let ids: Vec<i32> = videos.iter().map(|video| video.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn the_status_of_a_video_shows_its_files() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::Failed).await;
+        state::increment_attempts(&db, 1).await.unwrap();
+        state::mark_permanently_failed(&db, 1).await.unwrap();
+        std::fs::create_dir(folder.path().join("1")).unwrap();
+        let client = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        let status = client.video_status(1).await.unwrap().unwrap();
+
+        assert_eq!(status.video.twitch_id, "v1");
+        assert_eq!(status.final_path, folder.path().join("1.mp4"));
+        assert!(!status.final_exists);
+        assert_eq!(status.parts_folder, folder.path().join("1"));
+        assert!(status.parts_folder_exists);
+        assert_eq!(status.attempts, 1);
+        assert!(status.permanently_failed);
+
+        std::fs::write(folder.path().join("1.mp4"), b"video").unwrap();
+        let status = client.video_status(1).await.unwrap().unwrap();
+        assert!(status.final_exists);
+    }
+
+    #[tokio::test]
+    async fn an_unknown_video_has_no_status() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        let client = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        assert!(client.video_status(1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn resets_only_the_failed_videos() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::Failed).await;
+        insert_video(&db, 2, 1, Status::Failed).await;
+        insert_video(&db, 3, 1, Status::Downloaded).await;
+        for id in [1, 2] {
+            state::increment_attempts(&db, id).await.unwrap();
+        }
+        state::mark_permanently_failed(&db, 1).await.unwrap();
+        state::set_value(&db, 2, state::KEY_UPLOAD_PENDING, true)
+            .await
+            .unwrap();
+        let client = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+
+        let reset = client.reset_failed().await.unwrap();
+
+        assert_eq!(reset, 2);
+        for id in [1, 2] {
+            assert_eq!(get_video(client.db(), id).await.status, Status::NotStarted);
+            assert_eq!(state::get_attempts(client.db(), id).await.unwrap(), 0);
+            assert!(!state::is_permanently_failed(client.db(), id).await.unwrap());
+            let pending = state::get_value(client.db(), id, state::KEY_UPLOAD_PENDING)
+                .await
+                .unwrap();
+            assert_eq!(pending, None);
         }
+        assert_eq!(get_video(client.db(), 3).await.status, Status::Downloaded);
+    }
+
+    #[tokio::test]
+    async fn a_stale_download_is_reset_even_if_its_file_exists() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        let video = insert_video(&db, 1, 1, Status::Downloading).await;
+        let client = DownloaderClient::new(MockDownloader::new(folder.path()), db);
+        let output_folder = client
+            .output_folder_for(&video, folder.path())
+            .await
+            .unwrap();
+        std::fs::create_dir_all(&output_folder).unwrap();
+        let final_path = client.final_path(&output_folder, 1);
+        std::fs::write(&final_path, b"left behind").unwrap();
+
+        client.recover_stale_downloads().await.unwrap();
+
+        assert_eq!(get_video(client.db(), 1).await.status, Status::NotStarted);
+        assert!(final_path.is_file());
+    }
+
+    #[test]
+    fn the_temporary_files_of_every_container_are_recognized() {
+        assert!(is_temporary_file("1.mp4.part"));
+        assert!(is_temporary_file("1.mkv.part"));
+        assert!(is_temporary_file("1.json.tmp"));
+        assert!(!is_temporary_file("1.mkv"));
+        assert!(!is_temporary_file("1_part1.mp4"));
+    }
+
+    #[tokio::test]
+    async fn cleanup_removes_the_leftover_files_of_an_mkv() {
+        let folder = tempfile::tempdir().unwrap();
+        let db = database().await;
+        insert_user(&db, 1, "streamer").await;
+        insert_video(&db, 1, 1, Status::NotStarted).await;
+        let mut downloader = MockDownloader::new(folder.path());
+        downloader.settings.twitch.output_container = OutputContainer::Mkv;
+        let client = DownloaderClient::new(downloader, db);
+        std::fs::write(folder.path().join("1.mkv.part"), b"partial").unwrap();
+        std::fs::write(folder.path().join("2.mkv"), b"video").unwrap();
+
+        client.cleanup(false).await.unwrap();
+
+        assert!(!folder.path().join("1.mkv.part").exists());
+        assert!(folder.path().join("2.mkv").is_file());
     }
 }
@@ -0,0 +1,103 @@
+use crate::config::DownloaderConfig;
+use crate::prelude::*;
+use crate::twitch::helix::VideoInfo;
+use crate::twitch::{clip, DownloadInfo, DownloadOptions, DownloadedVideo, TwitchClient};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+
+/// Everything the [`DownloaderClient`](crate::client::DownloaderClient) needs
+/// to download videos.
+///
+/// This is implemented by [`TwitchClient`], but allows running the client
+/// with anything else, for example a fake that does not touch twitch.
+pub trait VideoDownloader {
+    fn config(&self) -> &Conf;
+    fn settings(&self) -> &DownloaderConfig;
+    /// Downloads the video to `<id>.mp4` (or the configured container) in the output folder.
+    fn download_video(
+        &self,
+        id: &str,
+        video_id: &str,
+        quality: &str,
+        output_folder: &Path,
+        cancel: &CancellationToken,
+        options: DownloadOptions,
+    ) -> impl Future<Output = Result<DownloadedVideo>>;
+    /// Gets the current title, duration etc. of a video if that is supported.
+    fn get_video_info(&self, video_id: &str) -> impl Future<Output = Result<Option<VideoInfo>>>;
+    /// Gets everything that is known about a video without downloading anything.
+    fn inspect_video(
+        &self,
+        video_id: &str,
+        quality: &str,
+    ) -> impl Future<Output = Result<DownloadInfo>>;
+    /// Downloads the thumbnail to `<id>.jpg` in the output folder.
+    fn download_thumbnail(
+        &self,
+        id: &str,
+        video_id: &str,
+        output_folder: &Path,
+    ) -> impl Future<Output = Result<PathBuf>>;
+    /// Downloads the chat to the output path and returns the amount of comments.
+    fn download_chat(
+        &self,
+        video_id: &str,
+        output_path: &Path,
+    ) -> impl Future<Output = Result<usize>>;
+    /// How many requests were rate limited by twitch so far.
+    fn throttled_requests(&self) -> u64 {
+        0
+    }
+}
+
+impl VideoDownloader for TwitchClient {
+    fn config(&self) -> &Conf {
+        &self.config
+    }
+
+    fn settings(&self) -> &DownloaderConfig {
+        &self.settings
+    }
+
+    async fn download_video(
+        &self,
+        id: &str,
+        video_id: &str,
+        quality: &str,
+        output_folder: &Path,
+        cancel: &CancellationToken,
+        options: DownloadOptions,
+    ) -> Result<DownloadedVideo> {
+        TwitchClient::download_video(self, id, video_id, quality, output_folder, cancel, options)
+            .await
+    }
+
+    async fn get_video_info(&self, video_id: &str) -> Result<Option<VideoInfo>> {
+        if clip::is_clip_id(video_id) {
+            return Ok(None);
+        }
+        TwitchClient::get_video_info(self, video_id).await
+    }
+
+    async fn inspect_video(&self, video_id: &str, quality: &str) -> Result<DownloadInfo> {
+        TwitchClient::inspect_video(self, video_id, quality).await
+    }
+
+    async fn download_thumbnail(
+        &self,
+        id: &str,
+        video_id: &str,
+        output_folder: &Path,
+    ) -> Result<PathBuf> {
+        TwitchClient::download_thumbnail(self, id, video_id, output_folder).await
+    }
+
+    async fn download_chat(&self, video_id: &str, output_path: &Path) -> Result<usize> {
+        TwitchClient::download_chat(self, video_id, output_path).await
+    }
+
+    fn throttled_requests(&self) -> u64 {
+        TwitchClient::throttled_requests(self)
+    }
+}
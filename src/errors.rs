@@ -1,14 +1,27 @@
 use crate::prelude::*;
 use std::path::PathBuf;
+use twba_local_db::prelude::Status;
 use twba_reqwest_backoff::ReqwestBackoffError;
 
 #[derive(Debug, thiserror::Error)]
 pub enum DownloaderError {
     #[error("Video not found: {0}")]
     VideoNotFound(String),
+    #[error("Invalid video id")]
+    InvalidVideoId(#[from] VideoIdError),
 
     #[error("User not found: {0}")]
     UserNotFound(i32),
+    #[error("There is no user with the id or login {0:?}")]
+    UnknownUser(String),
+    #[error("The status of video {0} was changed by something else")]
+    StatusChanged(i32),
+    #[error("Video {video_id} can't be moved from {from:?} to {to:?}")]
+    InvalidStatusTransition {
+        video_id: i32,
+        from: Status,
+        to: Status,
+    },
 
     #[error("Malformed playlist")]
     MalformedPlaylist(#[from] MalformedPlaylistError),
@@ -25,10 +38,91 @@ pub enum DownloaderError {
     AccessTokenJsonParse(#[source] serde_json::Error),
     #[error("The server did not provide an access token")]
     AccessTokenEmpty,
+    #[error("Twitch GQL returned an error: {0}")]
+    GqlError(String),
+    #[error("Twitch GQL rejected the request ({status}): {message}")]
+    GqlRejected {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    #[error("Twitch GQL rate limited the request")]
+    GqlRateLimited,
+    #[error("Twitch rate limited the playlist request")]
+    UsherRateLimited,
+    #[error("Twitch GQL is unavailable ({0})")]
+    GqlUnavailable(reqwest::StatusCode),
+    #[error("The server did not provide an access token for any of the player types {player_types:?}: {reason}")]
+    Restricted {
+        reason: &'static str,
+        player_types: Vec<String>,
+    },
+    #[error("Could not parse json to thumbnail url")]
+    ThumbnailJsonParse(#[source] serde_json::Error),
+    #[error("The video has no thumbnail: {0}")]
+    ThumbnailNotFound(String),
+    #[error("Could not parse the json from the Helix api")]
+    HelixJsonParse(#[source] serde_json::Error),
+    #[error("Could not parse json to chat comments")]
+    ChatJsonParse(#[source] serde_json::Error),
     #[error("Got an error with the Filesystem")]
     File(#[from] DownloadFileError),
     #[error("Error while loading config")]
     LoadConfig(#[source] anyhow::Error),
+    #[error("Could not run ffmpeg at {0:?}")]
+    FfmpegNotFound(String, #[source] std::io::Error),
+    #[error("There are no checksums for the video {0}")]
+    NoChecksums(i32),
+    #[error("Could not serialize the run summary")]
+    SummaryJson(#[source] serde_json::Error),
+    #[error("{0} videos failed to download")]
+    VideosFailed(usize),
+    #[error("Could not upload to the storage")]
+    Storage(#[source] anyhow::Error),
+    #[error("Could not start the metrics server")]
+    MetricsServer(#[source] std::io::Error),
+    #[error("Another downloader instance (pid {0:?}) is running")]
+    AlreadyRunning(Option<u32>),
+    #[error("The CDN kept refusing the parts, gave up after {refreshes} access token refreshes")]
+    AccessExpired { refreshes: u32 },
+    #[error("The download was cancelled")]
+    Cancelled,
+    #[error("Gave up on the video after {0:?}")]
+    VideoTimeout(std::time::Duration),
+    #[error("Reached the limit of {limit} bytes for this run")]
+    ByteCapReached { limit: u64 },
+    #[error("The video is too old ({age_days} days, the limit is {max_days} days)")]
+    VideoTooOld { age_days: i64, max_days: u32 },
+    #[error("The video is too long ({duration_hours:.2}h, the limit is {max_hours:.2}h)")]
+    VideoTooLong { duration_hours: f32, max_hours: f32 },
+}
+
+impl DownloaderError {
+    /// Whether retrying the download can never succeed, so the video should not be retried.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            DownloaderError::VideoTooLong { .. }
+                | DownloaderError::VideoNotFound(_)
+                | DownloaderError::MalformedPlaylist(MalformedPlaylistError::Restricted { .. })
+        )
+    }
+
+    /// Whether the video can't be downloaded yet and should just be tried
+    /// again in the next run, without counting as failed.
+    pub fn is_retry_later(&self) -> bool {
+        matches!(
+            self,
+            DownloaderError::MalformedPlaylist(MalformedPlaylistError::StillProcessing { .. })
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VideoIdError {
+    #[error("{input:?} is a clip, clips are downloaded by their slug ({slug})")]
+    Clip { input: String, slug: String },
+    #[error("{0:?} is neither a video id nor the url of a twitch video")]
+    Invalid(String),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -37,11 +131,21 @@ pub enum MalformedPlaylistError {
     Empty,
     #[error("Playlist did not specify any qualities")]
     NoQualities,
+    #[error("Playlist contained a stream without an uri")]
+    MissingUri,
 
     #[error("Could not parse the playlist")]
     Parse(#[from] PlaylistParseError),
     #[error("Could not parse the url/the url did not contain the expected information")]
     InvalidUrl,
+    #[error("The video is still being processed by twitch ({segments} segments so far)")]
+    StillProcessing { segments: usize },
+    #[error("Twitch refused to give the playlist ({code}): {message}")]
+    Restricted { code: String, message: String },
+    /// Something else than a playlist was sent (e.g. an html error page),
+    /// which might work on the next try.
+    #[error("The response does not start with #EXTM3U, so it is not a playlist: {start}")]
+    NotAPlaylist { start: String },
 }
 #[derive(Debug, thiserror::Error)]
 pub enum PlaylistParseError {
@@ -49,13 +153,22 @@ pub enum PlaylistParseError {
     Eof,
     #[error("Invalid time format in playlist")]
     InvalidTimeFormat(#[source] chrono::ParseError),
+    #[error("Invalid byte range in playlist: {0}")]
+    InvalidByteRange(String),
 }
 #[derive(Debug, thiserror::Error)]
 pub enum DownloadFileError {
-    #[error("The target folder is not empty {0:?}")]
-    TargetFolderIsNotEmpty(PathBuf),
+    #[error("The CDN refused the request for {0} (403)")]
+    Forbidden(String),
+    #[error("The CDN answered the request for {url} with {status}")]
+    PartStatus {
+        url: String,
+        status: reqwest::StatusCode,
+    },
     #[error("The target folder is not a directory {0:?}")]
     TargetFolderIsNotADirectory(PathBuf),
+    #[error("The target path is a directory instead of a file: {0:?}")]
+    TargetIsADirectory(PathBuf),
     #[error("The target path already exists: {0:?}")]
     TargetAlreadyExists(PathBuf),
     #[error("Could not create the target folder")]
@@ -71,6 +184,12 @@ pub enum DownloadFileError {
 
     #[error("The ffmpeg command returned an error")]
     Ffmpeg(#[source] tokio::io::Error),
+    #[error("ffmpeg exited with code {code:?}: {stderr}")]
+    FfmpegFailed { code: Option<i32>, stderr: String },
+    #[error("ffprobe could not read {path:?}: {stderr}")]
+    FfprobeFailed { path: PathBuf, stderr: String },
+    #[error("The video is too short: {actual:.1}s instead of {expected:.1}s")]
+    VideoTooShort { expected: f32, actual: f32 },
 
     #[error("could not canonicalize path: {0:?}")]
     Canonicalization(#[source] std::io::Error),
@@ -79,4 +198,39 @@ pub enum DownloadFileError {
     DownloadBackoff(#[source] ReqwestBackoffError),
     #[error("Got an Error during a reqwest request (download)")]
     DownloadReqwest(#[source] reqwest::Error),
+    #[error("Could not download these parts: {0:?}")]
+    FailedParts(Vec<String>),
+    #[error("Expected {expected} parts but got {got}, missing: {missing:?}")]
+    MissingParts {
+        expected: usize,
+        got: usize,
+        missing: Vec<String>,
+    },
+    #[error("{url} is only {size} bytes, expected at least {min_size}")]
+    PartTooSmall {
+        url: String,
+        size: u64,
+        min_size: u64,
+    },
+    #[error("These parts are too small to be complete: {0:?}")]
+    TooSmallParts(Vec<String>),
+    #[error("These parts changed since they were downloaded: {0:?}")]
+    ChangedParts(Vec<String>),
+    #[error("Timed out after {timeout:?} while downloading {url}")]
+    Timeout {
+        url: String,
+        timeout: std::time::Duration,
+    },
+    #[error("Incomplete download of {url}: expected {expected} bytes, got {got}")]
+    IncompleteDownload {
+        expected: u64,
+        got: u64,
+        url: String,
+    },
+    #[error("Not enough free disk space at {path:?}: {available} bytes available, {required} bytes required")]
+    InsufficientDiskSpace {
+        path: PathBuf,
+        available: u64,
+        required: u64,
+    },
 }
@@ -0,0 +1,50 @@
+//! Downloads twitch VODs that are queued in the twba database.
+//!
+//! The `twba-downloader` binary is a thin wrapper around this library, so the
+//! same downloading logic can be embedded into other services. The entry point
+//! is [`DownloaderClientBuilder`], which takes an already open database
+//! connection and the shared twba config.
+//!
+//! ```no_run
+//! use twba_downloader::summary::Outcome;
+//! use twba_downloader::{DownloaderClientBuilder, DownloaderConfig};
+//!
+//! # async fn example(
+//! #     db: twba_local_db::re_exports::sea_orm::DatabaseConnection,
+//! #     conf: twba_common::prelude::Conf,
+//! # ) -> twba_downloader::prelude::Result<()> {
+//! let client = DownloaderClientBuilder::new(db, conf)
+//!     .settings(DownloaderConfig::default())
+//!     .output_folder("/mnt/videos")
+//!     .quality("1080p60")
+//!     .build()
+//!     .await?;
+//! let summary = client.download_not_downloaded_videos().await?;
+//! println!("{} videos failed", summary.count(Outcome::Failed));
+//! # Ok(())
+//! # }
+//! ```
+pub mod checksum;
+pub mod client;
+pub mod config;
+pub mod downloader;
+mod errors;
+pub mod lock;
+pub mod metadata;
+pub mod metrics;
+pub mod notifications;
+pub mod prelude;
+pub mod state;
+pub mod storage;
+pub mod summary;
+#[cfg(test)]
+mod test_util;
+pub mod twitch;
+
+pub use client::{DownloaderClient, DownloaderClientBuilder};
+pub use config::DownloaderConfig;
+pub use downloader::VideoDownloader;
+pub use errors::{
+    DownloadFileError, DownloaderError, MalformedPlaylistError, PlaylistParseError, VideoIdError,
+};
+pub use twitch::TwitchClient;
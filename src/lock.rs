@@ -0,0 +1,85 @@
+//! Makes sure only one downloader works on a download folder at a time.
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const LOCK_FILE_NAME: &str = ".twba_downloader.lock";
+
+/// An exclusive lock on the download folder, which is released when this is dropped.
+///
+/// The lock is held by the OS (flock), so it is also released if the process
+/// crashes. A lock file left behind by a dead process is therefore just reused,
+/// and the file is never removed.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Takes the lock or fails with [`DownloaderError::AlreadyRunning`].
+    pub fn acquire(download_folder: &Path) -> Result<Self> {
+        let path = download_folder.join(LOCK_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(DownloadFileError::FileCreation)?;
+        if file.try_lock_exclusive().is_err() {
+            let mut content = String::new();
+            let _ = file.read_to_string(&mut content);
+            return Err(DownloaderError::AlreadyRunning(content.trim().parse().ok()));
+        }
+        file.set_len(0).map_err(DownloadFileError::Write)?;
+        file.seek(SeekFrom::Start(0))
+            .map_err(DownloadFileError::Write)?;
+        write!(file, "{}", std::process::id()).map_err(DownloadFileError::Write)?;
+        file.flush().map_err(DownloadFileError::Write)?;
+        debug!("acquired the lock {:?}", path);
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // the file is never removed: another instance could already have it
+        // open, and would lock a file that a third one no longer sees
+        let _ = FileExt::unlock(&self.file);
+        debug!("released the lock {:?}", self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_second_instance_can_not_take_the_lock() {
+        let folder = tempfile::tempdir().unwrap();
+        let _lock = InstanceLock::acquire(folder.path()).unwrap();
+
+        let second = InstanceLock::acquire(folder.path());
+
+        assert!(
+            matches!(second, Err(DownloaderError::AlreadyRunning(Some(pid))) if pid == std::process::id()),
+            "{:?}",
+            second
+        );
+    }
+
+    #[test]
+    fn the_lock_can_be_taken_again_once_released() {
+        let folder = tempfile::tempdir().unwrap();
+        drop(InstanceLock::acquire(folder.path()).unwrap());
+
+        let again = InstanceLock::acquire(folder.path());
+
+        assert!(again.is_ok(), "{:?}", again);
+        assert!(folder.path().join(LOCK_FILE_NAME).is_file());
+    }
+}
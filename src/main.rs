@@ -1,43 +1,267 @@
-use prelude::*;
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 use twba_backup_config::get_default_builder;
+use twba_downloader::prelude::{DownloaderError, Result};
+use twba_downloader::twitch::progress::format_bytes;
+use twba_downloader::twitch::twitch_utils::parse_video_id;
+use twba_downloader::{client, config, lock, metrics, summary, DownloaderClientBuilder};
 use twba_local_db::prelude::{Status, Videos, VideosColumn};
-pub mod client;
-mod errors;
-pub mod prelude;
-pub mod twitch;
+
+/// The exit code when the downloader was stopped with Ctrl-C (same as a shell uses for SIGINT).
+const EXIT_CODE_INTERRUPTED: i32 = 130;
+/// The exit code when at least one video of the run failed to download.
+const EXIT_CODE_VIDEOS_FAILED: i32 = 1;
+/// The exit code when the run failed as a whole, e.g. because of the config or the database.
+const EXIT_CODE_FATAL: i32 = 2;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Downloads the queued videos if no command is given
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Overrides the download folder from the config
+    #[arg(long, global = true, env = "TWBA_DOWNLOAD_FOLDER")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Lists the videos that are queued, downloading, downloaded or failed
+    List,
+    /// Shows everything that is known about a single video
+    Status {
+        /// The id of the video in the database
+        id: i32,
+    },
+    /// Downloads the queued videos or a single video
+    Download(DownloadArgs),
+    /// Checks that the files of a downloaded video were not changed
+    Verify {
+        /// The id of the video in the database
+        id: i32,
+    },
+    /// Lists the qualities a video is available in
+    Qualities {
+        /// The twitch id or url of the video
+        video_id: String,
+    },
+    /// Removes leftover part folders and temporary files of failed downloads
+    Cleanup {
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Resets videos so they are downloaded again
+    Retry {
+        /// Resets all failed videos
+        #[arg(long)]
+        failed: bool,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct DownloadArgs {
+    /// Only download the video with this twitch id (or url) instead of the whole queue
+    #[arg(long = "id")]
+    video_id: Option<String>,
+    /// The quality to download the single video in (only used with --id)
+    #[arg(long, default_value = "max")]
+    quality: String,
+    /// Only show what would be downloaded without downloading anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Only download the queued videos of this user (login or id)
+    #[arg(long, conflicts_with = "video_id")]
+    user: Option<String>,
+    /// Keep the downloaded parts after the videos were converted
+    #[arg(long)]
+    keep_parts: bool,
+    /// Replace an existing download of the video (only used with --id)
+    #[arg(long, requires = "video_id")]
+    force: bool,
+}
+
+impl Default for DownloadArgs {
+    fn default() -> Self {
+        Self {
+            video_id: None,
+            quality: "max".to_string(),
+            dry_run: false,
+            user: None,
+            keep_parts: false,
+            force: false,
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let _guard = twba_common::init_tracing("twba_downloader");
     info!("Hello, world!");
+    let args = Args::parse();
 
-    let x = run().await;
-    x.or_else(|e| match e {
-        DownloaderError::LoadConfig(e) => {
-            println!("Error while loading config: {}", e);
-            Ok(())
+    if let Err(e) = run(args).await {
+        let code = exit_code(e);
+        if code != 0 {
+            std::process::exit(code);
         }
-        e => Err(e),
-    })?;
+    }
 
     info!("Bye");
-    Ok(())
+}
+
+/// Reports the error of a run and returns the exit code for it.
+///
+/// - 0: nothing went wrong, or another instance is already running
+/// - [`EXIT_CODE_VIDEOS_FAILED`]: the run completed, but at least one video failed
+/// - [`EXIT_CODE_FATAL`]: the run could not start or was aborted, e.g. because
+///   the config or the database could not be loaded
+/// - [`EXIT_CODE_INTERRUPTED`]: the downloader was stopped with Ctrl-C
+fn exit_code(e: DownloaderError) -> i32 {
+    match e {
+        DownloaderError::LoadConfig(e) => {
+            println!("Error while loading config: {:?}", e);
+            EXIT_CODE_FATAL
+        }
+        DownloaderError::AlreadyRunning(pid) => {
+            match pid {
+                Some(pid) => println!("Another downloader instance (pid {}) is running", pid),
+                None => println!("Another downloader instance is running"),
+            }
+            0
+        }
+        DownloaderError::Cancelled => {
+            warn!("Stopped because the download was interrupted");
+            EXIT_CODE_INTERRUPTED
+        }
+        DownloaderError::VideosFailed(count) => {
+            warn!("{} videos failed to download", count);
+            EXIT_CODE_VIDEOS_FAILED
+        }
+        e => {
+            error!("The run failed: {:?}", e);
+            EXIT_CODE_FATAL
+        }
+    }
 }
 
 #[tracing::instrument]
-async fn run() -> Result<()> {
-    let conf = get_default_builder().load().map_err(|e| {
+async fn run(args: Args) -> Result<()> {
+    let command = args
+        .command
+        .unwrap_or_else(|| Command::Download(DownloadArgs::default()));
+    let mut conf = get_default_builder().load().map_err(|e| {
         error!("Failed to load config: {:?}", e);
         DownloaderError::LoadConfig(e.into())
     })?;
+    let mut settings = config::DownloaderConfig::load()?;
+    if let Command::Download(DownloadArgs {
+        keep_parts: true, ..
+    }) = &command
+    {
+        settings.twitch.keep_parts = true;
+    }
+    if let Some(output) = args.output {
+        config::validate_download_folder(&output)?;
+        conf.download_folder_path = output.to_string_lossy().to_string();
+    }
+    config::validate(&conf, &settings)?;
+    info!("Using the download folder {:?}", conf.download_folder_path);
+    // only the commands that change something need to wait for other instances
+    let _lock = match command {
+        Command::Download(_) | Command::Cleanup { .. } | Command::Retry { .. } => Some(
+            lock::InstanceLock::acquire(Path::new(&conf.download_folder_path))?,
+        ),
+        Command::List
+        | Command::Status { .. }
+        | Command::Verify { .. }
+        | Command::Qualities { .. } => None,
+    };
 
     let db = twba_local_db::open_database(Some(&conf.db_url)).await?;
     twba_local_db::migrate_db(&db).await?;
     // local_db::print_db(&db).await?;
 
-    dbg!(&conf);
+    config::log_config_summary(&conf, &settings);
+    if let Some(listen_addr) = &settings.metrics.listen_addr {
+        let download_folder = PathBuf::from(&conf.download_folder_path);
+        metrics::start_server(listen_addr, download_folder).await?;
+    }
+    let client = DownloaderClientBuilder::new(db, conf)
+        .settings(settings)
+        .build()
+        .await?;
+
+    match command {
+        Command::List => list_videos(&client).await,
+        Command::Status { id } => print_video_status(&client, id).await,
+        Command::Retry { failed } => {
+            if !failed {
+                println!("Nothing to retry, use --failed to reset all failed videos");
+                return Ok(());
+            }
+            let count = client.reset_failed().await?;
+            println!("Reset {} failed videos", count);
+            Ok(())
+        }
+        Command::Verify { id } => {
+            let invalid = client.verify(id).await?;
+            if invalid.is_empty() {
+                println!("All files of video {} are valid", id);
+            } else {
+                println!("These files of video {} are missing or changed:", id);
+                for path in invalid {
+                    println!("  {:?}", path);
+                }
+            }
+            Ok(())
+        }
+        Command::Cleanup { dry_run } => {
+            let report = client.cleanup(dry_run).await?;
+            let verb = if dry_run { "Would remove" } else { "Removed" };
+            println!(
+                "{} {} files and folders, {}",
+                verb,
+                report.removed.len(),
+                format_bytes(report.bytes)
+            );
+            Ok(())
+        }
+        Command::Qualities { video_id } => print_qualities(&client, &video_id).await,
+        Command::Download(args) => download(&client, args).await,
+    }
+}
+
+async fn download(client: &client::DownloaderClient, args: DownloadArgs) -> Result<()> {
+    client.downloader.check_ffmpeg().await?;
+    cancel_on_ctrl_c(client.cancellation_token());
+
+    if args.dry_run {
+        return client.plan_downloads().await;
+    }
+
+    client.recover_stale_downloads().await?;
+
+    if let Some(video_id) = args.video_id {
+        let output_folder = PathBuf::from(&client.downloader.config.download_folder_path);
+        return match client
+            .download_video_by_id(&video_id, args.quality, &output_folder, args.force)
+            .await
+        {
+            Err(DownloaderError::Cancelled) => Err(DownloaderError::Cancelled),
+            Err(e) => {
+                error!("Could not download the video {}: {:?}", video_id, e);
+                Err(DownloaderError::VideosFailed(1))
+            }
+            Ok(()) => Ok(()),
+        };
+    }
+
     let amount_of_downloaded_but_not_uploaded_videos =
-        get_amount_of_downloaded_but_not_uploaded_videos(&db).await?;
+        get_amount_of_downloaded_but_not_uploaded_videos(client.db()).await?;
     //TODO: make configurable
     if amount_of_downloaded_but_not_uploaded_videos >= 3 {
         info!(
@@ -56,14 +280,99 @@ async fn run() -> Result<()> {
     //     info!("Quitting because user requested it.");
     //     return Ok(());
     // }
-    let twitch_client = twitch::TwitchClient::new(conf);
-    let client = client::DownloaderClient::new(twitch_client, db);
+    let summary = match args.user {
+        Some(user) => {
+            let user = client.find_user(&user).await?;
+            client.download_videos_for_user(user.id).await?
+        }
+        None => client.download_not_downloaded_videos().await?,
+    };
+    if summary.has_failures() {
+        return Err(DownloaderError::VideosFailed(
+            summary.count(summary::Outcome::Failed),
+        ));
+    }
 
-    client.download_not_downloaded_videos().await?;
+    Ok(())
+}
 
+async fn list_videos(client: &client::DownloaderClient) -> Result<()> {
+    let videos = client.list_videos().await?;
+    println!(
+        "{:>6}  {:<12}  {:<12}  {:<26}  title",
+        "id", "twitch id", "status", "created at"
+    );
+    for video in videos {
+        // Debug and Display of these don't support padding
+        let status = format!("{:?}", video.status);
+        let created_at = video.created_at.to_string();
+        println!(
+            "{:>6}  {:<12}  {:<12}  {:<26}  {}",
+            video.id, video.twitch_id, status, created_at, video.name
+        );
+    }
     Ok(())
 }
 
+async fn print_qualities(client: &client::DownloaderClient, video_id: &str) -> Result<()> {
+    let video_id = parse_video_id(video_id)?;
+    let qualities = client.downloader.get_available_qualities(&video_id).await?;
+    println!(
+        "{:<20}  {:>10}  {:>5}  {:>10}",
+        "name", "resolution", "fps", "bandwidth"
+    );
+    for quality in qualities {
+        let resolution = quality
+            .resolution
+            .map(|(width, height)| format!("{}x{}", width, height))
+            .unwrap_or_default();
+        let fps = quality
+            .fps()
+            .map(|fps| format!("{:.0}", fps))
+            .unwrap_or_default();
+        let bandwidth = quality
+            .bandwidth
+            .map(|bandwidth| format!("{}/s", format_bytes(bandwidth / 8)))
+            .unwrap_or_default();
+        println!(
+            "{:<20}  {:>10}  {:>5}  {:>10}",
+            quality.name, resolution, fps, bandwidth
+        );
+    }
+    Ok(())
+}
+
+async fn print_video_status(client: &client::DownloaderClient, id: i32) -> Result<()> {
+    let Some(status) = client.video_status(id).await? else {
+        println!("There is no video with the id {}", id);
+        return Ok(());
+    };
+    println!("{:#?}", status.video);
+    println!(
+        "final video: {:?} (exists: {})",
+        status.final_path, status.final_exists
+    );
+    println!(
+        "parts folder: {:?} (exists: {})",
+        status.parts_folder, status.parts_folder_exists
+    );
+    println!(
+        "failed attempts: {}, permanently failed: {}",
+        status.attempts, status.permanently_failed
+    );
+    Ok(())
+}
+
+/// Cancels the token when Ctrl-C is pressed, so the current download can stop cleanly.
+fn cancel_on_ctrl_c(token: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Received Ctrl-C, stopping the current download");
+            token.cancel();
+        }
+    });
+}
+
 async fn get_amount_of_downloaded_but_not_uploaded_videos<C>(db: &C) -> Result<u64>
 where
     C: twba_local_db::re_exports::sea_orm::ConnectionTrait,
@@ -76,7 +385,7 @@ where
         .await?)
 }
 
-pub fn wait_for_user() -> StdResult<bool, Box<dyn StdError>> {
+pub fn wait_for_user() -> std::result::Result<bool, Box<dyn std::error::Error>> {
     use std::io::{self, Write};
     loop {
         print!("Press Enter to continue or 'q' to quit: ");
@@ -98,3 +407,23 @@ pub fn wait_for_user() -> StdResult<bool, Box<dyn StdError>> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_outcome_of_a_run_has_its_exit_code() {
+        let load_config = DownloaderError::LoadConfig(anyhow::anyhow!("missing field"));
+        assert_eq!(exit_code(load_config), EXIT_CODE_FATAL);
+        assert_eq!(
+            exit_code(DownloaderError::VideosFailed(2)),
+            EXIT_CODE_VIDEOS_FAILED
+        );
+        assert_eq!(exit_code(DownloaderError::Cancelled), EXIT_CODE_INTERRUPTED);
+        assert_eq!(exit_code(DownloaderError::AlreadyRunning(Some(1234))), 0);
+        assert_eq!(exit_code(DownloaderError::AlreadyRunning(None)), 0);
+        let other = DownloaderError::VideoNotFound("123".to_string());
+        assert_eq!(exit_code(other), EXIT_CODE_FATAL);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::checksum::FileInfo;
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use crate::twitch::{DownloadedVideo, PartGap};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use twba_local_db::prelude::VideosModel;
+
+/// Information about a downloaded video for the other twba components.
+///
+/// This is written as `<id>.json` next to the downloaded `<id>.mp4` (or `.mkv`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadMetadata {
+    /// The id of the video in the database.
+    pub id: i32,
+    pub twitch_id: String,
+    pub title: String,
+    /// When the video was streamed.
+    pub recorded_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// The quality the video was downloaded in.
+    pub quality: String,
+    /// The duration of the video in seconds according to the playlist.
+    pub duration: f32,
+    /// The duration of the video file in seconds, as measured by ffprobe.
+    pub measured_duration: Option<f32>,
+    pub part_count: usize,
+    /// The video files, which are multiple if the video was split.
+    pub files: Vec<FileInfo>,
+    /// Whether parts of the video could not be downloaded.
+    #[serde(default)]
+    pub has_gaps: bool,
+    /// The parts that are missing from the video.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gaps: Vec<PartGap>,
+    /// The keys of the files in the storage, if they were uploaded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub object_keys: Vec<String>,
+}
+
+impl DownloadMetadata {
+    pub fn new(video: &VideosModel, downloaded: &DownloadedVideo, files: Vec<FileInfo>) -> Self {
+        Self {
+            id: video.id,
+            twitch_id: video.twitch_id.clone(),
+            title: video.name.clone(),
+            recorded_at: downloaded.info.streamed_at,
+            quality: downloaded.info.quality.clone(),
+            duration: downloaded.info.total_duration,
+            measured_duration: downloaded.measured_duration,
+            part_count: downloaded.info.part_count,
+            files,
+            has_gaps: !downloaded.gaps.is_empty(),
+            gaps: downloaded.gaps.clone(),
+            object_keys: vec![],
+        }
+    }
+
+    /// The path of the metadata file for the given video file.
+    pub fn path_for(video_path: &Path) -> PathBuf {
+        video_path.with_extension("json")
+    }
+
+    /// Writes the metadata next to the video file.
+    ///
+    /// The file is written to a temporary file first and then renamed, so
+    /// there is never a half written metadata file.
+    pub async fn write(&self, video_path: &Path) -> Result<PathBuf> {
+        let path = Self::path_for(video_path);
+        let tmp_path = path.with_extension("json.tmp");
+        let json = serde_json::to_vec_pretty(self).map_err(|e| {
+            DownloadFileError::Write(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })?;
+        tokio::fs::write(&tmp_path, json)
+            .await
+            .map_err(DownloadFileError::Write)?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,194 @@
+//! Prometheus metrics for long running deployments.
+//!
+//! The metrics are always collected, but only served on `/metrics` if
+//! `metrics.listen_addr` is set in the config.
+use crate::prelude::*;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntGauge, Registry, TextEncoder,
+};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Gets the global metrics.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub videos_downloaded: IntCounter,
+    pub videos_failed: IntCounter,
+    pub bytes_downloaded: IntCounter,
+    /// How long it takes to download a single part, including retries.
+    pub part_download_seconds: Histogram,
+    pub parts_in_flight: IntGauge,
+    /// The free space on the download disk, updated on every scrape.
+    pub disk_free_bytes: IntGauge,
+    /// How long the steps after downloading the parts take, by `step`.
+    pub processing_seconds: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("twba_downloader".to_string()), None)
+            .expect("the metrics prefix is valid");
+        let videos_downloaded =
+            IntCounter::new("videos_downloaded_total", "Videos that were downloaded")
+                .expect("valid metric");
+        let videos_failed =
+            IntCounter::new("videos_failed_total", "Videos that failed").expect("valid metric");
+        let bytes_downloaded =
+            IntCounter::new("bytes_downloaded_total", "Bytes downloaded from the CDN")
+                .expect("valid metric");
+        let part_download_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "part_download_duration_seconds",
+                "The time it takes to download a single part",
+            )
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]),
+        )
+        .expect("valid metric");
+        let parts_in_flight = IntGauge::new("parts_in_flight", "Parts that are being downloaded")
+            .expect("valid metric");
+        let disk_free_bytes = IntGauge::new("disk_free_bytes", "Free space on the download disk")
+            .expect("valid metric");
+        let processing_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "processing_duration_seconds",
+                "The time it takes to combine and convert the parts",
+            )
+            .buckets(vec![
+                1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0,
+            ]),
+            &["step"],
+        )
+        .expect("valid metric");
+
+        registry
+            .register(Box::new(videos_downloaded.clone()))
+            .expect("metric is only registered once");
+        registry
+            .register(Box::new(videos_failed.clone()))
+            .expect("metric is only registered once");
+        registry
+            .register(Box::new(bytes_downloaded.clone()))
+            .expect("metric is only registered once");
+        registry
+            .register(Box::new(part_download_seconds.clone()))
+            .expect("metric is only registered once");
+        registry
+            .register(Box::new(parts_in_flight.clone()))
+            .expect("metric is only registered once");
+        registry
+            .register(Box::new(disk_free_bytes.clone()))
+            .expect("metric is only registered once");
+        registry
+            .register(Box::new(processing_seconds.clone()))
+            .expect("metric is only registered once");
+
+        Self {
+            registry,
+            videos_downloaded,
+            videos_failed,
+            bytes_downloaded,
+            part_download_seconds,
+            parts_in_flight,
+            disk_free_bytes,
+            processing_seconds,
+        }
+    }
+
+    /// Encodes all metrics in the prometheus text format.
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            warn!("Could not encode the metrics: {:?}", err);
+        }
+        buffer
+    }
+}
+
+/// Counts a part as in flight for as long as this is alive.
+pub struct InFlightPart(());
+
+impl InFlightPart {
+    pub fn start() -> Self {
+        metrics().parts_in_flight.inc();
+        Self(())
+    }
+}
+
+impl Drop for InFlightPart {
+    fn drop(&mut self) {
+        metrics().parts_in_flight.dec();
+    }
+}
+
+/// Starts serving the metrics on `/metrics` in the background.
+///
+/// Fails if the address can't be bound, so a wrong config is noticed right away.
+#[tracing::instrument]
+pub async fn start_server(listen_addr: &str, download_folder: PathBuf) -> Result<()> {
+    let listener = TcpListener::bind(listen_addr)
+        .await
+        .map_err(DownloaderError::MetricsServer)?;
+    info!("Serving metrics on http://{}/metrics", listen_addr);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let download_folder = download_folder.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_connection(stream, &download_folder).await {
+                            debug!("Could not answer metrics request: {:?}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("Could not accept metrics connection: {:?}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    download_folder: &std::path::Path,
+) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, content_type, body) = if path == "/metrics" {
+        let metrics = metrics();
+        if let Ok(free) = fs2::available_space(download_folder) {
+            metrics.disk_free_bytes.set(free as i64);
+        }
+        (
+            "200 OK",
+            TextEncoder::new().format_type().to_string(),
+            metrics.encode(),
+        )
+    } else {
+        (
+            "404 Not Found",
+            "text/plain".to_string(),
+            b"not found".to_vec(),
+        )
+    };
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await
+}
@@ -0,0 +1,111 @@
+//! Optional webhook notifications about finished and failed downloads.
+//!
+//! Sending a notification never fails the download, errors are only logged.
+use crate::config::NotificationsConfig;
+use crate::prelude::*;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use twba_reqwest_backoff::ReqwestClient;
+
+/// How often a webhook is tried before giving up.
+const WEBHOOK_ATTEMPTS: u32 = 3;
+/// The time to wait between two attempts of sending a webhook.
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// The json payload that is posted to the webhook.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    /// A single video was downloaded or failed to download.
+    Video {
+        /// The id of the video in the database.
+        id: i32,
+        twitch_id: String,
+        status: String,
+        path: Option<PathBuf>,
+        /// The size of the video file in bytes.
+        size: Option<u64>,
+        /// The duration of the video in seconds.
+        duration: Option<f32>,
+        error: Option<String>,
+    },
+    /// A run of [`crate::client::DownloaderClient::download_not_downloaded_videos`] finished.
+    RunFinished {
+        downloaded: usize,
+        failed: usize,
+        /// Videos that were not attempted because of disk space or a cancellation.
+        skipped: usize,
+        /// Failed videos that will not be retried anymore.
+        permanently_failed: usize,
+    },
+}
+
+pub struct Notifier {
+    client: ReqwestClient,
+    webhook_url: Option<String>,
+}
+
+/// The webhook url can contain a token, so it is not shown.
+impl std::fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Notifier")
+            .field("webhook_configured", &self.webhook_url.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Notifier {
+    pub fn new(config: &NotificationsConfig) -> Self {
+        Self {
+            client: reqwest::Client::new().into(),
+            webhook_url: config.webhook_url.clone(),
+        }
+    }
+
+    /// Posts the notification to the webhook if one is configured.
+    #[tracing::instrument(skip(self))]
+    pub async fn notify(&self, notification: &Notification) {
+        let Some(url) = &self.webhook_url else {
+            return;
+        };
+        let body = match serde_json::to_string(notification) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Could not serialize the notification: {:?}", err);
+                return;
+            }
+        };
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            match self.send(url, body.clone()).await {
+                Ok(()) => {
+                    trace!("Sent notification to the webhook");
+                    return;
+                }
+                Err(err) => warn!(
+                    "Could not send notification to the webhook (attempt {}/{}): {:?}",
+                    attempt, WEBHOOK_ATTEMPTS, err
+                ),
+            }
+            if attempt < WEBHOOK_ATTEMPTS {
+                tokio::time::sleep(WEBHOOK_RETRY_DELAY).await;
+            }
+        }
+        error!(
+            "Giving up on sending the notification after {} attempts",
+            WEBHOOK_ATTEMPTS
+        );
+    }
+
+    async fn send(&self, url: &str, body: String) -> Result<()> {
+        let request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .build()?;
+        let response = self.client.execute_with_backoff(request).await?;
+        response.error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! Downloader specific data about videos that does not fit into the shared videos table.
+//!
+//! The data is stored as key/value pairs per video in a separate table, so
+//! new values can be added without needing a migration of the shared database.
+use crate::checksum::FileInfo;
+use crate::prelude::*;
+use crate::twitch::progress::DownloadProgress;
+use twba_local_db::re_exports::sea_orm::sea_query::{
+    Alias, ColumnDef, Expr, Index, OnConflict, Query, Table,
+};
+use twba_local_db::re_exports::sea_orm::{ConnectionTrait, QueryResult};
+
+const TABLE: &str = "downloader_video_state";
+const VIDEO_ID: &str = "video_id";
+const KEY: &str = "key";
+const VALUE: &str = "value";
+
+/// The amount of failed download attempts of a video.
+pub const KEY_ATTEMPTS: &str = "attempts";
+
+/// Set if the video failed in a way that can't be fixed by retrying.
+pub const KEY_PERMANENTLY_FAILED: &str = "permanently_failed";
+
+/// The amount of downloaded parts of the current download.
+pub const KEY_PROGRESS_PARTS_DONE: &str = "progress_parts_done";
+/// The amount of parts of the current download.
+pub const KEY_PROGRESS_PARTS_TOTAL: &str = "progress_parts_total";
+/// The amount of bytes of the downloaded parts, including those of an earlier run.
+pub const KEY_PROGRESS_BYTES: &str = "progress_bytes";
+
+/// The duration of the downloaded video file in seconds.
+pub const KEY_MEASURED_DURATION: &str = "measured_duration";
+
+/// The absolute path of the downloaded video (the first file if it was split).
+pub const KEY_FINAL_PATH: &str = "final_path";
+/// The size of the downloaded video in bytes (of all files if it was split).
+pub const KEY_FINAL_SIZE: &str = "final_size";
+/// The files of the downloaded video with their size and hash as a json array.
+pub const KEY_FILES: &str = "files";
+
+/// Set if the video was downloaded, but could not be uploaded to the storage yet.
+pub const KEY_UPLOAD_PENDING: &str = "upload_pending";
+/// The keys of the uploaded files of the video in the storage as a json array.
+pub const KEY_OBJECT_KEYS: &str = "object_keys";
+
+/// Set if parts are missing from the downloaded video.
+pub const KEY_HAS_GAPS: &str = "has_gaps";
+
+/// The view count of the video when it was last refreshed.
+pub const KEY_VIEW_COUNT: &str = "view_count";
+
+/// Creates the state table if it does not exist yet.
+pub async fn init<C: ConnectionTrait>(db: &C) -> Result<()> {
+    let statement = Table::create()
+        .table(Alias::new(TABLE))
+        .if_not_exists()
+        .col(ColumnDef::new(Alias::new(VIDEO_ID)).integer().not_null())
+        .col(ColumnDef::new(Alias::new(KEY)).string().not_null())
+        .col(ColumnDef::new(Alias::new(VALUE)).string().not_null())
+        .primary_key(
+            Index::create()
+                .col(Alias::new(VIDEO_ID))
+                .col(Alias::new(KEY)),
+        )
+        .to_owned();
+    let backend = db.get_database_backend();
+    db.execute(backend.build(&statement)).await?;
+    Ok(())
+}
+
+/// Gets a single value for a video.
+pub async fn get_value<C: ConnectionTrait>(
+    db: &C,
+    video_id: i32,
+    key: &str,
+) -> Result<Option<String>> {
+    let statement = Query::select()
+        .column(Alias::new(VALUE))
+        .from(Alias::new(TABLE))
+        .and_where(Expr::col(Alias::new(VIDEO_ID)).eq(video_id))
+        .and_where(Expr::col(Alias::new(KEY)).eq(key))
+        .to_owned();
+    let backend = db.get_database_backend();
+    let row: Option<QueryResult> = db.query_one(backend.build(&statement)).await?;
+    Ok(match row {
+        Some(row) => Some(row.try_get::<String>("", VALUE)?),
+        None => None,
+    })
+}
+
+/// Sets a single value for a video, overwriting any previous value.
+pub async fn set_value<C: ConnectionTrait, V: ToString>(
+    db: &C,
+    video_id: i32,
+    key: &str,
+    value: V,
+) -> Result<()> {
+    let statement = Query::insert()
+        .into_table(Alias::new(TABLE))
+        .columns([Alias::new(VIDEO_ID), Alias::new(KEY), Alias::new(VALUE)])
+        .values_panic([video_id.into(), key.into(), value.to_string().into()])
+        .on_conflict(
+            OnConflict::columns([Alias::new(VIDEO_ID), Alias::new(KEY)])
+                .update_column(Alias::new(VALUE))
+                .to_owned(),
+        )
+        .to_owned();
+    let backend = db.get_database_backend();
+    db.execute(backend.build(&statement)).await?;
+    Ok(())
+}
+
+/// Removes a single value for a video.
+pub async fn remove_value<C: ConnectionTrait>(db: &C, video_id: i32, key: &str) -> Result<()> {
+    let statement = Query::delete()
+        .from_table(Alias::new(TABLE))
+        .and_where(Expr::col(Alias::new(VIDEO_ID)).eq(video_id))
+        .and_where(Expr::col(Alias::new(KEY)).eq(key))
+        .to_owned();
+    let backend = db.get_database_backend();
+    db.execute(backend.build(&statement)).await?;
+    Ok(())
+}
+
+/// Gets the amount of failed download attempts of a video.
+pub async fn get_attempts<C: ConnectionTrait>(db: &C, video_id: i32) -> Result<u32> {
+    Ok(get_value(db, video_id, KEY_ATTEMPTS)
+        .await?
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Increments the amount of failed download attempts of a video and returns the new amount.
+pub async fn increment_attempts<C: ConnectionTrait>(db: &C, video_id: i32) -> Result<u32> {
+    let attempts = get_attempts(db, video_id).await? + 1;
+    set_value(db, video_id, KEY_ATTEMPTS, attempts).await?;
+    Ok(attempts)
+}
+
+/// Marks a video as failed in a way that can't be fixed by retrying.
+pub async fn mark_permanently_failed<C: ConnectionTrait>(db: &C, video_id: i32) -> Result<()> {
+    set_value(db, video_id, KEY_PERMANENTLY_FAILED, true).await
+}
+
+/// Whether the video failed in a way that can't be fixed by retrying.
+pub async fn is_permanently_failed<C: ConnectionTrait>(db: &C, video_id: i32) -> Result<bool> {
+    Ok(get_value(db, video_id, KEY_PERMANENTLY_FAILED)
+        .await?
+        .is_some_and(|x| x == "true"))
+}
+
+/// Stores the download progress of a video.
+pub async fn set_progress<C: ConnectionTrait>(
+    db: &C,
+    video_id: i32,
+    progress: DownloadProgress,
+) -> Result<()> {
+    set_value(db, video_id, KEY_PROGRESS_PARTS_DONE, progress.parts_done).await?;
+    set_value(db, video_id, KEY_PROGRESS_PARTS_TOTAL, progress.parts_total).await?;
+    set_value(db, video_id, KEY_PROGRESS_BYTES, progress.bytes_downloaded).await?;
+    Ok(())
+}
+
+/// Stores where the downloaded files of a video are.
+pub async fn set_files<C: ConnectionTrait>(
+    db: &C,
+    video_id: i32,
+    files: &[FileInfo],
+) -> Result<()> {
+    if let Some(first) = files.first() {
+        set_value(db, video_id, KEY_FINAL_PATH, first.path.to_string_lossy()).await?;
+    }
+    let size: u64 = files.iter().map(|file| file.size).sum();
+    set_value(db, video_id, KEY_FINAL_SIZE, size).await?;
+    let json = serde_json::to_string(files).unwrap_or_default();
+    set_value(db, video_id, KEY_FILES, json).await
+}
+
+/// The files of the downloaded video, if they were recorded.
+pub async fn get_files<C: ConnectionTrait>(db: &C, video_id: i32) -> Result<Option<Vec<FileInfo>>> {
+    Ok(get_value(db, video_id, KEY_FILES)
+        .await?
+        .and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+/// Whether the video was downloaded, but could not be uploaded to the storage yet.
+pub async fn is_upload_pending<C: ConnectionTrait>(db: &C, video_id: i32) -> Result<bool> {
+    Ok(get_value(db, video_id, KEY_UPLOAD_PENDING)
+        .await?
+        .is_some_and(|x| x == "true"))
+}
@@ -0,0 +1,54 @@
+//! Optional storage the downloaded videos are uploaded to.
+//!
+//! Without a configured storage the videos only stay in the download folder.
+use crate::config::StorageConfig;
+use crate::prelude::*;
+use std::path::Path;
+
+#[cfg(feature = "s3")]
+pub mod s3;
+
+/// A place the downloaded videos are uploaded to.
+#[derive(Debug)]
+pub enum Storage {
+    /// An S3 compatible bucket.
+    #[cfg(feature = "s3")]
+    S3(s3::S3Storage),
+}
+
+impl Storage {
+    /// Creates the configured storage, if there is one.
+    ///
+    /// Fails if a storage is configured that the downloader was built without.
+    pub fn from_config(config: &StorageConfig) -> Result<Option<Self>> {
+        let Some(s3_config) = &config.s3 else {
+            return Ok(None);
+        };
+        #[cfg(feature = "s3")]
+        {
+            info!(
+                "Uploading the downloaded videos to the bucket {:?}",
+                s3_config.bucket
+            );
+            Ok(Some(Self::S3(s3::S3Storage::new(s3_config))))
+        }
+        #[cfg(not(feature = "s3"))]
+        {
+            let _ = s3_config;
+            Err(DownloaderError::LoadConfig(anyhow::anyhow!(
+                "storage.s3 is configured, but the downloader was built without the s3 feature"
+            )))
+        }
+    }
+
+    /// Uploads the file and returns the key it is stored under.
+    pub async fn upload(&self, path: &Path) -> Result<String> {
+        // there is nothing to upload to without any storage feature
+        #[cfg(not(feature = "s3"))]
+        let _ = path;
+        match *self {
+            #[cfg(feature = "s3")]
+            Self::S3(ref storage) => storage.upload(path).await,
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Uploads to S3 compatible buckets.
+use crate::config::S3Config;
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// The size of a single part of a multipart upload.
+///
+/// S3 needs at least 5 MiB for every part but the last and allows at most
+/// 10000 parts, so this is enough for files of up to ~156 GiB.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Self {
+        let credentials = Credentials::new(
+            &config.access_key_id,
+            &config.secret_access_key,
+            None,
+            None,
+            "twba_downloader",
+        );
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .endpoint_url(&config.endpoint)
+            .region(Region::new(config.region.clone()))
+            .credentials_provider(credentials)
+            // most self hosted servers don't support bucket subdomains
+            .force_path_style(true)
+            .build();
+        Self {
+            client: Client::from_conf(s3_config),
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.clone(),
+        }
+    }
+
+    /// Uploads the file in parts, so it never has to be in memory completely.
+    ///
+    /// The upload is aborted if a part fails, so no incomplete uploads are left
+    /// in the bucket.
+    #[tracing::instrument(skip(self))]
+    pub async fn upload(&self, path: &Path) -> Result<String> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| DownloaderError::Storage(anyhow::anyhow!("{:?} is no file", path)))?;
+        let key = format!("{}{}", self.prefix, name.to_string_lossy());
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(|e| DownloaderError::Storage(e.into()))?;
+        let upload_id = upload.upload_id().ok_or_else(|| {
+            DownloaderError::Storage(anyhow::anyhow!("the server did not return an upload id"))
+        })?;
+
+        let parts = match self.upload_parts(path, &key, upload_id).await {
+            Ok(parts) => parts,
+            Err(err) => {
+                if let Err(abort_err) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    warn!("Could not abort the upload of {}: {:?}", key, abort_err);
+                }
+                return Err(err);
+            }
+        };
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| DownloaderError::Storage(e.into()))?;
+        info!("Uploaded {:?} to {}/{}", path, self.bucket, key);
+        Ok(key)
+    }
+
+    async fn upload_parts(
+        &self,
+        path: &Path,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Vec<CompletedPart>> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .map_err(DownloadFileError::Read)?;
+        let mut parts = vec![];
+        for part_number in 1.. {
+            let mut buffer = Vec::with_capacity(PART_SIZE);
+            (&mut file)
+                .take(PART_SIZE as u64)
+                .read_to_end(&mut buffer)
+                .await
+                .map_err(DownloadFileError::Read)?;
+            // an empty file still needs one (empty) part
+            if buffer.is_empty() && part_number > 1 {
+                break;
+            }
+            let last = buffer.len() < PART_SIZE;
+            trace!("uploading part {} of {}", part_number, key);
+            let uploaded = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(buffer))
+                .send()
+                .await
+                .map_err(|e| DownloaderError::Storage(e.into()))?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(uploaded.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+            if last {
+                break;
+            }
+        }
+        Ok(parts)
+    }
+}
@@ -0,0 +1,133 @@
+//! The summary of a run over all queued videos.
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use crate::twitch::progress::{format_bytes, format_duration};
+use serde::Serialize;
+use std::path::Path;
+
+/// What happened to a single video during a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Downloaded,
+    Failed,
+    /// Not attempted because there was not enough disk space or the run was cancelled.
+    Skipped,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoOutcome {
+    /// The id of the video in the database.
+    pub id: i32,
+    pub title: String,
+    pub outcome: Outcome,
+    /// The size of the downloaded files in bytes.
+    pub bytes: Option<u64>,
+    /// The duration of the video in seconds.
+    pub duration: Option<f32>,
+    pub error: Option<String>,
+}
+
+/// The outcome of every video of a run of
+/// [`crate::client::DownloaderClient::download_not_downloaded_videos`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunSummary {
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub finished_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub videos: Vec<VideoOutcome>,
+    /// Failed videos that will not be retried anymore.
+    pub permanently_failed: Vec<i32>,
+    /// The bytes of all parts that were downloaded during the run.
+    pub bytes_downloaded: u64,
+    /// The configured limit for `bytes_downloaded`.
+    pub max_bytes_per_run: Option<u64>,
+    /// The requests that twitch answered with `429 Too Many Requests`.
+    pub throttled_requests: u64,
+}
+
+impl RunSummary {
+    pub fn new() -> Self {
+        Self {
+            started_at: Some(chrono::Utc::now()),
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, outcome: VideoOutcome) {
+        self.videos.push(outcome);
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.videos.iter().filter(|v| v.outcome == outcome).count()
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.count(Outcome::Failed) > 0
+    }
+
+    pub fn finish(&mut self) {
+        self.finished_at = Some(chrono::Utc::now());
+    }
+
+    /// Logs one line per video and the totals.
+    pub fn log(&self) {
+        if self.videos.is_empty() {
+            info!("Run summary: no videos were processed");
+            return;
+        }
+        info!("Run summary:");
+        info!(
+            "{:>6}  {:<10}  {:>10}  {:>8}  title / error",
+            "id", "outcome", "size", "duration"
+        );
+        for video in &self.videos {
+            let outcome = format!("{:?}", video.outcome);
+            let size = video.bytes.map(format_bytes).unwrap_or_default();
+            let duration = video
+                .duration
+                .map(|d| format_duration(d as u64))
+                .unwrap_or_default();
+            let details = match &video.error {
+                Some(error) => format!("{} ({})", video.title, error),
+                None => video.title.clone(),
+            };
+            info!(
+                "{:>6}  {:<10}  {:>10}  {:>8}  {}",
+                video.id, outcome, size, duration, details
+            );
+        }
+        let bytes: u64 = self.videos.iter().filter_map(|v| v.bytes).sum();
+        info!(
+            "{} downloaded ({}), {} failed, {} skipped, {} cancelled",
+            self.count(Outcome::Downloaded),
+            format_bytes(bytes),
+            self.count(Outcome::Failed),
+            self.count(Outcome::Skipped),
+            self.count(Outcome::Cancelled)
+        );
+        match self.max_bytes_per_run {
+            Some(limit) => info!(
+                "{} of the limit of {} were used",
+                format_bytes(self.bytes_downloaded),
+                format_bytes(limit)
+            ),
+            None => info!("{} were transferred", format_bytes(self.bytes_downloaded)),
+        }
+        if self.throttled_requests > 0 {
+            warn!(
+                "Twitch rate limited {} requests, consider lowering twitch.api_requests_per_minute",
+                self.throttled_requests
+            );
+        }
+    }
+
+    /// Writes the summary as json, replacing the previous one.
+    pub async fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(DownloaderError::SummaryJson)?;
+        tokio::fs::write(path, json)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Helpers for the tests: a config, an in-memory database and a downloader
+//! that does not touch twitch.
+use crate::config::DownloaderConfig;
+use crate::downloader::VideoDownloader;
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use crate::state;
+use crate::twitch::helix::VideoInfo;
+use crate::twitch::{DownloadInfo, DownloadOptions, DownloadedVideo};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::ActiveValue::Set;
+use twba_local_db::re_exports::sea_orm::{ActiveModelTrait, DatabaseConnection, EntityTrait};
+
+/// The default twba config, downloading into `folder`.
+pub fn conf(folder: &Path) -> Conf {
+    let mut conf = twba_backup_config::get_default_builder()
+        .load()
+        .expect("the default config can be loaded");
+    conf.download_folder_path = folder.to_string_lossy().to_string();
+    conf.db_url = "sqlite::memory:".to_string();
+    conf
+}
+
+/// An empty database that only lives as long as the connection.
+pub async fn database() -> DatabaseConnection {
+    let url = "sqlite::memory:".to_string();
+    let db = twba_local_db::open_database(Some(&url))
+        .await
+        .expect("the database can be opened");
+    twba_local_db::migrate_db(&db)
+        .await
+        .expect("the database can be migrated");
+    state::init(&db)
+        .await
+        .expect("the state table can be created");
+    db
+}
+
+pub async fn insert_user(db: &DatabaseConnection, id: i32, twitch_name: &str) -> UsersModel {
+    UsersActiveModel {
+        id: Set(id),
+        twitch_name: Set(twitch_name.to_string()),
+        active: Set(true),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .expect("the user can be inserted")
+}
+
+/// Inserts a video with the twitch id `v<id>`, the higher the id the newer the video.
+pub async fn insert_video(
+    db: &DatabaseConnection,
+    id: i32,
+    user_id: i32,
+    status: Status,
+) -> VideosModel {
+    VideosActiveModel {
+        id: Set(id),
+        twitch_id: Set(format!("v{}", id)),
+        name: Set(format!("video {}", id)),
+        duration: Set(0),
+        status: Set(status),
+        user_id: Set(user_id),
+        created_at: Set(format!("2024-01-01T00:{:02}:00+00:00", id % 60)),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .expect("the video can be inserted")
+}
+
+pub async fn get_video(db: &DatabaseConnection, id: i32) -> VideosModel {
+    Videos::find_by_id(id)
+        .one(db)
+        .await
+        .expect("the video can be read")
+        .expect("the video exists")
+}
+
+/// Downloads every video instantly by writing a small file, or fails the
+/// videos it was told to fail.
+#[derive(Debug)]
+pub struct MockDownloader {
+    config: Conf,
+    pub settings: DownloaderConfig,
+    failing: HashSet<String>,
+    downloaded: Mutex<Vec<String>>,
+    /// The twitch ids and qualities of the inspected videos.
+    inspected: Mutex<Vec<(String, String)>>,
+    /// Changes the status of every video while it is downloaded.
+    status_during_download: Option<(DatabaseConnection, Status)>,
+}
+
+impl MockDownloader {
+    pub fn new(folder: &Path) -> Self {
+        Self {
+            config: conf(folder),
+            settings: DownloaderConfig::default(),
+            failing: HashSet::new(),
+            downloaded: Mutex::new(vec![]),
+            inspected: Mutex::new(vec![]),
+            status_during_download: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: Conf) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Fails every download of the video with this twitch id.
+    pub fn fail(mut self, video_id: &str) -> Self {
+        self.failing.insert(video_id.to_string());
+        self
+    }
+
+    /// Changes the status of the video in the database while it is
+    /// downloaded, like another instance would.
+    pub fn change_status_during_download(
+        mut self,
+        db: &DatabaseConnection,
+        status: Status,
+    ) -> Self {
+        self.status_during_download = Some((db.clone(), status));
+        self
+    }
+
+    /// The twitch ids of all videos that were downloaded (or tried to), in order.
+    pub fn downloaded(&self) -> Vec<String> {
+        self.downloaded.lock().unwrap().clone()
+    }
+
+    /// The twitch ids and qualities of all videos that were inspected, in order.
+    pub fn inspected(&self) -> Vec<(String, String)> {
+        self.inspected.lock().unwrap().clone()
+    }
+}
+
+pub fn download_info(quality: &str) -> DownloadInfo {
+    DownloadInfo {
+        quality: quality.to_string(),
+        requested_quality: None,
+        vod_age: None,
+        streamed_at: None,
+        parts: vec![],
+        base_url: String::new(),
+        estimated_size: None,
+        total_duration: 60.0,
+        part_count: 0,
+        raw_playlist: None,
+    }
+}
+
+impl VideoDownloader for MockDownloader {
+    fn config(&self) -> &Conf {
+        &self.config
+    }
+
+    fn settings(&self) -> &DownloaderConfig {
+        &self.settings
+    }
+
+    async fn download_video(
+        &self,
+        id: &str,
+        video_id: &str,
+        quality: &str,
+        output_folder: &Path,
+        cancel: &CancellationToken,
+        _options: DownloadOptions,
+    ) -> Result<DownloadedVideo> {
+        self.downloaded.lock().unwrap().push(video_id.to_string());
+        if cancel.is_cancelled() {
+            return Err(DownloaderError::Cancelled);
+        }
+        if let Some((db, status)) = &self.status_during_download {
+            VideosActiveModel {
+                id: Set(id.parse().expect("the id of a video is a number")),
+                status: Set(status.clone()),
+                ..Default::default()
+            }
+            .update(db)
+            .await
+            .expect("the status can be changed");
+        }
+        if self.failing.contains(video_id) {
+            return Err(DownloadFileError::FailedParts(vec![format!("{}/0.ts", video_id)]).into());
+        }
+        let extension = self.settings.twitch.output_container.extension();
+        let path = output_folder.join(format!("{}.{}", id, extension));
+        tokio::fs::write(&path, video_id.as_bytes())
+            .await
+            .map_err(DownloadFileError::Write)?;
+        Ok(DownloadedVideo {
+            path,
+            info: download_info(quality),
+            measured_duration: Some(60.0),
+            chunks: vec![],
+            gaps: vec![],
+        })
+    }
+
+    async fn get_video_info(&self, _video_id: &str) -> Result<Option<VideoInfo>> {
+        Ok(None)
+    }
+
+    async fn inspect_video(&self, video_id: &str, quality: &str) -> Result<DownloadInfo> {
+        self.inspected
+            .lock()
+            .unwrap()
+            .push((video_id.to_string(), quality.to_string()));
+        Ok(download_info(quality))
+    }
+
+    async fn download_thumbnail(
+        &self,
+        _id: &str,
+        video_id: &str,
+        _output_folder: &Path,
+    ) -> Result<PathBuf> {
+        Err(DownloaderError::ThumbnailNotFound(video_id.to_string()))
+    }
+
+    async fn download_chat(&self, _video_id: &str, _output_path: &Path) -> Result<usize> {
+        Ok(0)
+    }
+}
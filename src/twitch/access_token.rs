@@ -2,7 +2,13 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TwitchVideoAccessTokenResponse {
-    pub data: VideoAccessTokenResponseData,
+    pub data: Option<VideoAccessTokenResponseData>,
+    pub errors: Option<Vec<GqlError>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GqlError {
+    pub message: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
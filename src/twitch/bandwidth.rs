@@ -0,0 +1,169 @@
+use crate::prelude::*;
+use crate::twitch::progress::format_bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// A token bucket that limits the download speed across all parts of a video.
+///
+/// Bytes are consumed after they were received, so a single chunk can go
+/// over the limit, which is then paid back by waiting before the next chunk.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_second: u64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    /// Creates a new limiter. A limit of 0 means unlimited.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            state: Mutex::new(BucketState {
+                tokens: bytes_per_second as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Consumes the given amount of bytes and waits if the limit was exceeded.
+    ///
+    /// Returns how long it waited.
+    pub async fn consume(&self, bytes: u64) -> Duration {
+        if self.bytes_per_second == 0 {
+            return Duration::ZERO;
+        }
+        let wait = {
+            let mut state = self.state.lock().expect("bandwidth limiter mutex poisoned");
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            let rate = self.bytes_per_second as f64;
+            // at most one second worth of bytes can be saved up
+            state.tokens = (state.tokens + elapsed * rate).min(rate);
+            state.last_refill = now;
+            state.tokens -= bytes as f64;
+            if state.tokens < 0.0 {
+                Some(Duration::from_secs_f64(-state.tokens / rate))
+            } else {
+                None
+            }
+        };
+        match wait {
+            Some(wait) => {
+                tokio::time::sleep(wait).await;
+                wait
+            }
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// Spaces out requests to the twitch api evenly across all videos, so a
+/// burst of requests does not trip the rate limit of twitch.
+#[derive(Debug)]
+pub struct RequestLimiter {
+    interval: Duration,
+    /// When the next request may be sent.
+    next: Mutex<Instant>,
+    throttled: AtomicU64,
+}
+
+impl RequestLimiter {
+    /// Creates a new limiter. A limit of 0 means unlimited.
+    pub fn new(requests_per_minute: u32) -> Self {
+        let interval = match requests_per_minute {
+            0 => Duration::ZERO,
+            limit => Duration::from_secs(60) / limit,
+        };
+        Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits until the next request may be sent.
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().expect("request limiter mutex poisoned");
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// Pauses all requests after twitch answered that there were too many.
+    pub fn throttle(&self, pause: Duration) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        let mut next = self.next.lock().expect("request limiter mutex poisoned");
+        *next = (*next).max(Instant::now() + pause);
+    }
+
+    /// How many requests were answered with `429 Too Many Requests`.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the bytes that were downloaded during a run across all videos,
+/// to stop once `max_bytes_per_run` is reached.
+#[derive(Debug)]
+pub struct ByteBudget {
+    used: AtomicU64,
+    limit: Option<u64>,
+    reached: CancellationToken,
+}
+
+impl ByteBudget {
+    /// Creates a new budget. Without a limit the bytes are only counted.
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            used: AtomicU64::new(0),
+            limit,
+            reached: CancellationToken::new(),
+        }
+    }
+
+    /// Adds the downloaded bytes.
+    pub fn consume(&self, bytes: u64) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let Some(limit) = self.limit else {
+            return;
+        };
+        if used >= limit && !self.reached.is_cancelled() {
+            info!(
+                "Reached the limit of {} for this run after {}",
+                format_bytes(limit),
+                format_bytes(used)
+            );
+            self.reached.cancel();
+        }
+    }
+
+    /// The bytes that were downloaded so far.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    pub fn is_reached(&self) -> bool {
+        self.reached.is_cancelled()
+    }
+
+    /// A token that is cancelled once the limit is reached.
+    pub fn reached_token(&self) -> CancellationToken {
+        self.reached.clone()
+    }
+}
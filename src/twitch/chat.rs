@@ -0,0 +1,180 @@
+use super::TwitchClient;
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+/// The hash of the persisted `VideoCommentsByOffsetOrCursor` GQL query.
+const VIDEO_COMMENTS_QUERY_HASH: &str =
+    "b70a3591ff0f4e0313d126c6a1502d79a1c02baebb288227c582044aa76adf6a";
+
+/// A single chat message as it is written to the chat file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatComment {
+    /// The time since the start of the video in seconds.
+    pub offset_seconds: u64,
+    pub commenter: Option<String>,
+    pub fragments: Vec<String>,
+    pub badges: Vec<ChatBadge>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatBadge {
+    #[serde(rename = "setID")]
+    pub set_id: String,
+    pub version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoCommentsResponse {
+    data: Option<VideoCommentsResponseData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoCommentsResponseData {
+    video: Option<VideoCommentsResponseVideo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoCommentsResponseVideo {
+    comments: Option<VideoComments>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoComments {
+    edges: Vec<VideoCommentEdge>,
+    #[serde(rename = "pageInfo")]
+    page_info: PageInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct PageInfo {
+    #[serde(rename = "hasNextPage")]
+    has_next_page: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoCommentEdge {
+    cursor: Option<String>,
+    node: VideoCommentNode,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoCommentNode {
+    #[serde(rename = "contentOffsetSeconds")]
+    content_offset_seconds: u64,
+    commenter: Option<Commenter>,
+    message: Option<CommentMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commenter {
+    #[serde(rename = "displayName")]
+    display_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentMessage {
+    #[serde(default)]
+    fragments: Vec<CommentFragment>,
+    #[serde(rename = "userBadges", default)]
+    user_badges: Vec<ChatBadge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentFragment {
+    text: String,
+}
+
+impl From<VideoCommentNode> for ChatComment {
+    fn from(node: VideoCommentNode) -> Self {
+        let (fragments, badges) = match node.message {
+            Some(message) => (
+                message.fragments.into_iter().map(|f| f.text).collect(),
+                message.user_badges,
+            ),
+            None => (vec![], vec![]),
+        };
+        Self {
+            offset_seconds: node.content_offset_seconds,
+            commenter: node.commenter.map(|c| c.display_name),
+            fragments,
+            badges,
+        }
+    }
+}
+
+impl TwitchClient {
+    /// Downloads the chat replay of a video as newline delimited json.
+    ///
+    /// If the video has no chat (or it is disabled), an empty file is written.
+    /// Returns the amount of written comments.
+    #[tracing::instrument(skip(self))]
+    pub async fn download_chat<VideoId: DIntoString>(
+        &self,
+        video_id: VideoId,
+        output_path: &Path,
+    ) -> Result<usize> {
+        let video_id = video_id.into();
+        let mut file = tokio::fs::File::create(output_path)
+            .await
+            .map_err(DownloadFileError::FileCreation)?;
+        let mut cursor: Option<String> = None;
+        let mut count = 0;
+        loop {
+            let variables = match &cursor {
+                Some(cursor) => json!({ "videoID": video_id, "cursor": cursor }),
+                None => json!({ "videoID": video_id, "contentOffsetSeconds": 0 }),
+            };
+            let body = json!({
+                "operationName": "VideoCommentsByOffsetOrCursor",
+                "variables": variables,
+                "extensions": {
+                    "persistedQuery": {
+                        "version": 1,
+                        "sha256Hash": VIDEO_COMMENTS_QUERY_HASH
+                    }
+                }
+            })
+            .to_string();
+            let json = self.execute_gql(body).await?;
+            let response: VideoCommentsResponse =
+                serde_json::from_str(&json).map_err(DownloaderError::ChatJsonParse)?;
+            let Some(comments) = response
+                .data
+                .and_then(|data| data.video)
+                .and_then(|video| video.comments)
+            else {
+                debug!("video {} has no (more) chat comments", video_id);
+                break;
+            };
+
+            let mut last_cursor = None;
+            for edge in comments.edges {
+                last_cursor = edge.cursor;
+                let comment = ChatComment::from(edge.node);
+                let mut line = serde_json::to_vec(&comment).map_err(|e| {
+                    DownloadFileError::Write(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        e,
+                    ))
+                })?;
+                line.push(b'\n');
+                file.write_all(&line)
+                    .await
+                    .map_err(DownloadFileError::Write)?;
+                count += 1;
+            }
+            trace!("downloaded {} chat comments so far", count);
+
+            match last_cursor {
+                Some(next) if comments.page_info.has_next_page => cursor = Some(next),
+                _ => break,
+            }
+        }
+        file.flush().await.map_err(DownloadFileError::Write)?;
+        Ok(count)
+    }
+}
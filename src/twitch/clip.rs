@@ -0,0 +1,264 @@
+use super::bandwidth::{BandwidthLimiter, ByteBudget};
+use super::parts_util::{require_space, validate_mp4};
+use super::{DownloadInfo, DownloadedVideo, TwitchClient};
+use crate::errors::{DownloadFileError, MalformedPlaylistError};
+use crate::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+
+/// The hash of the persisted `VideoAccessToken_Clip` GQL query.
+const CLIP_ACCESS_TOKEN_QUERY_HASH: &str =
+    "36b89d2507fce29e5ca551df756d27c1cfe079e2609642b4390aa4c35796eb11";
+
+#[derive(Debug, Deserialize)]
+struct ClipAccessTokenResponse {
+    data: Option<ClipAccessTokenResponseData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipAccessTokenResponseData {
+    clip: Option<Clip>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Clip {
+    #[serde(rename = "playbackAccessToken")]
+    playback_access_token: Option<ClipPlaybackAccessToken>,
+    #[serde(rename = "videoQualities", default)]
+    video_qualities: Vec<ClipQuality>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipPlaybackAccessToken {
+    value: String,
+    signature: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipQuality {
+    #[serde(rename = "frameRate")]
+    frame_rate: f32,
+    quality: String,
+    #[serde(rename = "sourceURL")]
+    source_url: String,
+}
+
+impl ClipQuality {
+    /// The name of the quality in the same format as the video qualities (e.g. `720p60`).
+    fn name(&self) -> String {
+        format!("{}p{}", self.quality, self.frame_rate.round() as u32)
+    }
+}
+
+/// Whether the id belongs to a clip (clips have slugs, videos have numeric ids).
+///
+/// An empty id is neither.
+pub fn is_clip_id(id: &str) -> bool {
+    !id.is_empty() && !id.chars().all(|c| c.is_ascii_digit())
+}
+
+impl TwitchClient {
+    /// Downloads a clip directly as a single mp4 file to `<id>.mp4` in the output folder.
+    ///
+    /// Like the parts of a video, the clip is limited by `max_download_bytes_per_sec`,
+    /// counts towards the byte budget of the run and is only downloaded if there
+    /// is enough space for it.
+    #[tracing::instrument(skip(self, cancel, byte_budget))]
+    pub(super) async fn download_clip(
+        &self,
+        id: &str,
+        slug: &str,
+        quality: &str,
+        output_folder: &Path,
+        cancel: &CancellationToken,
+        byte_budget: Option<&ByteBudget>,
+    ) -> Result<DownloadedVideo> {
+        let final_path = output_folder.join(format!("{}.mp4", id));
+        if final_path.exists() {
+            return self.existing_download(final_path, quality).await;
+        }
+        let body = json!({
+            "operationName": "VideoAccessToken_Clip",
+            "variables": { "slug": slug },
+            "extensions": {
+                "persistedQuery": {
+                    "version": 1,
+                    "sha256Hash": CLIP_ACCESS_TOKEN_QUERY_HASH
+                }
+            }
+        })
+        .to_string();
+        let json = self.execute_gql(body).await?;
+        let response: ClipAccessTokenResponse =
+            serde_json::from_str(&json).map_err(DownloaderError::AccessTokenJsonParse)?;
+        let clip = response
+            .data
+            .and_then(|data| data.clip)
+            .ok_or_else(|| DownloaderError::VideoNotFound(slug.to_string()))?;
+        let token = clip
+            .playback_access_token
+            .ok_or(DownloaderError::AccessTokenEmpty)?;
+
+        // the qualities are ordered from highest to lowest
+        let chosen = match clip.video_qualities.iter().find(|q| q.name() == quality) {
+            Some(chosen) => chosen,
+            None => {
+                let highest = clip
+                    .video_qualities
+                    .first()
+                    .ok_or(MalformedPlaylistError::NoQualities)?;
+                if quality != "max" {
+                    warn!(
+                        "Given quality not found ({}), using highest quality: {}",
+                        quality,
+                        highest.name()
+                    );
+                }
+                highest
+            }
+        };
+        info!("Downloading clip {} in quality {}", slug, chosen.name());
+
+        let request = self
+            .client
+            .get(&chosen.source_url)
+            .query(&[("sig", &token.signature), ("token", &token.value)])
+            .build()?;
+        // an expired signature or a deleted clip must not end up in the file
+        let mut response = self
+            .retry
+            .execute(&self.client, request)
+            .await?
+            .error_for_status()?;
+        require_space(
+            output_folder,
+            response.content_length().unwrap_or(0) + self.settings.min_free_space_bytes,
+        )?;
+        let limiter = BandwidthLimiter::new(self.settings.twitch.max_download_bytes_per_sec);
+        let tmp_path = final_path.with_extension("mp4.part");
+        let mut file = tokio::fs::File::create(&tmp_path)
+            .await
+            .map_err(DownloadFileError::FileCreation)?;
+        loop {
+            let chunk = tokio::select! {
+                chunk = response.chunk() => chunk.map_err(DownloadFileError::DownloadReqwest)?,
+                _ = cancel.cancelled() => {
+                    drop(file);
+                    if let Err(err) = tokio::fs::remove_file(&tmp_path).await {
+                        warn!("Could not remove {:?}: {:?}", tmp_path, err);
+                    }
+                    return Err(DownloaderError::Cancelled);
+                }
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            file.write_all(&chunk)
+                .await
+                .map_err(DownloadFileError::Write)?;
+            if let Some(byte_budget) = byte_budget {
+                byte_budget.consume(chunk.len() as u64);
+            }
+            limiter.consume(chunk.len() as u64).await;
+        }
+        file.flush().await.map_err(DownloadFileError::Write)?;
+        tokio::fs::rename(&tmp_path, &final_path)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+        // the length of a clip is not known up front, it only has to be readable
+        let measured_duration = validate_mp4(&final_path, 0.0, &self.settings.twitch).await?;
+
+        Ok(DownloadedVideo {
+            path: final_path,
+            info: DownloadInfo {
+                quality: chosen.name(),
+                requested_quality: None,
+                vod_age: None,
+                streamed_at: None,
+                parts: vec![],
+                base_url: chosen.source_url.clone(),
+                estimated_size: None,
+                total_duration: 0.0,
+                part_count: 1,
+                raw_playlist: None,
+            },
+            measured_duration: Some(measured_duration),
+            chunks: vec![],
+            gaps: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DownloaderConfig;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn only_slugs_are_clip_ids() {
+        assert!(is_clip_id("AwkwardHelplessSalamanderSwiftRage"));
+        assert!(is_clip_id("Slug-abc_123"));
+        assert!(!is_clip_id("123456789"));
+        assert!(!is_clip_id(""));
+    }
+
+    #[tokio::test]
+    async fn an_error_response_is_not_saved_as_the_clip() {
+        let server = MockServer::start().await;
+        let access_token = json!({
+            "data": {
+                "clip": {
+                    "playbackAccessToken": { "value": "token", "signature": "sig" },
+                    "videoQualities": [{
+                        "frameRate": 60.0,
+                        "quality": "1080",
+                        "sourceURL": format!("{}/clip.mp4", server.uri())
+                    }]
+                }
+            }
+        });
+        Mock::given(method("POST"))
+            .and(path("/gql"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(access_token))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/clip.mp4"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("not found"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let output = tempfile::tempdir().unwrap();
+        let mut settings = DownloaderConfig::default();
+        settings.min_free_space_bytes = 0;
+        settings.twitch.gql_url = format!("{}/gql", server.uri());
+        settings.twitch.api_requests_per_minute = 0;
+        let client = TwitchClient::new(crate::test_util::conf(output.path()), settings).unwrap();
+        let budget = ByteBudget::new(None);
+
+        let result = client
+            .download_clip(
+                "1",
+                "AwkwardHelplessSalamanderSwiftRage",
+                "max",
+                output.path(),
+                &CancellationToken::new(),
+                Some(&budget),
+            )
+            .await;
+
+        assert!(
+            matches!(result, Err(DownloaderError::Reqwest(_))),
+            "{:?}",
+            result
+        );
+        assert!(!output.path().join("1.mp4").exists());
+        assert!(!output.path().join("1.mp4.part").exists());
+        assert_eq!(budget.used(), 0);
+    }
+}
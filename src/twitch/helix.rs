@@ -0,0 +1,93 @@
+use super::TwitchClient;
+use crate::prelude::*;
+use serde::Deserialize;
+
+/// Information about a video from the Helix api.
+#[derive(Debug, Clone)]
+pub struct VideoInfo {
+    pub title: String,
+    /// The duration in seconds.
+    pub duration: u64,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub view_count: u64,
+    pub user_login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HelixVideosResponse {
+    data: Vec<HelixVideo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HelixVideo {
+    title: String,
+    /// Something like `3h8m33s`.
+    duration: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    view_count: u64,
+    user_login: String,
+}
+
+impl TwitchClient {
+    /// Gets the current information about a video from the Helix api.
+    ///
+    /// Returns `None` if no Helix credentials are configured. Fails with
+    /// [`DownloaderError::VideoNotFound`] if the video does not exist (anymore).
+    #[tracing::instrument(skip(self))]
+    pub async fn get_video_info<VideoId: DIntoString>(
+        &self,
+        video_id: VideoId,
+    ) -> Result<Option<VideoInfo>> {
+        let video_id = video_id.into();
+        let settings = &self.settings.twitch;
+        let (Some(client_id), Some(token)) =
+            (&settings.helix_client_id, &settings.helix_access_token)
+        else {
+            return Ok(None);
+        };
+        let url = format!("{}/videos", settings.helix_url.trim_end_matches('/'));
+        let request = self
+            .client
+            .get(url)
+            .query(&[("id", &video_id)])
+            .header("Client-Id", client_id)
+            .header("Authorization", format!("Bearer {}", token))
+            .build()?;
+        let response = self.retry.execute(&self.client, request).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(DownloaderError::VideoNotFound(video_id));
+        }
+        let response = response.error_for_status()?;
+        let json = response.text().await?;
+        let response: HelixVideosResponse =
+            serde_json::from_str(&json).map_err(DownloaderError::HelixJsonParse)?;
+        let video = response
+            .data
+            .into_iter()
+            .next()
+            .ok_or_else(|| DownloaderError::VideoNotFound(video_id.clone()))?;
+        Ok(Some(VideoInfo {
+            duration: parse_helix_duration(&video.duration),
+            title: video.title,
+            created_at: video.created_at,
+            view_count: video.view_count,
+            user_login: video.user_login,
+        }))
+    }
+}
+
+/// Parses a duration like `3h8m33s` to seconds.
+fn parse_helix_duration(duration: &str) -> u64 {
+    let mut total = 0;
+    let mut number = 0;
+    for c in duration.chars() {
+        match c {
+            '0'..='9' => number = number * 10 + c.to_digit(10).unwrap_or_default() as u64,
+            'h' => total += std::mem::take(&mut number) * 3600,
+            'm' => total += std::mem::take(&mut number) * 60,
+            's' => total += std::mem::take(&mut number),
+            _ => {}
+        }
+    }
+    total
+}
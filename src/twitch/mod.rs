@@ -1,105 +1,634 @@
-use futures_util::{StreamExt, TryStreamExt};
+use futures_util::StreamExt;
 use serde_json::json;
-use std::collections::HashMap;
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
+use tokio::sync::mpsc;
 use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 use twba_reqwest_backoff::ReqwestClient;
 
+use crate::config::{redact_url, DownloaderConfig, OutputContainer, TwitchDownloaderConfig};
 use crate::errors::*;
 use crate::prelude::*;
 
 mod access_token;
+pub mod bandwidth;
+pub mod chat;
+pub mod clip;
+pub mod helix;
+mod thumbnail;
+use crate::twitch::bandwidth::{BandwidthLimiter, ByteBudget, RequestLimiter};
+use crate::twitch::part_manifest::{
+    matches_entry, read_manifest, verify_against_manifest, ManifestEntry, ManifestWriter,
+};
 use crate::twitch::parts_util::*;
+use crate::twitch::progress::{
+    format_bytes, format_duration, DownloadPhase, DownloadProgress, PartProgress,
+};
+use crate::twitch::retry::RetryPolicy;
 use crate::twitch::twitch_utils::*;
-use access_token::TwitchVideoAccessTokenResponse;
+use access_token::{TwitchVideoAccessTokenResponse, VideoAccessTokenResponseDataAccessToken};
+use thumbnail::TwitchVideoThumbnailResponse;
 
+mod part_manifest;
 mod parts_util;
+pub(crate) use parts_util::existing_split_files;
+pub mod progress;
+mod retry;
 pub mod twitch_utils;
 
-#[derive(Debug)]
 pub struct TwitchClient {
     client: ReqwestClient,
+    /// The client for downloading the parts, which might use a different proxy.
+    cdn_client: ReqwestClient,
+    retry: RetryPolicy,
+    /// Limits the access token and playlist requests.
+    api_limiter: RequestLimiter,
     pub config: Conf,
+    pub settings: DownloaderConfig,
 }
+/// Only shows the parts that can't contain secrets, since this ends up in the logs.
+impl Debug for TwitchClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TwitchClient")
+            .field("retry", &self.retry)
+            .finish_non_exhaustive()
+    }
+}
+/// The Client-ID of the twitch website, which works for all public videos.
+const DEFAULT_CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
+/// After how many parts in a row that the CDN refused the access token is refreshed.
+const FORBIDDEN_PARTS_BEFORE_REFRESH: u32 = 3;
+/// How often a request that twitch answered with `429 Too Many Requests` is sent again.
+const RATE_LIMITED_ATTEMPTS: u32 = 3;
+/// How often the access token is refreshed during the download of a video before giving up.
+const MAX_TOKEN_REFRESHES: u32 = 3;
+/// After how many downloaded parts the free disk space is checked again.
+const DISK_CHECK_INTERVAL_PARTS: u64 = 20;
+
 //region public functions
 impl TwitchClient {
-    #[tracing::instrument]
-    pub fn new(config: Conf) -> Self {
-        let client = reqwest::Client::new().into();
-        Self { client, config }
+    #[tracing::instrument(skip_all)]
+    pub fn new(config: Conf, settings: DownloaderConfig) -> Result<Self> {
+        let proxy = settings.twitch.proxy_url.as_deref();
+        let cdn_proxy = settings.twitch.cdn_proxy_url.as_deref().or(proxy);
+        info!("Using proxy: {}", describe_proxy(proxy));
+        info!("Using CDN proxy: {}", describe_proxy(cdn_proxy));
+        if config.twitch.downloader_id.is_empty() {
+            warn!("twitch.downloader_id is not configured, using the default GQL Client-ID");
+        } else {
+            info!("Using the configured GQL Client-ID");
+        }
+        let retry = RetryPolicy::from_config(&settings.twitch);
+        info!(
+            "Retrying requests up to {} times with a delay of {:?} to {:?}",
+            retry.max_attempts, retry.initial_delay, retry.max_delay
+        );
+        let headers = settings.twitch.headers()?;
+        if !headers.is_empty() {
+            info!("Sending {} extra headers with every request", headers.len());
+        }
+        let client = build_client(proxy, &headers)?;
+        let cdn_client = build_client(cdn_proxy, &headers)?;
+        let api_limiter = RequestLimiter::new(settings.twitch.api_requests_per_minute);
+        Ok(Self {
+            client,
+            cdn_client,
+            retry,
+            api_limiter,
+            config,
+            settings,
+        })
     }
+    /// Makes sure ffmpeg can be started, so videos don't fail after all parts were downloaded.
     #[tracing::instrument(skip(self))]
-    pub async fn download_video<VideoId: DIntoString, QUALITY: DIntoString>(
+    pub async fn check_ffmpeg(&self) -> Result<()> {
+        let ffmpeg = &self.settings.twitch.ffmpeg_path;
+        let output = Command::new(ffmpeg)
+            .arg("-version")
+            .output()
+            .await
+            .map_err(|e| DownloaderError::FfmpegNotFound(ffmpeg.clone(), e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let version = stdout.lines().next().unwrap_or_default();
+        info!("Using ffmpeg at {:?}: {}", ffmpeg, version);
+        Ok(())
+    }
+    /// Gets everything that is known about a video without downloading anything.
+    pub async fn inspect_video<VideoId: DIntoString, QUALITY: DIntoString>(
         &self,
-        id: i32,
         video_id: VideoId,
         quality: QUALITY,
+    ) -> Result<DownloadInfo> {
+        self.get_download_info(video_id, quality).await
+    }
+    /// Downloads the thumbnail of a video to `<id>.jpg` in the output folder.
+    #[tracing::instrument(skip(self))]
+    pub async fn download_thumbnail<ID: DIntoString, VideoId: DIntoString>(
+        &self,
+        id: ID,
+        video_id: VideoId,
         output_folder: &Path,
     ) -> Result<PathBuf> {
+        let id = id.into();
+        let video_id = video_id.into();
+        let json = json!({
+            "query": "query VideoThumbnail($vodID: ID!) { video(id: $vodID) { previewThumbnailURL } }",
+            "variables": { "vodID": video_id }
+        })
+        .to_string();
+        let json = self.execute_gql(json).await?;
+        let response: TwitchVideoThumbnailResponse =
+            serde_json::from_str(&json).map_err(DownloaderError::ThumbnailJsonParse)?;
+        let url = response
+            .data
+            .video
+            .and_then(|video| video.preview_thumbnail_url)
+            .ok_or_else(|| DownloaderError::ThumbnailNotFound(video_id.clone()))?;
+        let width = self.settings.thumbnail_width.to_string();
+        let height = self.settings.thumbnail_height.to_string();
+        let url = url
+            .replace("%{width}", &width)
+            .replace("%{height}", &height)
+            .replace("{width}", &width)
+            .replace("{height}", &height);
+        debug!("Downloading thumbnail from {}", url);
+
+        let request = self.client.get(url).build()?;
+        let response = self.retry.execute(&self.client, request).await?;
+        let bytes = response.bytes().await?;
+        let path = output_folder.join(format!("{}.jpg", id));
+        tokio::fs::write(&path, bytes)
+            .await
+            .map_err(DownloadFileError::Write)?;
+        Ok(path)
+    }
+    #[tracing::instrument(skip(self))]
+    pub async fn download_video<ID: DIntoString, VideoId: DIntoString, QUALITY: DIntoString>(
+        &self,
+        id: ID,
+        video_id: VideoId,
+        quality: QUALITY,
+        output_folder: &Path,
+        cancel: &CancellationToken,
+        options: DownloadOptions,
+    ) -> Result<DownloadedVideo> {
+        let id = id.into();
         let video_id = video_id.into();
-        let folder_path = output_folder.join(id.to_string());
-        let final_path = output_folder.join(format!("{}.mp4", id));
+        let quality: String = quality.into();
+        if video_id.is_empty() {
+            return Err(VideoIdError::Invalid(video_id).into());
+        }
+        if clip::is_clip_id(&video_id) {
+            return self
+                .download_clip(
+                    &id,
+                    &video_id,
+                    &quality,
+                    output_folder,
+                    cancel,
+                    options.byte_budget.as_deref(),
+                )
+                .await;
+        }
+        let folder_path = self.settings.parts_folder(output_folder, &id);
+        let final_path = output_folder.join(format!(
+            "{}.{}",
+            id,
+            self.settings.twitch.output_container.extension()
+        ));
         if final_path.exists() {
-            return Err(DownloadFileError::TargetAlreadyExists(final_path).into());
+            return self.existing_download(final_path, &quality).await;
+        }
+        let split_files = existing_split_files(&final_path);
+        if !split_files.is_empty() {
+            return self
+                .existing_split_download(final_path, split_files, &quality)
+                .await;
         }
-        if !folder_path.exists() {
-            std::fs::create_dir_all(&folder_path)
-                .map_err(DownloadFileError::CouldNotCreateTargetFolder)?;
-        } else if !folder_path.is_dir() {
-            return Err(DownloadFileError::TargetFolderIsNotADirectory(folder_path).into());
+        DownloadProgress::report_phase(
+            options.progress.as_ref(),
+            &video_id,
+            DownloadPhase::FetchingPlaylist,
+        )
+        .await;
+        let cached = self
+            .cached_download_info(&video_id, &quality, &folder_path)
+            .await?;
+        let download_info = match cached {
+            Some(download_info) => download_info,
+            None => match self.get_download_info(&video_id, &quality).await {
+                Err(DownloaderError::MalformedPlaylist(
+                    MalformedPlaylistError::StillProcessing { .. },
+                )) if self.settings.twitch.wait_for_processing => {
+                    self.wait_for_vod_ready(
+                        &video_id,
+                        &quality,
+                        Duration::from_secs(self.settings.twitch.processing_poll_interval_secs),
+                        Duration::from_secs(self.settings.twitch.processing_max_wait_secs),
+                        cancel,
+                    )
+                    .await?;
+                    self.get_download_info(&video_id, &quality).await?
+                }
+                download_info => download_info?,
+            },
+        };
+        self.check_duration(&download_info)?;
+        self.check_disk_space(&download_info, output_folder)?;
+        if cancel.is_cancelled() {
+            return Err(DownloaderError::Cancelled);
+        }
+        let converted = self
+            .download_and_convert(&video_id, &download_info, &folder_path, cancel, options)
+            .await;
+        let (converted_path, measured_duration, gaps) = match converted {
+            Err(err)
+                if self.settings.temp_folder_path.is_some()
+                    && !matches!(
+                        err,
+                        DownloaderError::Cancelled | DownloaderError::ByteCapReached { .. }
+                    ) =>
+            {
+                // the temp folder is only scratch space, so nothing is left in it
+                if folder_path.exists() {
+                    if let Err(remove_err) = tokio::fs::remove_dir_all(&folder_path).await {
+                        warn!("Could not remove {:?}: {:?}", folder_path, remove_err);
+                    }
+                }
+                return Err(err);
+            }
+            converted => converted?,
+        };
+
+        move_file(&converted_path, &final_path).await?;
+        if self.settings.twitch.keep_parts {
+            info!("Kept the parts of the video in {:?}", folder_path);
         } else {
-            // folder exists and is a directory
-            if folder_path
-                .read_dir()
-                .map_err(DownloadFileError::Read)?
-                .next()
-                .is_some()
+            //clean up the leftover parts
+            tokio::fs::remove_dir_all(folder_path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+        let chunks =
+            split_video_if_too_long(&final_path, measured_duration, &self.settings.twitch).await?;
+        Ok(DownloadedVideo {
+            path: final_path,
+            info: download_info,
+            measured_duration: Some(measured_duration),
+            chunks,
+            gaps,
+        })
+    }
+}
+//endregion
+impl TwitchClient {
+    /// Uses a video that is already at its final path (e.g. because an earlier
+    /// run stopped right after moving it there) instead of downloading it again.
+    ///
+    /// The video is split if it is too long, since the earlier run might have
+    /// stopped (or failed) before that.
+    ///
+    /// Fails with [`DownloadFileError::TargetAlreadyExists`] if the file is
+    /// empty or can't be read.
+    async fn existing_download(
+        &self,
+        final_path: PathBuf,
+        quality: &str,
+    ) -> Result<DownloadedVideo> {
+        let duration = match probe_existing_video(&final_path, &self.settings.twitch).await {
+            Ok(duration) => duration,
+            Err(err) => {
+                warn!(
+                    "{:?} already exists but does not look like a complete video: {}",
+                    final_path, err
+                );
+                return Err(DownloadFileError::TargetAlreadyExists(final_path).into());
+            }
+        };
+        info!(
+            "{:?} already exists and is readable ({:.1}s), not downloading it again",
+            final_path, duration
+        );
+        let chunks = split_video_if_too_long(&final_path, duration, &self.settings.twitch).await?;
+        Ok(existing_video(final_path, chunks, quality, duration))
+    }
+
+    /// Uses the files an earlier run split the video into, like [`Self::existing_download`].
+    async fn existing_split_download(
+        &self,
+        final_path: PathBuf,
+        chunks: Vec<PathBuf>,
+        quality: &str,
+    ) -> Result<DownloadedVideo> {
+        let mut duration = 0.0;
+        for chunk in &chunks {
+            match probe_existing_video(chunk, &self.settings.twitch).await {
+                Ok(chunk_duration) => duration += chunk_duration,
+                Err(err) => {
+                    warn!(
+                        "{:?} already exists but does not look like a complete video: {}",
+                        chunk, err
+                    );
+                    return Err(DownloadFileError::TargetAlreadyExists(chunk.clone()).into());
+                }
+            }
+        }
+        // the files overlap, so the overlap is counted once for every cut
+        duration -= self.settings.twitch.split_overlap_secs as f32 * (chunks.len() - 1) as f32;
+        info!(
+            "{:?} was already split into {} files, not downloading it again",
+            final_path,
+            chunks.len()
+        );
+        Ok(existing_video(final_path, chunks, quality, duration))
+    }
+
+    /// Downloads the parts into the part folder (or continues with what an
+    /// earlier run left there) and converts them.
+    ///
+    /// Returns the converted video in the part folder, its measured duration
+    /// and the parts that are missing from it.
+    async fn download_and_convert(
+        &self,
+        video_id: &str,
+        download_info: &DownloadInfo,
+        folder_path: &Path,
+        cancel: &CancellationToken,
+        options: DownloadOptions,
+    ) -> Result<(PathBuf, f32, Vec<PartGap>)> {
+        let folder_path = folder_path.to_path_buf();
+        let progress_sender = options.progress.clone();
+        let resume_point = determine_resume_point(&folder_path).await?;
+        if let Some(playlist) = &download_info.raw_playlist {
+            write_playlist(&folder_path, playlist).await?;
+        }
+        // a resumed download does not depend on twitch still having the playlist
+        write_download_info(&folder_path, download_info).await?;
+        let (combined, gaps) = match resume_point {
+            ResumePoint::DownloadParts => {
+                let parts = self
+                    .download_all_parts(video_id, download_info, &folder_path, cancel, options)
+                    .await;
+                let (parts, gaps) = match parts {
+                    Err(DownloaderError::Cancelled) => {
+                        return self.cancel_download(&folder_path).await
+                    }
+                    Err(
+                        err @ (DownloaderError::File(DownloadFileError::InsufficientDiskSpace {
+                            ..
+                        })
+                        | DownloaderError::ByteCapReached { .. }),
+                    ) => {
+                        warn!("Stopping the download: {}", err);
+                        self.clean_up_partial_download(&folder_path).await?;
+                        return Err(err);
+                    }
+                    parts => parts?,
+                };
+                if cancel.is_cancelled() {
+                    return self.cancel_download(&folder_path).await;
+                }
+
+                // the gaps are needed again if the conversion gets resumed
+                write_gaps(&folder_path, &gaps).await?;
+                let expected_parts: Vec<PlaylistPart> = download_info
+                    .parts
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| !gaps.iter().any(|gap| gap.index == *index))
+                    .map(|(_, part)| part.clone())
+                    .collect();
+                verify_parts(
+                    &parts,
+                    &expected_parts,
+                    &folder_path,
+                    PartSizeCheck::new(&self.settings.twitch, download_info),
+                )?;
+                if self.settings.twitch.verify_part_checksums {
+                    verify_against_manifest(&folder_path, &parts).await?;
+                }
+                let expected_duration = expected_duration(download_info, &gaps);
+                DownloadProgress::report_phase(
+                    progress_sender.as_ref(),
+                    video_id,
+                    DownloadPhase::Combining,
+                )
+                .await;
+                let combined = tokio::select! {
+                    combined = combine_parts_to_mp4(
+                        &parts,
+                        &folder_path,
+                        expected_duration,
+                        &self.settings.twitch,
+                        progress_sender.as_ref(),
+                        video_id,
+                    ) => combined,
+                    // ffmpeg is killed when the combine future is dropped
+                    _ = cancel.cancelled() => return self.cancel_download(&folder_path).await,
+                };
+                (combined, gaps)
+            }
+            ResumePoint::ConvertTs => {
+                let gaps = read_gaps(&folder_path).await?;
+                DownloadProgress::report_phase(
+                    progress_sender.as_ref(),
+                    video_id,
+                    DownloadPhase::Converting,
+                )
+                .await;
+                let combined = tokio::select! {
+                    combined = convert_combined_ts(
+                        &folder_path,
+                        expected_duration(download_info, &gaps),
+                        &self.settings.twitch,
+                    ) => combined,
+                    _ = cancel.cancelled() => return self.cancel_download(&folder_path).await,
+                };
+                (combined, gaps)
+            }
+        };
+        let (converted_path, measured_duration) = combined?;
+        Ok((converted_path, measured_duration, gaps))
+    }
+
+    /// The download info that an interrupted download left in the part folder.
+    ///
+    /// If the CDN refuses the base url of it by now, a freshly signed one is
+    /// used. The cache is kept as it is if that fails (e.g. because the video
+    /// was deleted), so whatever was already downloaded can still be used.
+    ///
+    /// If the earlier run was for another quality, its parts can't be combined
+    /// with the ones of this quality, so the part folder is removed.
+    async fn cached_download_info(
+        &self,
+        video_id: &str,
+        quality: &str,
+        folder_path: &Path,
+    ) -> Result<Option<DownloadInfo>> {
+        let Some(mut download_info) = read_download_info(folder_path).await else {
+            return Ok(None);
+        };
+        if download_info.requested_quality.as_deref() != Some(quality) {
+            info!(
+                "the earlier run downloaded {} instead of {}, starting over",
+                download_info.quality, quality
+            );
+            tokio::fs::remove_dir_all(folder_path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+            return Ok(None);
+        }
+        info!(
+            "continuing with the playlist of an earlier run ({} parts)",
+            download_info.part_count
+        );
+        // the first part that is still missing is the next one that is downloaded
+        let probe = download_info
+            .parts
+            .iter()
+            .find(|part| !folder_path.join(part.file_name()).exists())
+            .or(download_info.parts.first());
+        let Some(request) = probe.and_then(|probe| {
+            self.cdn_client
+                .head(probe.url(&download_info.base_url))
+                .build()
+                .ok()
+        }) else {
+            return Ok(None);
+        };
+        let stale = match self.retry.execute(&self.cdn_client, request).await {
+            Ok(response) => response.status() == reqwest::StatusCode::FORBIDDEN,
+            Err(err) => {
+                debug!("could not check the cached base url: {:?}", err);
+                false
+            }
+        };
+        if stale {
+            debug!("the cached base url expired, getting a new one");
+            match self
+                .refresh_base_url(video_id, &download_info.quality)
+                .await
             {
-                // folder is not empty
-                return Err(DownloadFileError::TargetFolderIsNotEmpty(folder_path).into());
+                Ok(base_url) => download_info.base_url = base_url,
+                Err(err) => warn!(
+                    "Could not refresh the expired playlist, continuing with the parts that are there: {:?}",
+                    err
+                ),
             }
         }
+        Ok(Some(download_info))
+    }
 
-        let mut parts = self
-            .download_all_parts(quality, &video_id, &folder_path)
-            .await?;
+    /// Cleans up after a cancelled download and returns [`DownloaderError::Cancelled`].
+    ///
+    /// The downloaded parts and a combined `video.ts` are kept to resume the
+    /// download later, unless configured otherwise. A partially converted
+    /// video is always removed, since it can't be resumed.
+    async fn cancel_download<T>(&self, folder_path: &Path) -> Result<T> {
+        self.clean_up_partial_download(folder_path).await?;
+        Err(DownloaderError::Cancelled)
+    }
 
-        sort_parts(&mut parts);
-        let mp4_file_path = combine_parts_to_mp4(&parts, &folder_path).await?;
+    /// Removes the parts of a download that did not finish, unless they are
+    /// kept to resume the download later.
+    async fn clean_up_partial_download(&self, folder_path: &Path) -> Result<()> {
+        if self.settings.clean_parts_on_cancel {
+            info!("Removing the parts of the cancelled download");
+            tokio::fs::remove_dir_all(folder_path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        } else {
+            for path in converted_leftovers(folder_path) {
+                if path.exists() {
+                    tokio::fs::remove_file(&path)
+                        .await
+                        .map_err(DownloadFileError::Filesystem)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Makes sure the video is not longer than the configured maximum duration.
+    fn check_duration(&self, download_info: &DownloadInfo) -> Result<()> {
+        let Some(max_hours) = self.settings.max_video_duration_hours else {
+            return Ok(());
+        };
+        let duration_hours = download_info.total_duration / 3600.0;
+        if duration_hours > max_hours {
+            info!(
+                "Video is {:.2}h long, which is more than the limit of {:.2}h",
+                duration_hours, max_hours
+            );
+            return Err(DownloaderError::VideoTooLong {
+                duration_hours,
+                max_hours,
+            });
+        }
+        Ok(())
+    }
 
-        tokio::fs::rename(&mp4_file_path, &final_path)
-            .await
-            .map_err(DownloadFileError::Filesystem)?;
-        //clean up the leftover parts
-        tokio::fs::remove_dir_all(folder_path)
-            .await
-            .map_err(DownloadFileError::Filesystem)?;
-        Ok(final_path)
+    /// Makes sure there is enough space on the disk for the video.
+    ///
+    /// While converting, the combined ts file and the mp4 both exist, so twice
+    /// the estimated size is needed on top of the configured minimum. With
+    /// faststart, ffmpeg writes another copy of the mp4 in its second pass and
+    /// kept parts take up space until the end as well.
+    fn check_disk_space(&self, download_info: &DownloadInfo, output_folder: &Path) -> Result<()> {
+        let estimated_size = download_info.estimated_size(&self.settings);
+        let mut copies = 2;
+        if self.settings.twitch.use_faststart() {
+            copies += 1;
+        }
+        if self.settings.twitch.keep_parts {
+            copies += 1;
+        }
+        let min_free_space = self.settings.min_free_space_bytes;
+        match &self.settings.temp_folder_path {
+            Some(temp_folder) => {
+                // only the final video ends up in the output folder
+                require_space(temp_folder, estimated_size * copies + min_free_space)?;
+                require_space(output_folder, estimated_size + min_free_space)
+            }
+            None => require_space(output_folder, estimated_size * copies + min_free_space),
+        }
     }
-}
-//endregion
-impl TwitchClient {
-    async fn download_all_parts<QUALITY: DIntoString>(
+
+    async fn download_all_parts(
         &self,
-        quality: QUALITY,
-        video_id: &String,
+        video_id: &str,
+        download_info: &DownloadInfo,
         folder_path: &Path,
-    ) -> Result<Vec<PathBuf>> {
-        let download_info = self.get_download_info(video_id, quality).await?;
-        let parts = download_info.parts;
-        let base_url = download_info.base_url;
+        cancel: &CancellationToken,
+        options: DownloadOptions,
+    ) -> Result<(Vec<PathBuf>, Vec<PartGap>)> {
+        let progress_sender = options.progress;
+        let byte_budget = options
+            .byte_budget
+            .unwrap_or_else(|| Arc::new(ByteBudget::new(None)));
+        let parts = download_info.parts.clone();
+        let base_url = download_info.base_url.clone();
         let age = download_info.vod_age;
         if parts.is_empty() {
             return Err(MalformedPlaylistError::Empty.into());
         }
+        info!(
+            "downloading {} parts with a total duration of {}",
+            download_info.part_count,
+            format_duration(download_info.total_duration as u64)
+        );
         let try_unmute = age.unwrap_or(999) < 24; //hours i think
         let amount_of_parts = parts.len() as u64;
-        let thread_count = self.config.twitch.downloader_thread_count;
+        let thread_count = options
+            .thread_count
+            .unwrap_or(self.config.twitch.downloader_thread_count);
         let thread_count: u64 = if thread_count < 1 {
             1
         } else if thread_count > amount_of_parts {
@@ -108,69 +637,554 @@ impl TwitchClient {
             thread_count
         };
 
-        // todo!("maybe add a progress bar/indicator?");
-        let it = parts
-            .into_iter()
-            .map(|part| {
-                let client = self.client.clone();
-                let url = base_url.clone();
+        // the parts keep their index in the playlist, so they can be combined in that order
+        // a folder of an earlier run is reused, but only with the files that belong to the playlist
+        let mut removed_files = remove_unexpected_files(folder_path, &parts).await?;
+        let size_check = PartSizeCheck::new(&self.settings.twitch, download_info);
+        let manifest = read_manifest(folder_path).await;
+        let manifest_writer = ManifestWriter::open(folder_path).await?;
+        let mut present_parts = vec![];
+        let mut missing_parts = vec![];
+        for (index, part) in parts.into_iter().enumerate() {
+            let path = folder_path.join(part.file_name());
+            let is_present = match manifest.get(&part.file_name()) {
+                Some(entry) if path.is_file() => {
+                    let matches = matches_entry(&path, entry).await?;
+                    if !matches {
+                        debug!("part {} changed since it was downloaded", part.uri);
+                    }
+                    matches
+                }
+                _ => {
+                    let downloaded = is_part_downloaded(
+                        &part,
+                        &base_url,
+                        folder_path,
+                        try_unmute,
+                        &self.cdn_client,
+                        &self.retry,
+                        size_check,
+                    )
+                    .await;
+                    if downloaded {
+                        // the part is from a run that did not write the manifest yet
+                        let entry = ManifestEntry::of_file(&path).await?;
+                        manifest_writer.record(&part.file_name(), &entry);
+                    }
+                    downloaded
+                }
+            };
+            if is_present {
+                let path = path
+                    .canonicalize()
+                    .map_err(DownloadFileError::Canonicalization)?;
+                present_parts.push((index, path));
+            } else {
+                if path.exists() {
+                    debug!("removing the invalid part {:?}", path);
+                    fs::remove_file(&path)
+                        .await
+                        .map_err(DownloadFileError::Filesystem)?;
+                    removed_files += 1;
+                }
+                missing_parts.push((index, part));
+            }
+        }
+        if !present_parts.is_empty() || removed_files > 0 {
+            info!(
+                "resuming, {} of {} parts already present, removed {} invalid or unknown files",
+                present_parts.len(),
+                amount_of_parts,
+                removed_files
+            );
+        }
+
+        let mut present_bytes = 0;
+        for (_, path) in &present_parts {
+            present_bytes += fs::metadata(path).await.map(|m| m.len()).unwrap_or(0);
+        }
+        let progress = Arc::new(PartProgress::new(
+            video_id,
+            amount_of_parts,
+            present_parts.len() as u64,
+            present_bytes,
+            download_info.estimated_size,
+        ));
+        let reporter = progress.clone().spawn_reporter(
+            Duration::from_secs(self.settings.progress_log_interval_secs.max(1)),
+            self.settings.log_progress,
+            progress_sender.clone(),
+        );
+        let ctx = PartDownloadContext {
+            client: self.cdn_client.clone(),
+            retry: self.retry,
+            limiter: Arc::new(BandwidthLimiter::new(
+                self.settings.twitch.max_download_bytes_per_sec,
+            )),
+            budget: byte_budget.clone(),
+            part_timeout: Duration::from_secs(self.settings.twitch.part_timeout_secs),
+            stall_timeout: Duration::from_secs(self.settings.twitch.part_stall_timeout_secs),
+            size_check,
+        };
+        let ctx = &ctx;
+        let progress = &progress;
+        let recorder = &manifest_writer;
+        let forbidden_in_a_row = &AtomicU32::new(0);
+        // other programs can fill up the disk while the parts are downloaded
+        let disk_full = &CancellationToken::new();
+        // only stops the video if it should not be finished after the byte cap was reached
+        let byte_cap = &if self.settings.abort_at_byte_cap {
+            byte_budget.reached_token()
+        } else {
+            CancellationToken::new()
+        };
+        let parts_since_disk_check = &AtomicU64::new(0);
+        let min_free_space = self.settings.min_free_space_bytes;
+        // `paused` stops the pass early when the access token seems to have expired
+        let download_pass = move |parts: Vec<(usize, PlaylistPart)>,
+                                  base_url: String,
+                                  paused: CancellationToken| {
+            let it = parts.into_iter().map(move |(index, part)| {
+                let base_url = base_url.clone();
+                let paused = paused.clone();
                 async move {
+                    let paused_error = |part: &PlaylistPart| {
+                        DownloadFileError::Forbidden(part.uri.clone()).into()
+                    };
+                    if cancel.is_cancelled() || disk_full.is_cancelled() || byte_cap.is_cancelled() {
+                        return ((index, part), Err(DownloaderError::Cancelled));
+                    }
+                    if paused.is_cancelled() {
+                        let err = paused_error(&part);
+                        return ((index, part), Err(err));
+                    }
                     // download
-                    let result = download_part(part, url, folder_path, try_unmute, client).await;
+                    let result = tokio::select! {
+                        result = download_part(part.clone(), base_url, folder_path, try_unmute, ctx.clone()) => result,
+                        _ = cancel.cancelled() => return ((index, part), Err(DownloaderError::Cancelled)),
+                        _ = disk_full.cancelled() => return ((index, part), Err(DownloaderError::Cancelled)),
+                        _ = byte_cap.cancelled() => return ((index, part), Err(DownloaderError::Cancelled)),
+                        _ = paused.cancelled() => {
+                            let err = paused_error(&part);
+                            return ((index, part), Err(err));
+                        }
+                    };
+                    match &result {
+                        Ok(_) => forbidden_in_a_row.store(0, Ordering::Relaxed),
+                        Err(DownloadFileError::Forbidden(_)) => {
+                            let count = forbidden_in_a_row.fetch_add(1, Ordering::Relaxed) + 1;
+                            if count >= FORBIDDEN_PARTS_BEFORE_REFRESH {
+                                paused.cancel();
+                            }
+                        }
+                        Err(err) if is_storage_full(err) => disk_full.cancel(),
+                        Err(_) => {}
+                    }
+                    let checked = parts_since_disk_check.fetch_add(1, Ordering::Relaxed) + 1;
+                    if checked % DISK_CHECK_INTERVAL_PARTS == 0 {
+                        match get_available_space(folder_path) {
+                            Ok(available) if available < min_free_space => disk_full.cancel(),
+                            Ok(_) => {}
+                            Err(err) => warn!("could not check the free disk space: {:?}", err),
+                        }
+                    }
                     // report progress
                     trace!("downloaded part: {:?}", result);
-                    // return result
-                    result
+                    let result: Result<PathBuf> = match result {
+                        Ok((path, entry)) => {
+                            progress.part_done(entry.size);
+                            recorder.record(&part.file_name(), &entry);
+                            path.canonicalize()
+                                .map_err(|e| DownloadFileError::Canonicalization(e).into())
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    ((index, part), result)
                 }
-            })
-            .map(|x| async {
-                x.await.and_then(|x: PathBuf| {
-                    x.canonicalize()
-                        .map_err(DownloadFileError::Canonicalization)
-                })
             });
-        let x = futures::stream::iter(it)
-            .buffer_unordered(thread_count as usize)
-            .try_collect::<Vec<_>>()
-            .await?;
+            futures::stream::iter(it)
+                .buffer_unordered(thread_count as usize)
+                .collect::<Vec<_>>()
+        };
 
-        Ok(x)
+        // a failed part does not stop the other parts, it is retried with
+        // fresh requests once all other parts are done
+        let retry_passes = self.settings.twitch.part_retry_passes;
+        let mut pending = missing_parts;
+        let mut downloaded = vec![];
+        let mut pass = 0;
+        let mut base_url = base_url;
+        let mut refreshes = 0;
+        let mut gaps = vec![];
+        let outcome: Result<()> = loop {
+            let mut failed = vec![];
+            let paused = CancellationToken::new();
+            for ((index, part), result) in
+                download_pass(pending, base_url.clone(), paused.clone()).await
+            {
+                match result {
+                    Ok(path) => downloaded.push((index, path)),
+                    Err(err) => {
+                        debug!("could not download part {}: {:?}", part.uri, err);
+                        failed.push((index, part));
+                    }
+                }
+            }
+            if cancel.is_cancelled() {
+                break Err(DownloaderError::Cancelled);
+            }
+            if disk_full.is_cancelled() {
+                break Err(DownloadFileError::InsufficientDiskSpace {
+                    path: folder_path.to_path_buf(),
+                    available: get_available_space(folder_path).unwrap_or(0),
+                    required: min_free_space,
+                }
+                .into());
+            }
+            // the cap can be reached by the last part, which still finishes the video
+            let stopped_by_cap = byte_cap.is_cancelled() && !failed.is_empty();
+            if let Some(limit) = byte_budget.limit().filter(|_| stopped_by_cap) {
+                break Err(DownloaderError::ByteCapReached { limit });
+            }
+            if failed.is_empty() {
+                break Ok(());
+            }
+            if paused.is_cancelled() {
+                // the signature of the playlist expired, so every part would fail
+                if refreshes >= MAX_TOKEN_REFRESHES {
+                    break Err(DownloaderError::AccessExpired { refreshes });
+                }
+                refreshes += 1;
+                warn!(
+                    "the CDN refused {} parts in a row, getting a new access token ({} of {})",
+                    FORBIDDEN_PARTS_BEFORE_REFRESH, refreshes, MAX_TOKEN_REFRESHES
+                );
+                base_url = match self
+                    .refresh_base_url(video_id, &download_info.quality)
+                    .await
+                {
+                    Ok(base_url) => base_url,
+                    Err(err) => break Err(err),
+                };
+                forbidden_in_a_row.store(0, Ordering::Relaxed);
+                pending = failed;
+                continue;
+            }
+            if pass >= retry_passes {
+                let missing_allowed = self
+                    .settings
+                    .twitch
+                    .max_missing_parts
+                    .is_some_and(|limit| limit.allows(failed.len(), amount_of_parts as usize));
+                if missing_allowed {
+                    gaps = failed
+                        .iter()
+                        .map(|(index, _)| PartGap::new(*index, &download_info.parts))
+                        .collect();
+                    gaps.sort_by_key(|gap| gap.index);
+                    for gap in &gaps {
+                        warn!(
+                            "part {} could not be downloaded, leaving a gap of {:.1}s at {}",
+                            gap.uri,
+                            gap.duration,
+                            format_duration(gap.start as u64)
+                        );
+                    }
+                    break Ok(());
+                }
+                let mut failed: Vec<String> =
+                    failed.into_iter().map(|(_, part)| part.uri).collect();
+                failed.sort();
+                break Err(DownloadFileError::FailedParts(failed).into());
+            }
+            pass += 1;
+            warn!(
+                "{} parts could not be downloaded, retrying them (pass {} of {})",
+                failed.len(),
+                pass,
+                retry_passes
+            );
+            pending = failed;
+        };
+        reporter.abort();
+        // the queued entries are also written when the download stops early,
+        // so the next run can resume without checking every part again
+        let finished = manifest_writer.finish().await;
+        outcome?;
+        finished?;
+        progress.log_summary();
+        if let Some(sender) = progress_sender {
+            let _ = sender.try_send(progress.snapshot());
+        }
+        downloaded.append(&mut present_parts);
+        downloaded.sort_by_key(|(index, _)| *index);
+
+        let files = downloaded.into_iter().map(|(_, path)| path).collect();
+        Ok((files, gaps))
+    }
+    /// Gets a freshly signed playlist url for the video and returns the base
+    /// url of its parts.
+    async fn refresh_base_url(&self, video_id: &str, quality: &str) -> Result<String> {
+        let (_, playlist_url) = self.get_video_playlist(video_id, quality).await?;
+        Ok(playlist_base_url(&playlist_url)?)
     }
+
     #[tracing::instrument(skip(self))]
     async fn get_download_info<ID: DIntoString, QUALITY: DIntoString>(
         &self,
         video_id: ID,
         quality: QUALITY,
     ) -> Result<DownloadInfo> {
-        let playlist = self.get_video_playlist(video_id, quality).await?;
-        let playlist_content = self
-            .client
-            .execute_with_backoff(self.client.get(&playlist).build()?)
-            .await?
-            .text()
+        let video_id: String = video_id.into();
+        let requested_quality: String = quality.into();
+        let (quality, playlist) = self
+            .get_video_playlist(&video_id, &requested_quality)
             .await?;
-        let base_url = &playlist[..playlist
-            .rfind('/')
-            .ok_or(MalformedPlaylistError::InvalidUrl)?
-            + 1];
-        let parts = parse_playlist(playlist_content)?;
-        // dbg!(&parts);
-        Ok(DownloadInfo {
-            vod_age: parts.0,
-            parts: parts.1,
-            base_url: base_url.to_string(),
-        })
+        info!("Using quality: {}", quality);
+        let response = self
+            .retry
+            .execute(&self.client, self.client.get(&playlist).build()?)
+            .await?;
+        // an error body has no end marker and would look like a video that is still processed
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(DownloaderError::VideoNotFound(video_id));
+        }
+        let playlist_content = response.error_for_status()?.text().await?;
+        let base_url = playlist_base_url(&playlist)?;
+        let raw_playlist = self
+            .settings
+            .twitch
+            .log_full_playlists
+            .then(|| playlist_content.clone());
+        let playlist = parse_playlist(playlist_content)?;
+        let mut download_info = DownloadInfo {
+            quality,
+            requested_quality: Some(requested_quality),
+            vod_age: playlist.age,
+            streamed_at: playlist.streamed_at,
+            total_duration: playlist.parts.iter().map(|part| part.duration).sum(),
+            part_count: playlist.parts.len(),
+            parts: playlist.parts,
+            base_url,
+            estimated_size: None,
+            raw_playlist,
+        };
+        download_info.estimated_size = self.estimate_size(&download_info).await;
+        if let Some(estimated_size) = download_info.estimated_size {
+            info!("estimated size: ~{}", format_bytes(estimated_size));
+        }
+        Ok(download_info)
+    }
+
+    /// Polls the media playlist until twitch finished processing the video,
+    /// which is when the playlist gets its end marker.
+    ///
+    /// The access token is fetched again if it expires while waiting. Fails with
+    /// [`MalformedPlaylistError::StillProcessing`] if the video is still not
+    /// ready after `max_wait`.
+    #[tracing::instrument(skip(self, cancel))]
+    pub async fn wait_for_vod_ready(
+        &self,
+        video_id: &str,
+        quality: &str,
+        poll_interval: Duration,
+        max_wait: Duration,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let (_, mut playlist_url) = self.get_video_playlist(video_id, quality).await?;
+        let mut token_refreshed = false;
+        loop {
+            let request = self.client.get(&playlist_url).build()?;
+            let response = self.retry.execute(&self.client, request).await?;
+            let status = response.status();
+            if (status == reqwest::StatusCode::UNAUTHORIZED
+                || status == reqwest::StatusCode::FORBIDDEN)
+                && !token_refreshed
+            {
+                debug!("the access token expired, getting a new one");
+                (_, playlist_url) = self.get_video_playlist(video_id, quality).await?;
+                token_refreshed = true;
+                continue;
+            }
+            token_refreshed = false;
+            let content = response.error_for_status()?.text().await?;
+            match parse_playlist(content) {
+                Ok(_) => {
+                    info!(
+                        "twitch finished processing the video after {:?}",
+                        start.elapsed()
+                    );
+                    return Ok(());
+                }
+                Err(err @ MalformedPlaylistError::StillProcessing { .. }) => {
+                    if start.elapsed() + poll_interval > max_wait {
+                        warn!(
+                            "the video is still being processed after waiting {:?}",
+                            start.elapsed()
+                        );
+                        return Err(err.into());
+                    }
+                    info!(
+                        "waiting {:?} for twitch to finish processing the video",
+                        poll_interval
+                    );
+                    tokio::select! {
+                        _ = tokio::time::sleep(poll_interval) => {}
+                        _ = cancel.cancelled() => return Err(DownloaderError::Cancelled),
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
     }
 
+    /// Estimates the size of the whole video by sampling the sizes of the
+    /// first, middle and last part and extrapolating them by duration.
+    ///
+    /// Since the sizes are weighted by the duration of the sampled parts, a
+    /// shorter last part does not skew the estimate.
+    async fn estimate_size(&self, download_info: &DownloadInfo) -> Option<u64> {
+        let parts = &download_info.parts;
+        if parts.is_empty() {
+            return None;
+        }
+        let mut samples = vec![0, parts.len() / 2, parts.len() - 1];
+        samples.dedup();
+
+        let mut sampled_bytes = 0;
+        let mut sampled_duration = 0.0;
+        for i in samples {
+            let part = &parts[i];
+            let size = match part.byte_range {
+                Some(range) => Some(range.length),
+                None => {
+                    let url = part.url(&download_info.base_url);
+                    get_remote_size(url, &self.cdn_client, &self.retry).await
+                }
+            };
+            if let Some(size) = size {
+                sampled_bytes += size;
+                sampled_duration += part.duration;
+            }
+        }
+        if sampled_duration <= 0.0 {
+            return None;
+        }
+        let total_duration = download_info.total_duration;
+        Some((sampled_bytes as f64 / sampled_duration as f64 * total_duration as f64) as u64)
+    }
+
+    /// The Client-ID for GQL requests. Falls back to the one of the twitch website.
+    fn gql_client_id(&self) -> &str {
+        if self.config.twitch.downloader_id.is_empty() {
+            DEFAULT_CLIENT_ID
+        } else {
+            &self.config.twitch.downloader_id
+        }
+    }
+
+    /// Suggests the default Client-ID if twitch complains about the configured one.
+    fn with_client_id_hint(&self, mut message: String) -> String {
+        if !self.config.twitch.downloader_id.is_empty()
+            && message.to_ascii_lowercase().contains("client")
+        {
+            message.push_str(
+                " (clear twitch.downloader_id to use the default Client-ID of the twitch website)",
+            );
+        }
+        message
+    }
+
+    /// Sends a request to the twitch GQL api and returns the response body.
+    async fn execute_gql(&self, body: String) -> Result<String> {
+        let mut request = self
+            .client
+            .post(&self.settings.twitch.gql_url)
+            .header("Client-ID", self.gql_client_id());
+        if let Some(token) = &self.settings.twitch.oauth_token {
+            request = request.header("Authorization", format!("OAuth {}", token));
+        }
+        if let Some(device_id) = &self.settings.twitch.device_id {
+            request = request.header("X-Device-Id", device_id);
+        }
+        let request = request.body(body).build()?;
+
+        let response = self.execute_limited(request).await?;
+        let status = response.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(DownloaderError::GqlRateLimited);
+        }
+        if status.is_server_error() {
+            return Err(DownloaderError::GqlUnavailable(status));
+        }
+        let body = response.text().await?;
+        if status.is_client_error() {
+            return Err(DownloaderError::GqlRejected {
+                status,
+                message: self.with_client_id_hint(gql_error_message(&body)),
+            });
+        }
+        Ok(body)
+    }
+
+    /// Gets the access token of a video, trying the fallback player types if
+    /// twitch does not give one for the configured player type.
     #[tracing::instrument(skip(self))]
     async fn get_video_token_and_signature<S: DIntoString>(
         &self,
         video_id: S,
     ) -> Result<(String, String)> {
         let video_id = video_id.into();
-        trace!("Getting access token & signature for video {}", video_id,);
+        let twitch = &self.settings.twitch;
+        let mut tried = vec![];
+        for player_type in std::iter::once(&twitch.player_type).chain(&twitch.fallback_player_types)
+        {
+            if tried.contains(player_type) {
+                continue;
+            }
+            tried.push(player_type.clone());
+            let Some(access_token) = self
+                .request_video_access_token(&video_id, player_type)
+                .await?
+            else {
+                debug!(
+                    "Got no access token for video {} with player type {}",
+                    video_id, player_type
+                );
+                continue;
+            };
+            if tried.len() > 1 {
+                info!(
+                    "Got the access token for video {} with the fallback player type {}",
+                    video_id, player_type
+                );
+            }
+            return Ok((access_token.value, access_token.signature));
+        }
+        let reason = if twitch.oauth_token.is_some() {
+            "this VOD appears to be restricted and the configured twitch.oauth_token has no access to it"
+        } else {
+            "this VOD appears to be restricted; configure twitch.oauth_token"
+        };
+        Err(DownloaderError::Restricted {
+            reason,
+            player_types: tried,
+        })
+    }
+
+    /// Requests the access token of a video with a single player type.
+    async fn request_video_access_token(
+        &self,
+        video_id: &str,
+        player_type: &str,
+    ) -> Result<Option<VideoAccessTokenResponseDataAccessToken>> {
+        trace!(
+            "Getting access token & signature for video {} with player type {}",
+            video_id,
+            player_type
+        );
 
-        const URL: &str = "https://gql.twitch.tv/gql";
         let json = json!({"operationName":"PlaybackAccessToken_Template",
             "query": "query PlaybackAccessToken_Template($login: String!, $isLive: Boolean!, $vodID: ID!, $isVod: Boolean!, $playerType: String!) {  streamPlaybackAccessToken(channelName: $login, params: {platform: \"web\", playerBackend: \"mediaplayer\", playerType: $playerType}) @include(if: $isLive) {    value    signature    __typename  }  videoPlaybackAccessToken(id: $vodID, params: {platform: \"web\", playerBackend: \"mediaplayer\", playerType: $playerType}) @include(if: $isVod) {    value    signature    __typename  }}",
             "variables": {
@@ -178,18 +1192,10 @@ impl TwitchClient {
             "login": "",
             "isVod": true,
             "vodID": video_id,
-            "playerType": "embed"
+            "playerType": player_type
             }
         }).to_string();
-        let request = self
-            .client
-            .post(URL)
-            .header("Client-ID", &self.config.twitch.downloader_id)
-            .body(json)
-            .build()?;
-
-        let response = self.client.execute_with_backoff(request).await?;
-        let json = response.text().await?;
+        let json = self.execute_gql(json).await?;
         // trace!("Got json response: {}", json);
         let token_response: TwitchVideoAccessTokenResponse =
             serde_json::from_str(&json).map_err(DownloaderError::AccessTokenJsonParse)?;
@@ -198,12 +1204,19 @@ impl TwitchClient {
             video_id,
             token_response
         );
-        let access_token = token_response
+        if let Some(errors) = token_response.errors.filter(|errors| !errors.is_empty()) {
+            let messages = errors
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(DownloaderError::GqlError(
+                self.with_client_id_hint(messages),
+            ));
+        }
+        Ok(token_response
             .data
-            .video_playback_access_token
-            .ok_or(DownloaderError::AccessTokenEmpty)?;
-
-        Ok((access_token.value, access_token.signature))
+            .and_then(|data| data.video_playback_access_token))
     }
 
     #[tracing::instrument(skip(self))]
@@ -211,7 +1224,7 @@ impl TwitchClient {
         &self,
         video_id: ID,
         quality: QUALITY,
-    ) -> Result<String> {
+    ) -> Result<(String, String)> {
         let video_id = video_id.into();
         let quality = quality.into();
 
@@ -221,31 +1234,573 @@ impl TwitchClient {
             quality
         );
 
-        let playlist = self.get_video_playlist_per_quality(&video_id).await?;
-        let playlist = get_playlist_from_quality_list(playlist, &quality)?;
+        // the configured preferences are only used if no specific quality was requested
+        let preferences = if quality == "max" {
+            self.settings.twitch.quality_preferences.clone()
+        } else {
+            vec![quality]
+        };
+        let qualities = self.get_available_qualities(&video_id).await?;
+        let variant = select_quality(&qualities, &preferences)?;
 
-        Ok(playlist)
+        Ok((variant.name.clone(), variant.url.clone()))
     }
 
+    /// Gets all qualities the video is available in, in the order of the
+    /// master playlist (twitch lists the highest quality first).
+    ///
+    /// Fails with [`DownloaderError::VideoNotFound`] if usher does not know the
+    /// video and with [`MalformedPlaylistError::Restricted`] if it refuses it.
     #[tracing::instrument(skip(self))]
-    async fn get_video_playlist_per_quality(&self, video_id: &str) -> Result<String> {
+    pub async fn get_available_qualities(&self, video_id: &str) -> Result<Vec<QualityInfo>> {
         let (token, signature) = self.get_video_token_and_signature(video_id).await?;
 
         let playlist_url = format!(
-            "https://usher.ttvnw.net/vod/{}?nauth={}&nauthsig={}&allow_source=true&player=twitchweb",
-            video_id, token, signature
+            "{}/vod/{}?nauth={}&nauthsig={}&allow_source=true&player=twitchweb",
+            self.settings.twitch.usher_url.trim_end_matches('/'),
+            video_id,
+            token,
+            signature
         );
 
         let request = self.client.get(playlist_url).build()?;
-        let playlist = self.client.execute_with_backoff(request).await?;
-        let playlist = playlist.text().await?;
-        Ok(playlist)
+        let playlist = self.execute_limited(request).await?;
+        match playlist.status() {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                return Err(DownloaderError::UsherRateLimited)
+            }
+            reqwest::StatusCode::NOT_FOUND => {
+                return Err(DownloaderError::VideoNotFound(video_id.to_string()))
+            }
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                let body = playlist.text().await?;
+                return Err(parse_usher_error(body.trim_start()).into());
+            }
+            _ => {}
+        }
+        let playlist = playlist.error_for_status()?.text().await?;
+        Ok(parse_master_playlist(&playlist)?)
+    }
+
+    /// Sends a request to the twitch api once the rate limiter allows it.
+    ///
+    /// If twitch answers with `429 Too Many Requests` all requests are paused
+    /// for as long as twitch asks for (or `twitch.rate_limited_pause_secs`) and
+    /// the request is sent again. The last response is returned as it is.
+    async fn execute_limited(&self, request: reqwest::Request) -> Result<reqwest::Response> {
+        let mut request = request;
+        let mut attempt = 1;
+        loop {
+            let next_request = if attempt < RATE_LIMITED_ATTEMPTS {
+                request.try_clone()
+            } else {
+                None
+            };
+            self.api_limiter.acquire().await;
+            let response = self.retry.execute(&self.client, request).await?;
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+            let pause = retry_after(&response).unwrap_or(Duration::from_secs(
+                self.settings.twitch.rate_limited_pause_secs,
+            ));
+            warn!(
+                "Twitch rate limited the request to {} (attempt {}/{}), pausing requests for {:?}",
+                response.url().path(),
+                attempt,
+                RATE_LIMITED_ATTEMPTS,
+                pause
+            );
+            self.api_limiter.throttle(pause);
+            let Some(next_request) = next_request else {
+                return Ok(response);
+            };
+            request = next_request;
+            attempt += 1;
+        }
+    }
+
+    /// How many requests twitch answered with `429 Too Many Requests` so far.
+    pub fn throttled_requests(&self) -> u64 {
+        self.api_limiter.throttled()
+    }
+}
+
+/// Whether writing a part failed because the disk is full, no matter if that
+/// happened while creating, writing or renaming the file.
+fn is_storage_full(err: &DownloadFileError) -> bool {
+    match err {
+        DownloadFileError::FileCreation(e)
+        | DownloadFileError::Write(e)
+        | DownloadFileError::Filesystem(e) => e.kind() == std::io::ErrorKind::StorageFull,
+        _ => false,
+    }
+}
+
+/// Builds a http client that sends the headers with every request and all
+/// requests through the proxy if one is given.
+fn build_client(
+    proxy: Option<&str>,
+    headers: &reqwest::header::HeaderMap,
+) -> Result<ReqwestClient> {
+    let mut builder = reqwest::Client::builder().default_headers(headers.clone());
+    if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy).map_err(|e| {
+            DownloaderError::LoadConfig(anyhow::Error::new(e).context(format!(
+                "invalid proxy url: {}",
+                describe_proxy(Some(proxy))
+            )))
+        })?;
+        builder = builder.proxy(proxy);
+    }
+    let client = builder
+        .build()
+        .map_err(|e| DownloaderError::LoadConfig(e.into()))?;
+    Ok(client.into())
+}
+
+/// How long twitch asks to wait before sending the next request.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Gets the message out of the body of a rejected GQL request, which is
+/// something like `{"error":"Bad Request","status":400,"message":"..."}`.
+fn gql_error_message(body: &str) -> String {
+    #[derive(serde::Deserialize)]
+    struct ErrorBody {
+        message: String,
+    }
+    match serde_json::from_str::<ErrorBody>(body) {
+        Ok(error) => error.message,
+        Err(_) => body.chars().take(200).collect(),
+    }
+}
+
+/// Describes the proxy for logging without the password.
+fn describe_proxy(proxy: Option<&str>) -> String {
+    match proxy {
+        Some(proxy) => redact_url(proxy),
+        None => "none".to_string(),
+    }
+}
+
+/// Settings for a single download.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Gets the progress of the download. Nothing is sent if this is not set.
+    pub progress: Option<mpsc::Sender<DownloadProgress>>,
+    /// How many parts are downloaded at the same time.
+    /// Uses `twitch.downloader_thread_count` of the config if not set.
+    pub thread_count: Option<u64>,
+    /// Counts the downloaded bytes across the downloads of a run.
+    pub byte_budget: Option<Arc<ByteBudget>>,
+}
+
+/// A part that could not be downloaded and is missing from the video.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PartGap {
+    /// The position of the part in the playlist.
+    pub index: usize,
+    pub uri: String,
+    /// Where the part starts in the VOD in seconds.
+    pub start: f32,
+    /// The duration of the missing part in seconds.
+    pub duration: f32,
+}
+
+impl PartGap {
+    pub fn new(index: usize, parts: &[PlaylistPart]) -> Self {
+        let part = &parts[index];
+        Self {
+            index,
+            uri: part.uri.clone(),
+            start: parts[..index].iter().map(|part| part.duration).sum(),
+            duration: part.duration,
+        }
     }
 }
 
+/// A video that was downloaded successfully.
 #[derive(Debug, Clone)]
-struct DownloadInfo {
-    vod_age: Option<usize>,
-    parts: HashMap<String, f32>,
-    base_url: String,
+pub struct DownloadedVideo {
+    /// Where the final video file is.
+    pub path: PathBuf,
+    /// The information the download was based on.
+    pub info: DownloadInfo,
+    /// The duration of the final video in seconds, as measured by ffprobe.
+    pub measured_duration: Option<f32>,
+    /// The files the video was split into because it was too long. If this
+    /// is not empty, the file at `path` does not exist anymore.
+    pub chunks: Vec<PathBuf>,
+    /// The parts that are missing from the video, see
+    /// [`TwitchDownloaderConfig::max_missing_parts`].
+    pub gaps: Vec<PartGap>,
+}
+
+impl DownloadedVideo {
+    /// All files that belong to the video.
+    pub fn files(&self) -> Vec<PathBuf> {
+        if self.chunks.is_empty() {
+            vec![self.path.clone()]
+        } else {
+            self.chunks.clone()
+        }
+    }
+}
+
+/// A video that was already downloaded by an earlier run, of which only the duration is known.
+fn existing_video(
+    path: PathBuf,
+    chunks: Vec<PathBuf>,
+    quality: &str,
+    duration: f32,
+) -> DownloadedVideo {
+    DownloadedVideo {
+        path,
+        info: DownloadInfo {
+            quality: quality.to_string(),
+            requested_quality: None,
+            vod_age: None,
+            streamed_at: None,
+            parts: vec![],
+            base_url: String::new(),
+            estimated_size: None,
+            total_duration: duration,
+            part_count: 0,
+            raw_playlist: None,
+        },
+        measured_duration: Some(duration),
+        chunks,
+        gaps: vec![],
+    }
+}
+
+/// Everything that is known about a video before downloading it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DownloadInfo {
+    /// The quality that was chosen for the download.
+    pub quality: String,
+    /// The quality that was asked for (e.g. `max`), which the chosen one was selected by.
+    #[serde(default)]
+    pub requested_quality: Option<String>,
+    /// The age of the video in hours.
+    pub vod_age: Option<usize>,
+    /// When the video was streamed.
+    pub streamed_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// The parts of the video in the order of the playlist, which is the
+    /// order they are combined in.
+    pub parts: Vec<PlaylistPart>,
+    pub base_url: String,
+    /// The estimated size of the video in bytes, based on sampled part sizes.
+    pub estimated_size: Option<u64>,
+    /// The duration of all parts combined in seconds.
+    pub total_duration: f32,
+    pub part_count: usize,
+    /// The media playlist as it was downloaded, only kept with
+    /// `twitch.log_full_playlists`.
+    #[serde(skip)]
+    pub raw_playlist: Option<String>,
+}
+
+impl DownloadInfo {
+    /// The estimated size of the video in bytes.
+    ///
+    /// Falls back to a rough estimate based on the duration if the part
+    /// sizes could not be sampled.
+    pub fn estimated_size(&self, settings: &DownloaderConfig) -> u64 {
+        self.estimated_size.unwrap_or_else(|| {
+            (self.total_duration as f64 * settings.estimated_bytes_per_second as f64) as u64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MissingPartsLimit;
+    use std::io::ErrorKind;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const ACCESS_TOKEN: &str = r#"{"data":{"videoPlaybackAccessToken":{"value":"{\"vod_id\":123}","signature":"abcdef"}}}"#;
+
+    /// A client that gets its access tokens and playlists from the mock server.
+    async fn mock_twitch(server: &MockServer, output: &Path) -> TwitchClient {
+        Mock::given(method("POST"))
+            .and(path("/gql"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(ACCESS_TOKEN))
+            .mount(server)
+            .await;
+        let mut settings = DownloaderConfig::default();
+        settings.twitch.gql_url = format!("{}/gql", server.uri());
+        settings.twitch.usher_url = server.uri();
+        settings.twitch.api_requests_per_minute = 0;
+        settings.twitch.retry_initial_delay_ms = 1;
+        TwitchClient::new(crate::test_util::conf(output), settings).unwrap()
+    }
+
+    /// Lets usher list a single quality with its media playlist on the mock server.
+    async fn mock_master_playlist(server: &MockServer) {
+        let master_playlist = format!(
+            "#EXTM3U\n\
+             #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"1080p60 (source)\",AUTOSELECT=YES,DEFAULT=YES\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=6000000,RESOLUTION=1920x1080,VIDEO=\"chunked\",FRAME-RATE=60.000\n\
+             {}/abc_123/chunked/index-dvr.m3u8\n",
+            server.uri()
+        );
+        Mock::given(method("GET"))
+            .and(path("/vod/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(master_playlist))
+            .mount(server)
+            .await;
+    }
+
+    async fn download_info_with_media_status(status: u16) -> Result<DownloadInfo> {
+        let server = MockServer::start().await;
+        let output = tempfile::tempdir().unwrap();
+        let client = mock_twitch(&server, output.path()).await;
+        mock_master_playlist(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/abc_123/chunked/index-dvr.m3u8"))
+            .respond_with(ResponseTemplate::new(status).set_body_string("<html>error</html>"))
+            .mount(&server)
+            .await;
+        client.get_download_info("123", "max").await
+    }
+
+    #[test]
+    fn a_full_disk_is_detected_in_every_file_operation() {
+        let full = || std::io::Error::from(ErrorKind::StorageFull);
+        assert!(is_storage_full(&DownloadFileError::FileCreation(full())));
+        assert!(is_storage_full(&DownloadFileError::Write(full())));
+        assert!(is_storage_full(&DownloadFileError::Filesystem(full())));
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert!(!is_storage_full(&DownloadFileError::Write(denied)));
+        assert!(!is_storage_full(&DownloadFileError::Read(full())));
+    }
+
+    #[tokio::test]
+    async fn an_empty_video_id_is_rejected() {
+        let output = tempfile::tempdir().unwrap();
+        let client = TwitchClient::new(
+            crate::test_util::conf(output.path()),
+            DownloaderConfig::default(),
+        )
+        .unwrap();
+
+        let result = client
+            .download_video(
+                "1",
+                "",
+                "max",
+                output.path(),
+                &CancellationToken::new(),
+                DownloadOptions::default(),
+            )
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(DownloaderError::InvalidVideoId(VideoIdError::Invalid(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_parts_of_another_quality_are_not_continued() {
+        let output = tempfile::tempdir().unwrap();
+        let folder_path = output.path().join("1");
+        fs::create_dir_all(&folder_path).await.unwrap();
+        fs::write(folder_path.join("0.ts"), b"720p").await.unwrap();
+        let mut download_info = crate::test_util::download_info("720p60");
+        download_info.requested_quality = Some("720p".to_string());
+        write_download_info(&folder_path, &download_info)
+            .await
+            .unwrap();
+        let client = TwitchClient::new(
+            crate::test_util::conf(output.path()),
+            DownloaderConfig::default(),
+        )
+        .unwrap();
+
+        let cached = client
+            .cached_download_info("123", "max", &folder_path)
+            .await
+            .unwrap();
+
+        assert!(cached.is_none());
+        assert!(!folder_path.exists());
+    }
+
+    async fn qualities_with_usher_response(response: ResponseTemplate) -> Result<Vec<QualityInfo>> {
+        let server = MockServer::start().await;
+        let output = tempfile::tempdir().unwrap();
+        let client = mock_twitch(&server, output.path()).await;
+        Mock::given(method("GET"))
+            .and(path("/vod/123"))
+            .respond_with(response)
+            .mount(&server)
+            .await;
+        client.get_available_qualities("123").await
+    }
+
+    #[tokio::test]
+    async fn a_restricted_video_has_no_qualities() {
+        let body = r#"[{"error":"Manifest is restricted","error_code":"vod_manifest_restricted"}]"#;
+
+        let result =
+            qualities_with_usher_response(ResponseTemplate::new(403).set_body_string(body)).await;
+
+        let Err(DownloaderError::MalformedPlaylist(MalformedPlaylistError::Restricted {
+            code,
+            ..
+        })) = result
+        else {
+            panic!("the video is not restricted: {:?}", result);
+        };
+        assert_eq!(code, "vod_manifest_restricted");
+    }
+
+    #[tokio::test]
+    async fn a_video_unknown_to_usher_is_not_found() {
+        let result = qualities_with_usher_response(ResponseTemplate::new(404)).await;
+
+        assert!(
+            matches!(result, Err(DownloaderError::VideoNotFound(ref id)) if id == "123"),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn a_part_missing_in_both_forms_becomes_a_gap() {
+        let server = MockServer::start().await;
+        for (uri, status) in [
+            ("0.ts", 200),
+            ("1.ts", 404),
+            ("1-muted.ts", 404),
+            ("2.ts", 200),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(format!("/vod/{}", uri)))
+                .respond_with(
+                    ResponseTemplate::new(status).set_body_string(format!("{} body", uri)),
+                )
+                .mount(&server)
+                .await;
+        }
+        let output = tempfile::tempdir().unwrap();
+        let folder_path = output.path().join("1");
+        fs::create_dir_all(&folder_path).await.unwrap();
+        let mut settings = DownloaderConfig::default();
+        settings.min_free_space_bytes = 0;
+        settings.twitch.min_part_size_bytes = 0;
+        settings.twitch.part_retry_passes = 0;
+        settings.twitch.retry_initial_delay_ms = 1;
+        settings.twitch.max_missing_parts = Some(MissingPartsLimit::Count(1));
+        let client = TwitchClient::new(crate::test_util::conf(output.path()), settings).unwrap();
+        let mut download_info = crate::test_util::download_info("1080p60");
+        download_info.vod_age = Some(1);
+        download_info.base_url = format!("{}/vod/", server.uri());
+        download_info.parts = ["0.ts", "1-muted.ts", "2.ts"]
+            .into_iter()
+            .map(|uri| PlaylistPart {
+                uri: uri.to_string(),
+                duration: 10.0,
+                byte_range: None,
+            })
+            .collect();
+        download_info.part_count = 3;
+
+        let (files, gaps) = client
+            .download_all_parts(
+                "123",
+                &download_info,
+                &folder_path,
+                &CancellationToken::new(),
+                DownloadOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].index, 1);
+        assert!(!folder_path.join("1-muted.ts").exists());
+    }
+
+    #[tokio::test]
+    async fn the_manifest_is_written_when_the_download_fails() {
+        let server = MockServer::start().await;
+        for (uri, status) in [("0.ts", 200), ("1.ts", 404)] {
+            Mock::given(method("GET"))
+                .and(path(format!("/vod/{}", uri)))
+                .respond_with(ResponseTemplate::new(status).set_body_string("part"))
+                .mount(&server)
+                .await;
+        }
+        let output = tempfile::tempdir().unwrap();
+        let folder_path = output.path().join("1");
+        fs::create_dir_all(&folder_path).await.unwrap();
+        let mut settings = DownloaderConfig::default();
+        settings.min_free_space_bytes = 0;
+        settings.twitch.min_part_size_bytes = 0;
+        settings.twitch.part_retry_passes = 0;
+        settings.twitch.retry_initial_delay_ms = 1;
+        let client = TwitchClient::new(crate::test_util::conf(output.path()), settings).unwrap();
+        let mut download_info = crate::test_util::download_info("1080p60");
+        download_info.base_url = format!("{}/vod/", server.uri());
+        download_info.parts = ["0.ts", "1.ts"]
+            .into_iter()
+            .map(|uri| PlaylistPart {
+                uri: uri.to_string(),
+                duration: 10.0,
+                byte_range: None,
+            })
+            .collect();
+        download_info.part_count = 2;
+
+        let result = client
+            .download_all_parts(
+                "123",
+                &download_info,
+                &folder_path,
+                &CancellationToken::new(),
+                DownloadOptions::default(),
+            )
+            .await;
+
+        assert!(result.is_err());
+        let manifest = read_manifest(&folder_path).await;
+        assert!(manifest.contains_key("0.ts"));
+        assert!(!manifest.contains_key("1.ts"));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_media_playlist_is_not_mistaken_for_a_processing_video() {
+        let result = download_info_with_media_status(403).await;
+
+        let err = result.unwrap_err();
+        assert!(matches!(err, DownloaderError::Reqwest(_)), "{:?}", err);
+        assert!(!err.is_retry_later());
+    }
+
+    #[tokio::test]
+    async fn a_missing_media_playlist_is_a_missing_video() {
+        let result = download_info_with_media_status(404).await;
+
+        assert!(
+            matches!(result, Err(DownloaderError::VideoNotFound(ref id)) if id == "123"),
+            "{:?}",
+            result
+        );
+    }
 }
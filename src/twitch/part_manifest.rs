@@ -0,0 +1,202 @@
+//! Remembers the size and hash of every downloaded part in the part folder,
+//! so a resumed download can check that the parts of an earlier run are intact.
+//!
+//! The parts are hashed with XXH3 instead of SHA-256, since the hash only has
+//! to detect a damaged part and every part is hashed again before combining.
+use crate::errors::DownloadFileError;
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The file in the part folder with one `name,size,hash` line per part.
+pub const MANIFEST_FILE_NAME: &str = "parts.csv";
+
+/// The size of the buffer the parts are read with while hashing.
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Hashes the content of a part as it is written to the manifest.
+#[derive(Default)]
+pub struct PartHasher(xxhash_rust::xxh3::Xxh3);
+
+impl PartHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    /// The hex encoded hash of everything that was added.
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.0.digest())
+    }
+}
+
+/// Hashes a part that is already on disk without reading it into memory at once.
+pub async fn hash_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)
+        .await
+        .map_err(DownloadFileError::Read)?;
+    let mut hasher = PartHasher::new();
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(DownloadFileError::Read)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+/// What is known about a part that was downloaded completely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    /// The hex encoded XXH3 of the part.
+    pub hash: String,
+}
+
+impl ManifestEntry {
+    /// Gets the size and hash of a part that is already on disk.
+    pub async fn of_file(path: &Path) -> Result<Self> {
+        let size = fs::metadata(path)
+            .await
+            .map_err(DownloadFileError::Read)?
+            .len();
+        Ok(Self {
+            size,
+            hash: hash_file(path).await?,
+        })
+    }
+}
+
+/// Reads the manifest of the part folder, by the file names of the parts.
+///
+/// A part that was downloaded more than once has the entry of its last download.
+/// Lines that can't be read (e.g. cut off by a crash) are ignored.
+pub async fn read_manifest(folder_path: &Path) -> HashMap<String, ManifestEntry> {
+    let path = folder_path.join(MANIFEST_FILE_NAME);
+    let Ok(content) = fs::read_to_string(&path).await else {
+        return HashMap::new();
+    };
+    let mut entries = HashMap::new();
+    for line in content.lines() {
+        let mut fields = line.split(',');
+        let (Some(name), Some(size), Some(hash), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            debug!("ignoring the invalid manifest line {:?}", line);
+            continue;
+        };
+        let Ok(size) = size.parse() else {
+            debug!("ignoring the invalid manifest line {:?}", line);
+            continue;
+        };
+        entries.insert(
+            name.to_string(),
+            ManifestEntry {
+                size,
+                hash: hash.to_string(),
+            },
+        );
+    }
+    entries
+}
+
+/// Whether the file still has the size and hash of the manifest entry.
+pub async fn matches_entry(path: &Path, entry: &ManifestEntry) -> Result<bool> {
+    let size = fs::metadata(path)
+        .await
+        .map_err(DownloadFileError::Read)?
+        .len();
+    if size != entry.size {
+        return Ok(false);
+    }
+    Ok(hash_file(path).await? == entry.hash)
+}
+
+/// Checks every part against the manifest before the parts are combined.
+///
+/// Fails with the names of the parts that changed since they were downloaded.
+pub async fn verify_against_manifest(folder_path: &Path, files: &[PathBuf]) -> Result<()> {
+    let manifest = read_manifest(folder_path).await;
+    let mut changed = vec![];
+    for file in files {
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let Some(entry) = manifest.get(&name) else {
+            warn!("part {} is not in the manifest, it can't be verified", name);
+            continue;
+        };
+        if !matches_entry(file, entry).await? {
+            changed.push(name);
+        }
+    }
+    if !changed.is_empty() {
+        return Err(DownloadFileError::ChangedParts(changed).into());
+    }
+    debug!("verified {} parts against the manifest", files.len());
+    Ok(())
+}
+
+/// Appends the entries of the parts to the manifest.
+///
+/// The parts are downloaded concurrently, so all entries go through a
+/// channel to a single task that does the writing.
+#[derive(Debug)]
+pub struct ManifestWriter {
+    sender: mpsc::UnboundedSender<String>,
+    task: JoinHandle<StdResult<(), DownloadFileError>>,
+}
+
+impl ManifestWriter {
+    pub async fn open(folder_path: &Path) -> Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(folder_path.join(MANIFEST_FILE_NAME))
+            .await
+            .map_err(DownloadFileError::FileCreation)?;
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+        let task = tokio::spawn(async move {
+            while let Some(line) = receiver.recv().await {
+                file.write_all(line.as_bytes())
+                    .await
+                    .map_err(DownloadFileError::Write)?;
+            }
+            file.flush().await.map_err(DownloadFileError::Write)?;
+            Ok(())
+        });
+        Ok(Self { sender, task })
+    }
+
+    pub fn record(&self, name: &str, entry: &ManifestEntry) {
+        let line = format!("{},{},{}\n", name, entry.size, entry.hash);
+        if self.sender.send(line).is_err() {
+            warn!("Could not add part {} to the manifest", name);
+        }
+    }
+
+    /// Waits until all entries are written.
+    pub async fn finish(self) -> Result<()> {
+        drop(self.sender);
+        match self.task.await {
+            Ok(result) => Ok(result?),
+            Err(err) => {
+                warn!("The manifest writer stopped unexpectedly: {:?}", err);
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,168 +1,1897 @@
 use super::*;
-use tokio::io::BufWriter;
-
-/// Sorts the parts by their number.
-///  
-/// The parts must be named like this: `1.ts`, `2.ts`, `3-muted.ts`, `4-unmuted.ts`, etc.
-///
-/// Optionally if  the number contains a single `-` like this: `1094734-1.ts`, `1094734-2.ts`, `1094734-3-muted.ts`, `1094734-4-unmuted.ts`, etc.
-/// everything before the `-` will be ignored and it will try to parse the rest as a number.
-///
-/// If that all fails, it will panic!
-pub fn sort_parts(files: &mut [PathBuf]) {
-    files.sort_by_key(|path| {
-        let number = path
-            .file_stem()
-            .map(|x| {
-                x.to_str()
-                    .unwrap_or("")
-                    .replace("-muted", "")
-                    .replace("-unmuted", "")
-            })
-            .unwrap_or(String::from("0"));
-        match number.parse::<u32>() {
-            Ok(n) => n,
-            Err(e) => {
-                warn!(
-                    "potentially catchable error while parsing the file number: {}\n{}",
-                    number, e
-                );
-                if !number.contains('-') {
-                    error!("Error while parsing the file number: {}", number);
-                    panic!("Error while parsing the file number: {}", number)
-                }
-                let number = number.split('-').collect::<Vec<&str>>()[1];
-                number
-                    .parse()
-                    .unwrap_or_else(|_| panic!("Error while parsing the file number: {}", number))
+use crate::metrics::{metrics, InFlightPart};
+use crate::twitch::part_manifest::{ManifestEntry, PartHasher, MANIFEST_FILE_NAME};
+use crate::twitch::retry::RetryPolicy;
+use std::collections::HashSet;
+use std::future::Future;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, BufWriter};
+
+/// Makes sure there is exactly one downloaded file for every part of the
+/// playlist, that the files are in the order of the playlist and that none
+/// of them is implausibly small.
+pub fn verify_parts(
+    files: &[PathBuf],
+    expected: &[PlaylistPart],
+    folder_path: &Path,
+    size_check: PartSizeCheck,
+) -> StdResult<(), DownloadFileError> {
+    let missing: Vec<String> = expected
+        .iter()
+        .enumerate()
+        .filter(|(index, part)| {
+            let path = folder_path.join(part.file_name());
+            let path = path.canonicalize().unwrap_or(path);
+            !path.is_file() || files.get(*index) != Some(&path)
+        })
+        .map(|(_, part)| part.file_name())
+        .collect();
+    if !missing.is_empty() || files.len() != expected.len() {
+        return Err(DownloadFileError::MissingParts {
+            expected: expected.len(),
+            got: files.len(),
+            missing,
+        });
+    }
+    let too_small: Vec<String> = expected
+        .iter()
+        .zip(files)
+        .filter(|(part, file)| {
+            let size = std::fs::metadata(file).map(|m| m.len()).unwrap_or(0);
+            size < size_check.min_size(part)
+        })
+        .map(|(part, _)| part.file_name())
+        .collect();
+    if !too_small.is_empty() {
+        return Err(DownloadFileError::TooSmallParts(too_small));
+    }
+    Ok(())
+}
+
+/// How small a part may be compared to the size expected from its duration.
+const MIN_PART_SIZE_RATIO: f64 = 0.1;
+
+/// Finds parts that are too small to be complete, which happens when the CDN has hiccups.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PartSizeCheck {
+    /// The configured minimum size of any part.
+    pub min_size: u64,
+    /// The average bitrate of the video in bytes per second, if it is known.
+    pub bytes_per_second: Option<f64>,
+}
+
+impl PartSizeCheck {
+    pub fn new(settings: &TwitchDownloaderConfig, download_info: &DownloadInfo) -> Self {
+        let bytes_per_second = match download_info.estimated_size {
+            Some(size) if download_info.total_duration > 0.0 => {
+                Some(size as f64 / download_info.total_duration as f64)
             }
+            _ => None,
+        };
+        Self {
+            min_size: settings.min_part_size_bytes,
+            bytes_per_second,
         }
-    });
+    }
+
+    /// The smallest plausible size of the part.
+    ///
+    /// Besides the configured minimum, a part has to be at least a tenth of
+    /// the size its duration would have at the average bitrate, so a short
+    /// last part is not rejected.
+    pub fn min_size(&self, part: &PlaylistPart) -> u64 {
+        let relative = self
+            .bytes_per_second
+            .map(|bytes_per_second| {
+                (bytes_per_second * part.duration as f64 * MIN_PART_SIZE_RATIO) as u64
+            })
+            .unwrap_or(0);
+        self.min_size.max(relative)
+    }
+}
+
+/// The extension of the file a part is written to until it is complete.
+const TEMP_PART_EXTENSION: &str = "tmp";
+
+/// The path a part is downloaded to before it is renamed to `target_path`.
+fn temp_part_path(target_path: &Path) -> PathBuf {
+    let mut name = target_path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(TEMP_PART_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Whether the file is a part that was not completely downloaded.
+pub fn is_temp_part(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == TEMP_PART_EXTENSION)
+}
+
+/// Removes the temporary files of parts that were not completely downloaded
+/// by a previous run.
+pub async fn remove_temp_parts(folder_path: &Path) -> StdResult<(), DownloadFileError> {
+    let mut entries = fs::read_dir(folder_path)
+        .await
+        .map_err(DownloadFileError::Read)?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(DownloadFileError::Read)?
+    {
+        let path = entry.path();
+        if is_temp_part(&path) {
+            debug!("removing leftover temporary part {:?}", path);
+            fs::remove_file(&path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+    }
+    Ok(())
+}
+
+/// Where a download continues, based on what a previous run left in the part folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumePoint {
+    /// The missing parts are downloaded and then combined.
+    /// Parts that were already downloaded are kept.
+    DownloadParts,
+    /// The parts were already combined into `video.ts`, which only needs to be converted.
+    ConvertTs,
+}
+
+/// Prepares the part folder of a video and determines where its download continues.
+///
+/// Leftovers of an interrupted run that can't be used are removed:
+/// - temporary files of parts that were not completely downloaded
+/// - a partial `video.mp4` (or `.mkv`) and the `parts.txt` of an interrupted conversion
+/// - a `video.ts` while parts are still present, since combining them was
+///   interrupted (the parts that were already combined are downloaded again)
+pub async fn determine_resume_point(folder_path: &Path) -> Result<ResumePoint> {
+    if !folder_path.exists() {
+        fs::create_dir_all(folder_path)
+            .await
+            .map_err(DownloadFileError::CouldNotCreateTargetFolder)?;
+        return Ok(ResumePoint::DownloadParts);
+    }
+    if !folder_path.is_dir() {
+        return Err(
+            DownloadFileError::TargetFolderIsNotADirectory(folder_path.to_path_buf()).into(),
+        );
+    }
+    remove_temp_parts(folder_path).await?;
+    for path in converted_leftovers(folder_path) {
+        if path.exists() {
+            info!("removing {:?} of an interrupted conversion", path);
+            fs::remove_file(&path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+    }
+    let ts_file = folder_path.join("video.ts");
+    if !ts_file.exists() {
+        return Ok(ResumePoint::DownloadParts);
+    }
+    if has_parts(folder_path).await? {
+        info!("combining the parts was interrupted, downloading the combined parts again");
+        fs::remove_file(&ts_file)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+        return Ok(ResumePoint::DownloadParts);
+    }
+    info!("the parts were already combined, resuming with the conversion");
+    Ok(ResumePoint::ConvertTs)
+}
+
+/// The file in the part folder that keeps the download info, so an
+/// interrupted download continues with the same playlist.
+const DOWNLOAD_INFO_FILE_NAME: &str = "download_info.json";
+
+/// Writes the download info into the part folder, replacing the one of an earlier run.
+pub async fn write_download_info(folder_path: &Path, download_info: &DownloadInfo) -> Result<()> {
+    let json = serde_json::to_vec(download_info).map_err(|e| {
+        DownloadFileError::Write(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    })?;
+    fs::write(folder_path.join(DOWNLOAD_INFO_FILE_NAME), json)
+        .await
+        .map_err(DownloadFileError::Write)?;
+    Ok(())
+}
+
+/// The download info written by [`write_download_info`], if there is one that can be read.
+pub async fn read_download_info(folder_path: &Path) -> Option<DownloadInfo> {
+    let path = folder_path.join(DOWNLOAD_INFO_FILE_NAME);
+    let json = fs::read(&path).await.ok()?;
+    match serde_json::from_slice(&json) {
+        Ok(download_info) => Some(download_info),
+        Err(err) => {
+            warn!(
+                "Ignoring the unreadable download info {:?}: {:?}",
+                path, err
+            );
+            None
+        }
+    }
 }
 
+/// The file in the part folder the media playlist is written to for debugging.
+const PLAYLIST_FILE_NAME: &str = "playlist.m3u8";
+
+/// Writes the media playlist into the part folder, replacing the one of an earlier run.
+pub async fn write_playlist(folder_path: &Path, playlist: &str) -> Result<()> {
+    let path = folder_path.join(PLAYLIST_FILE_NAME);
+    debug!("writing the playlist to {:?}", path);
+    fs::write(&path, playlist)
+        .await
+        .map_err(DownloadFileError::Write)?;
+    Ok(())
+}
+
+/// The file in the part folder that lists the parts that are missing from the video.
+const GAPS_FILE_NAME: &str = "gaps.json";
+
+/// Remembers the missing parts of a video, so they are known when only the
+/// conversion is resumed.
+pub async fn write_gaps(folder_path: &Path, gaps: &[PartGap]) -> Result<()> {
+    let path = folder_path.join(GAPS_FILE_NAME);
+    if gaps.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+        return Ok(());
+    }
+    let json = serde_json::to_vec(gaps).map_err(|e| {
+        DownloadFileError::Write(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    })?;
+    fs::write(&path, json)
+        .await
+        .map_err(DownloadFileError::Write)?;
+    Ok(())
+}
+
+/// The missing parts that were written by [`write_gaps`], if there are any.
+pub async fn read_gaps(folder_path: &Path) -> Result<Vec<PartGap>> {
+    let path = folder_path.join(GAPS_FILE_NAME);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let json = fs::read(&path).await.map_err(DownloadFileError::Read)?;
+    serde_json::from_slice(&json).map_err(|e| {
+        DownloadFileError::Read(std::io::Error::new(std::io::ErrorKind::InvalidData, e)).into()
+    })
+}
+
+/// The duration the video should have without the missing parts.
+pub fn expected_duration(download_info: &DownloadInfo, gaps: &[PartGap]) -> f32 {
+    download_info.total_duration - gaps.iter().map(|gap| gap.duration).sum::<f32>()
+}
+
+/// The path the parts are converted to inside the part folder.
+pub fn converted_path(folder_path: &Path, container: OutputContainer) -> PathBuf {
+    folder_path.join(format!("video.{}", container.extension()))
+}
+
+/// The files of an interrupted conversion, which can't be resumed.
+///
+/// Both containers are included, since the config might have changed in between.
+pub fn converted_leftovers(folder_path: &Path) -> Vec<PathBuf> {
+    OutputContainer::ALL
+        .iter()
+        .map(|container| converted_path(folder_path, *container))
+        .chain(std::iter::once(folder_path.join("parts.txt")))
+        .collect()
+}
+
+/// Moves a file, also to another filesystem.
+///
+/// A rename does not work across filesystems, so in that case the file is
+/// copied next to the target, synced to the disk, renamed and then removed.
+pub async fn move_file(from: &Path, to: &Path) -> StdResult<(), DownloadFileError> {
+    match fs::rename(from, to).await {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {}
+        Err(e) => return Err(DownloadFileError::Filesystem(e)),
+    }
+    debug!("copying {:?} to {:?} on another filesystem", from, to);
+    let mut tmp_name = to.as_os_str().to_os_string();
+    tmp_name.push(".part");
+    let tmp_path = PathBuf::from(tmp_name);
+    let result = async {
+        fs::copy(from, &tmp_path).await?;
+        fs::File::open(&tmp_path).await?.sync_all().await?;
+        fs::rename(&tmp_path, to).await
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(DownloadFileError::Filesystem(e));
+    }
+    fs::remove_file(from)
+        .await
+        .map_err(DownloadFileError::Filesystem)
+}
+
+/// Removes everything from the part folder that is not a part of the
+/// playlist, e.g. parts of another quality or leftovers of older versions.
+/// The playlist written by [`write_playlist`], the download info and the
+/// manifest of the parts are kept.
+///
+/// Returns how many files and folders were removed.
+pub async fn remove_unexpected_files(folder_path: &Path, parts: &[PlaylistPart]) -> Result<usize> {
+    let mut expected: HashSet<String> = parts.iter().map(|part| part.file_name()).collect();
+    expected.insert(PLAYLIST_FILE_NAME.to_string());
+    expected.insert(DOWNLOAD_INFO_FILE_NAME.to_string());
+    expected.insert(MANIFEST_FILE_NAME.to_string());
+    let mut removed = 0;
+    let mut entries = fs::read_dir(folder_path)
+        .await
+        .map_err(DownloadFileError::Read)?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(DownloadFileError::Read)?
+    {
+        let path = entry.path();
+        if expected.contains(entry.file_name().to_string_lossy().as_ref()) {
+            continue;
+        }
+        debug!("removing {:?}, which is not a part of the playlist", path);
+        if path.is_dir() {
+            fs::remove_dir_all(&path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        } else {
+            fs::remove_file(&path)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Whether there are any downloaded parts in the folder.
+async fn has_parts(folder_path: &Path) -> StdResult<bool, DownloadFileError> {
+    let mut entries = fs::read_dir(folder_path)
+        .await
+        .map_err(DownloadFileError::Read)?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(DownloadFileError::Read)?
+    {
+        let path = entry.path();
+        let is_part = path.extension().is_some_and(|extension| extension == "ts")
+            && path.file_name().is_some_and(|name| name != "video.ts");
+        if is_part {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Appends all parts to a single ts file.
+///
+/// On Linux the parts are appended with `copy_file_range` if the filesystem
+/// supports it, otherwise they are read through the same buffer of
+/// `buffer_size` bytes.
+/// Every part is deleted as soon as it was appended, unless the parts are kept.
 #[instrument(skip(files), fields(part_amount=files.len()))]
-pub async fn combine_parts_to_single_ts(files: &[PathBuf], target: &Path) -> Result<()> {
+pub async fn combine_parts_to_single_ts(
+    files: &[PathBuf],
+    target: &Path,
+    keep_parts: bool,
+    buffer_size: usize,
+) -> Result<()> {
+    let _timer = metrics()
+        .processing_seconds
+        .with_label_values(&["combine"])
+        .start_timer();
+    append_parts(
+        files,
+        target,
+        keep_parts,
+        buffer_size,
+        cfg!(target_os = "linux"),
+    )
+    .await
+}
+
+/// Appends the parts like [`combine_parts_to_single_ts`], with
+/// `copy_file_range` only being tried if `in_kernel` is set.
+async fn append_parts(
+    files: &[PathBuf],
+    target: &Path,
+    keep_parts: bool,
+    buffer_size: usize,
+    in_kernel: bool,
+) -> Result<()> {
     debug!("combining all parts of video");
     debug!("part amount: {}", files.len());
     let target = fs::File::create(target)
         .await
         .map_err(DownloadFileError::FileCreation)?;
-    let mut target_buf = BufWriter::new(target);
+    let mut target_buf = BufWriter::with_capacity(buffer_size, target);
+    let mut buffer = vec![0; buffer_size.max(1)];
+    // only tried until it turns out to be unsupported
+    let mut use_copy_file_range = in_kernel;
+    let mut method_logged = false;
     for file_path in files {
         trace!("{:?}", file_path.file_name());
         let mut file = fs::File::open(&file_path)
             .await
             .map_err(DownloadFileError::Read)?;
+        let appended = use_copy_file_range && append_in_kernel(&file, &mut target_buf).await?;
+        if !method_logged || use_copy_file_range != appended {
+            if appended {
+                debug!("appending the parts with copy_file_range");
+            } else {
+                debug!(
+                    "appending the parts through a buffer of {} bytes",
+                    buffer_size
+                );
+            }
+            method_logged = true;
+        }
+        use_copy_file_range = appended;
+        if !appended {
+            loop {
+                let read = file
+                    .read(&mut buffer)
+                    .await
+                    .map_err(DownloadFileError::Read)?;
+                if read == 0 {
+                    break;
+                }
+                target_buf
+                    .write_all(&buffer[..read])
+                    .await
+                    .map_err(DownloadFileError::Write)?;
+            }
+        }
 
-        tokio::io::copy(&mut file, &mut target_buf)
-            .await
-            .map_err(DownloadFileError::Write)?;
-
-        tokio::fs::remove_file(&file_path)
-            .await
-            .map_err(DownloadFileError::Write)?;
+        if !keep_parts {
+            tokio::fs::remove_file(&file_path)
+                .await
+                .map_err(DownloadFileError::Write)?;
+        }
     }
     target_buf.flush().await.map_err(DownloadFileError::Write)?;
 
     Ok(())
 }
 
-pub async fn combine_parts_to_mp4(parts: &[PathBuf], folder_path: &Path) -> Result<PathBuf> {
+/// Appends the whole part to the target with `copy_file_range`.
+///
+/// Returns false without appending anything if the filesystem does not support it.
+async fn append_in_kernel(part: &fs::File, target: &mut BufWriter<fs::File>) -> Result<bool> {
+    // the kernel appends at the current offset of the target, so everything
+    // that is still buffered has to be written first
+    target.flush().await.map_err(DownloadFileError::Write)?;
+    let len = part
+        .metadata()
+        .await
+        .map_err(DownloadFileError::Read)?
+        .len();
+    let part = part
+        .try_clone()
+        .await
+        .map_err(DownloadFileError::Read)?
+        .into_std()
+        .await;
+    let target = target
+        .get_ref()
+        .try_clone()
+        .await
+        .map_err(DownloadFileError::Write)?
+        .into_std()
+        .await;
+    let appended = tokio::task::spawn_blocking(move || copy_file_range_all(&part, &target, len))
+        .await
+        .map_err(|e| DownloadFileError::Write(std::io::Error::other(e)))?;
+    Ok(appended.map_err(DownloadFileError::Write)?)
+}
+
+/// Copies `len` bytes from the current offset of `source` to the current
+/// offset of `target` without going through userspace.
+///
+/// Returns false if nothing was copied because the filesystems don't support it.
+#[cfg(target_os = "linux")]
+fn copy_file_range_all(
+    source: &std::fs::File,
+    target: &std::fs::File,
+    len: u64,
+) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    // the kernel copies at most this much with a single call anyway
+    const MAX_CHUNK: u64 = 1 << 30;
+    let mut remaining = len;
+    while remaining > 0 {
+        // SAFETY: both file descriptors belong to open files that outlive the
+        // call and null offsets make the kernel use and update the file offsets
+        let copied = unsafe {
+            libc::copy_file_range(
+                source.as_raw_fd(),
+                std::ptr::null_mut(),
+                target.as_raw_fd(),
+                std::ptr::null_mut(),
+                remaining.min(MAX_CHUNK) as usize,
+                0,
+            )
+        };
+        if copied < 0 {
+            let err = std::io::Error::last_os_error();
+            let unsupported = matches!(
+                err.raw_os_error(),
+                Some(libc::EOPNOTSUPP | libc::EXDEV | libc::ENOSYS | libc::EINVAL)
+            );
+            if unsupported && remaining == len {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        if copied == 0 {
+            // the part got shorter while it was copied
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        remaining -= copied as u64;
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_file_range_all(
+    _source: &std::fs::File,
+    _target: &std::fs::File,
+    _len: u64,
+) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Combines the parts to `video.mp4` (or the configured container) in the
+/// folder and makes sure the result is about as long as expected.
+///
+/// The converting phase is reported right before ffmpeg remuxes the video.
+/// Returns the path and the measured duration of the video.
+pub async fn combine_parts_to_mp4(
+    parts: &[PathBuf],
+    folder_path: &Path,
+    expected_duration: f32,
+    settings: &TwitchDownloaderConfig,
+    progress: Option<&mpsc::Sender<DownloadProgress>>,
+    video_id: &str,
+) -> Result<(PathBuf, f32)> {
     let ts_file_path = folder_path.join("video.ts");
-    let mp4_file_path = folder_path.join("video.mp4");
+    let mp4_file_path = converted_path(folder_path, settings.output_container);
+    let ffmpeg = &settings.ffmpeg_path;
+    let faststart = settings.use_faststart();
+    let keep_parts = settings.keep_parts;
+
+    if settings.pipe_parts_to_ffmpeg {
+        // ffmpeg combines and remuxes the parts in one go
+        DownloadProgress::report_phase(progress, video_id, DownloadPhase::Converting).await;
+        pipe_parts_to_mp4(parts, &mp4_file_path, ffmpeg, faststart, keep_parts).await?;
+    } else if !settings.combine_parts_to_ts {
+        DownloadProgress::report_phase(progress, video_id, DownloadPhase::Converting).await;
+        // validating before the parts are deleted keeps them for a retry
+        concat_parts_to_mp4(parts, folder_path, &mp4_file_path, ffmpeg, faststart).await?;
+        let duration = validate_mp4(&mp4_file_path, expected_duration, settings).await?;
+        if !keep_parts {
+            remove_parts(parts, folder_path).await?;
+        }
+        return Ok((mp4_file_path, duration));
+    } else {
+        combine_parts_to_single_ts(
+            parts,
+            &ts_file_path,
+            keep_parts,
+            settings.combine_buffer_size,
+        )
+        .await?;
+        DownloadProgress::report_phase(progress, video_id, DownloadPhase::Converting).await;
+        return convert_combined_ts(folder_path, expected_duration, settings).await;
+    }
+    let duration = validate_mp4(&mp4_file_path, expected_duration, settings).await?;
 
-    combine_parts_to_single_ts(parts, &ts_file_path).await?;
-    convert_ts_to_mp4(&ts_file_path, &mp4_file_path).await?;
+    Ok((mp4_file_path, duration))
+}
+
+/// Converts the combined `video.ts` in the folder to `video.mp4` (or the
+/// configured container) and makes sure the result is about as long as expected.
+///
+/// Returns the path and the measured duration of the video.
+pub async fn convert_combined_ts(
+    folder_path: &Path,
+    expected_duration: f32,
+    settings: &TwitchDownloaderConfig,
+) -> Result<(PathBuf, f32)> {
+    let ts_file_path = folder_path.join("video.ts");
+    let mp4_file_path = converted_path(folder_path, settings.output_container);
+    convert_ts_to_mp4(
+        &ts_file_path,
+        &mp4_file_path,
+        &settings.ffmpeg_path,
+        settings.use_faststart(),
+    )
+    .await?;
+    // the ts file is only removed once the mp4 is valid, so the conversion can be retried
+    let duration = validate_mp4(&mp4_file_path, expected_duration, settings).await?;
     tokio::fs::remove_file(ts_file_path)
         .await
         .map_err(DownloadFileError::Filesystem)?;
 
-    Ok(mp4_file_path)
+    Ok((mp4_file_path, duration))
+}
+
+/// Checks with ffprobe that the video is readable and not shorter than expected.
+///
+/// The video is removed if it is invalid. Returns the measured duration.
+#[instrument(skip(settings))]
+pub async fn validate_mp4(
+    mp4_file: &Path,
+    expected_duration: f32,
+    settings: &TwitchDownloaderConfig,
+) -> Result<f32> {
+    let result = probe_duration(mp4_file, &settings.ffprobe_path()).await;
+    let result = result.and_then(|actual| {
+        let min_duration = expected_duration * (1.0 - settings.duration_tolerance);
+        debug!(
+            "measured duration: {:.1}s, expected: {:.1}s",
+            actual, expected_duration
+        );
+        if actual < min_duration {
+            Err(DownloadFileError::VideoTooShort {
+                expected: expected_duration,
+                actual,
+            })
+        } else {
+            Ok(actual)
+        }
+    });
+    if result.is_err() && mp4_file.exists() {
+        tokio::fs::remove_file(mp4_file)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+    }
+    Ok(result?)
+}
+
+/// Checks that a video which already exists at its final path is not empty
+/// and can be read by ffprobe. Returns its duration.
+#[instrument(skip(settings))]
+pub async fn probe_existing_video(
+    path: &Path,
+    settings: &TwitchDownloaderConfig,
+) -> StdResult<f32, DownloadFileError> {
+    let size = tokio::fs::metadata(path)
+        .await
+        .map_err(DownloadFileError::Filesystem)?
+        .len();
+    if size == 0 {
+        return Err(DownloadFileError::TargetAlreadyExists(path.to_path_buf()));
+    }
+    probe_duration(path, &settings.ffprobe_path()).await
+}
+
+/// Gets the duration of a video in seconds with ffprobe.
+pub async fn probe_duration(file: &Path, ffprobe: &str) -> StdResult<f32, DownloadFileError> {
+    let output = Command::new(ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-show_entries")
+        .arg("format=duration")
+        .arg("-of")
+        .arg("default=noprint_wrappers=1:nokey=1")
+        .arg(file)
+        .output()
+        .await
+        .map_err(DownloadFileError::Ffmpeg)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.trim().parse::<f32>() {
+        Ok(duration) if output.status.success() => Ok(duration),
+        _ => Err(DownloadFileError::FfprobeFailed {
+            path: file.to_path_buf(),
+            stderr: stderr_tail(&output.stderr),
+        }),
+    }
 }
 
 #[instrument]
-pub async fn convert_ts_to_mp4(ts_file: &Path, mp4_file: &Path) -> Result<()> {
-    info!("converting to mp4");
+pub async fn convert_ts_to_mp4(
+    ts_file: &Path,
+    mp4_file: &Path,
+    ffmpeg: &str,
+    faststart: bool,
+) -> Result<()> {
+    let _timer = metrics()
+        .processing_seconds
+        .with_label_values(&["convert"])
+        .start_timer();
+    info!(
+        "converting to {}",
+        mp4_file.extension().unwrap_or_default().to_string_lossy()
+    );
     if mp4_file.exists() {
         tokio::fs::remove_file(&mp4_file)
             .await
             .map_err(DownloadFileError::Filesystem)?;
     }
     debug!(
-        "running ffmpeg command: ffmpeg -i {} -c {}",
+        "running ffmpeg command: {} -i {} {} {}",
+        ffmpeg,
         ts_file.display(),
+        copy_args(faststart).join(" "),
         mp4_file.display()
     );
-    let mut cmd = Command::new("ffmpeg");
+    if faststart {
+        debug!("moving the index to the front needs a second pass over the video");
+    }
+    let mut cmd = Command::new(ffmpeg);
     let start_time = Instant::now();
-    cmd.arg("-i")
+    cmd.kill_on_drop(true)
+        .arg("-i")
         .arg(ts_file)
-        .arg("-c")
-        .arg("copy")
+        .args(copy_args(faststart))
         .arg(mp4_file);
     let result = cmd.output().await;
     let duration = Instant::now().duration_since(start_time);
     debug!("ffmpeg command finished after duration: {:?}", duration);
-    result.map_err(DownloadFileError::Ffmpeg)?;
+    let output = result.map_err(DownloadFileError::Ffmpeg)?;
+    if !output.status.success() {
+        if mp4_file.exists() {
+            tokio::fs::remove_file(&mp4_file)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+        return Err(DownloadFileError::FfmpegFailed {
+            code: output.status.code(),
+            stderr: stderr_tail(&output.stderr),
+        }
+        .into());
+    }
     Ok(())
 }
-#[instrument]
+
+/// The path of the `index`th (starting at 1) file the video is split into,
+/// `<name>_part<index>.<extension>`.
+fn split_file_path(mp4_file: &Path, index: usize) -> PathBuf {
+    let stem = mp4_file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = mp4_file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string())
+        .unwrap_or_default();
+    mp4_file.with_file_name(format!("{}_part{}.{}", stem, index, extension))
+}
+
+/// The files an earlier run split the video into, in order.
+///
+/// Empty if the video was not split (or the split did not finish, since the
+/// files are only complete once the video itself is removed).
+pub fn existing_split_files(mp4_file: &Path) -> Vec<PathBuf> {
+    if mp4_file.exists() {
+        return vec![];
+    }
+    (1..)
+        .map(|index| split_file_path(mp4_file, index))
+        .take_while(|path| path.is_file())
+        .collect()
+}
+
+/// Splits the video into files that are not longer than the configured
+/// maximum duration, if it is longer than that.
+///
+/// The files are called `<name>_part1.mp4`, `<name>_part2.mp4`, ... (with
+/// the extension of the video) and the
+/// original video is removed afterwards. Returns the split files, which is
+/// empty if the video was short enough.
+///
+/// If splitting fails, the files that were already written are removed and
+/// the video is kept, so the split can be retried from it.
+#[instrument(skip(settings))]
+pub async fn split_video_if_too_long(
+    mp4_file: &Path,
+    duration: f32,
+    settings: &TwitchDownloaderConfig,
+) -> Result<Vec<PathBuf>> {
+    let Some(max_hours) = settings.max_output_duration_hours else {
+        return Ok(vec![]);
+    };
+    let max_secs = max_hours * 3600.0;
+    if duration <= max_secs {
+        return Ok(vec![]);
+    }
+    let overlap = (settings.split_overlap_secs as f32).min(max_secs / 2.0);
+    let step = max_secs - overlap;
+    let count = ((duration - overlap) / step).ceil() as usize;
+    info!(
+        "splitting the video ({}) into {} files of at most {}",
+        format_duration(duration as u64),
+        count,
+        format_duration(max_secs as u64)
+    );
+    let mut files = vec![];
+    for i in 0..count {
+        let start = i as f32 * step;
+        let target = split_file_path(mp4_file, i + 1);
+        debug!("writing {:?} starting at {:.1}s", target, start);
+        let output = Command::new(&settings.ffmpeg_path)
+            .kill_on_drop(true)
+            .arg("-y")
+            .arg("-ss")
+            .arg(format!("{:.3}", start))
+            .arg("-i")
+            .arg(mp4_file)
+            .arg("-t")
+            .arg(format!("{:.3}", max_secs))
+            .args(copy_args(settings.use_faststart()))
+            .arg(&target)
+            .output()
+            .await
+            .map_err(DownloadFileError::Ffmpeg)?;
+        if !output.status.success() {
+            for file in files.iter().chain(std::iter::once(&target)) {
+                if file.exists() {
+                    tokio::fs::remove_file(file)
+                        .await
+                        .map_err(DownloadFileError::Filesystem)?;
+                }
+            }
+            return Err(DownloadFileError::FfmpegFailed {
+                code: output.status.code(),
+                stderr: stderr_tail(&output.stderr),
+            }
+            .into());
+        }
+        files.push(target);
+    }
+    tokio::fs::remove_file(mp4_file)
+        .await
+        .map_err(DownloadFileError::Filesystem)?;
+    Ok(files)
+}
+
+/// Lets ffmpeg read the parts directly with the concat demuxer, so no combined ts file is needed.
+///
+/// The parts are not deleted, so they are still there if the result turns out to be invalid.
+#[instrument(skip(files), fields(part_amount=files.len()))]
+pub async fn concat_parts_to_mp4(
+    files: &[PathBuf],
+    folder_path: &Path,
+    mp4_file: &Path,
+    ffmpeg: &str,
+    faststart: bool,
+) -> Result<()> {
+    let _timer = metrics()
+        .processing_seconds
+        .with_label_values(&["concat"])
+        .start_timer();
+    info!("concatenating parts with ffmpeg");
+    if mp4_file.exists() {
+        tokio::fs::remove_file(&mp4_file)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+    }
+    let list_path = folder_path.join("parts.txt");
+    let mut list = String::new();
+    for file_path in files {
+        // relative paths in the list would be resolved relative to the list file
+        let file_path = std::path::absolute(file_path).map_err(DownloadFileError::Filesystem)?;
+        list.push_str(&concat_list_entry(&file_path));
+    }
+    tokio::fs::write(&list_path, list)
+        .await
+        .map_err(DownloadFileError::Write)?;
+
+    let start_time = Instant::now();
+    let result = Command::new(ffmpeg)
+        .kill_on_drop(true)
+        .arg("-f")
+        .arg("concat")
+        .arg("-safe")
+        .arg("0")
+        .arg("-i")
+        .arg(&list_path)
+        .args(copy_args(faststart))
+        .arg(mp4_file)
+        .output()
+        .await;
+    debug!(
+        "ffmpeg command finished after duration: {:?}",
+        start_time.elapsed()
+    );
+    let output = result.map_err(DownloadFileError::Ffmpeg)?;
+    if !output.status.success() {
+        if mp4_file.exists() {
+            tokio::fs::remove_file(&mp4_file)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+        return Err(DownloadFileError::FfmpegFailed {
+            code: output.status.code(),
+            stderr: stderr_tail(&output.stderr),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The ffmpeg arguments that copy the streams into the output file.
+///
+/// With faststart, ffmpeg moves the index of the mp4 to the front in a second
+/// pass after writing it, so the file can be streamed right away.
+fn copy_args(faststart: bool) -> Vec<&'static str> {
+    let mut args = vec!["-c", "copy"];
+    if faststart {
+        args.extend(["-movflags", "+faststart"]);
+    }
+    args
+}
+
+/// Removes the parts and the concat list after they were combined.
+async fn remove_parts(files: &[PathBuf], folder_path: &Path) -> Result<()> {
+    for file_path in files {
+        tokio::fs::remove_file(&file_path)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+    }
+    tokio::fs::remove_file(folder_path.join("parts.txt"))
+        .await
+        .map_err(DownloadFileError::Filesystem)?;
+    Ok(())
+}
+
+/// Formats a path as a line of an ffmpeg concat list.
+///
+/// Single quotes can't be escaped inside a quoted string, so the string is
+/// closed, an escaped quote is added and the string is opened again.
+fn concat_list_entry(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\'', "'\\''");
+    format!("file '{}'\n", path)
+}
+
+/// Feeds all parts through the stdin of ffmpeg, so no combined ts file is needed.
+///
+/// Every part is deleted as soon as it was written to ffmpeg, unless the parts are kept.
+/// Nothing is left to retry the conversion with then, so if the mp4 turns out
+/// to be invalid, all parts have to be downloaded again.
+#[instrument(skip(files), fields(part_amount=files.len()))]
+pub async fn pipe_parts_to_mp4(
+    files: &[PathBuf],
+    mp4_file: &Path,
+    ffmpeg: &str,
+    faststart: bool,
+    keep_parts: bool,
+) -> Result<()> {
+    info!("piping parts to ffmpeg");
+    if mp4_file.exists() {
+        tokio::fs::remove_file(&mp4_file)
+            .await
+            .map_err(DownloadFileError::Filesystem)?;
+    }
+    let start_time = Instant::now();
+    let mut child = Command::new(ffmpeg)
+        .kill_on_drop(true)
+        .arg("-f")
+        .arg("mpegts")
+        .arg("-i")
+        .arg("pipe:0")
+        .args(copy_args(faststart))
+        .arg(mp4_file)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(DownloadFileError::Ffmpeg)?;
+
+    // stderr has to be read while writing to stdin, otherwise ffmpeg can block on a full pipe
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stderr_reader = tokio::spawn(async move {
+        let mut buf = vec![];
+        let _ = stderr.read_to_end(&mut buf).await;
+        buf
+    });
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut pipe_error = None;
+    for file_path in files {
+        trace!("{:?}", file_path.file_name());
+        let mut file = fs::File::open(&file_path)
+            .await
+            .map_err(DownloadFileError::Read)?;
+        if let Err(e) = tokio::io::copy(&mut file, &mut stdin).await {
+            // ffmpeg closed the pipe early, the exit status tells us why
+            pipe_error = Some(e);
+            break;
+        }
+        if !keep_parts {
+            tokio::fs::remove_file(&file_path)
+                .await
+                .map_err(DownloadFileError::Write)?;
+        }
+    }
+    // closing stdin signals the end of the input to ffmpeg
+    drop(stdin);
+
+    let status = child.wait().await.map_err(DownloadFileError::Ffmpeg)?;
+    let stderr = stderr_reader.await.unwrap_or_default();
+    debug!(
+        "ffmpeg command finished after duration: {:?}",
+        start_time.elapsed()
+    );
+    if !status.success() {
+        if mp4_file.exists() {
+            tokio::fs::remove_file(&mp4_file)
+                .await
+                .map_err(DownloadFileError::Filesystem)?;
+        }
+        return Err(DownloadFileError::FfmpegFailed {
+            code: status.code(),
+            stderr: stderr_tail(&stderr),
+        }
+        .into());
+    }
+    if let Some(e) = pipe_error {
+        return Err(DownloadFileError::Write(e).into());
+    }
+    Ok(())
+}
+
+/// The maximum amount of bytes of the ffmpeg stderr that is kept for errors.
+const MAX_STDERR_LEN: usize = 2048;
+
+/// Gets the last part of the stderr output, since that is where ffmpeg puts the actual error.
+fn stderr_tail(stderr: &[u8]) -> String {
+    let start = stderr.len().saturating_sub(MAX_STDERR_LEN);
+    String::from_utf8_lossy(&stderr[start..]).trim().to_string()
+}
+/// Gets the available space on the filesystem that contains the given path.
+///
+/// If the path does not exist yet, the closest existing parent is used.
+pub fn get_available_space(path: &Path) -> StdResult<u64, DownloadFileError> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+    fs2::available_space(existing).map_err(DownloadFileError::Read)
+}
+
+/// Fails if the filesystem that contains the path has less than `required` bytes free.
+pub fn require_space(path: &Path, required: u64) -> StdResult<(), DownloadFileError> {
+    let available = get_available_space(path)?;
+    debug!(
+        "available space in {:?}: {}, required space: {}",
+        path,
+        format_bytes(available),
+        format_bytes(required)
+    );
+    if available < required {
+        return Err(DownloadFileError::InsufficientDiskSpace {
+            path: path.to_path_buf(),
+            available,
+            required,
+        });
+    }
+    Ok(())
+}
+
+/// Everything that is shared between the downloads of all parts of a video.
+#[derive(Debug, Clone)]
+pub struct PartDownloadContext {
+    pub client: ReqwestClient,
+    pub retry: RetryPolicy,
+    pub limiter: Arc<BandwidthLimiter>,
+    /// Counts the downloaded bytes of the whole run.
+    pub budget: Arc<ByteBudget>,
+    /// The maximum time a single download attempt of a part may take.
+    pub part_timeout: Duration,
+    /// The maximum time to wait for the next chunk of a part.
+    pub stall_timeout: Duration,
+    pub size_check: PartSizeCheck,
+}
+
+/// Downloads a single part into the folder and returns its path, size and hash.
+#[instrument(skip(ctx))]
 pub async fn download_part(
-    part: (String, f32),
+    part: PlaylistPart,
     base_url: String,
     folder_path: &Path,
     try_unmute: bool,
-    client: ReqwestClient,
-) -> StdResult<PathBuf, DownloadFileError> {
+    ctx: PartDownloadContext,
+) -> StdResult<(PathBuf, ManifestEntry), DownloadFileError> {
     trace!("downloading part: {:?}", part);
-    let (part, _duration) = part;
+    // guards, so the metrics are also updated when the download gets cancelled
+    let _in_flight = InFlightPart::start();
+    let _timer = metrics().part_download_seconds.start_timer();
+    download_part_inner(part, base_url, folder_path, try_unmute, &ctx).await
+}
+
+async fn download_part_inner(
+    part: PlaylistPart,
+    base_url: String,
+    folder_path: &Path,
+    try_unmute: bool,
+    ctx: &PartDownloadContext,
+) -> StdResult<(PathBuf, ManifestEntry), DownloadFileError> {
+    let range = part.byte_range;
 
-    let part_url = format!("{}{}", base_url, part);
-    let part_url_unmuted = format!("{}{}", base_url, part.replace("-muted", ""));
+    let part_url = part.url(&base_url);
+    let part_url_unmuted = part.unmuted_url(&base_url);
 
-    let try_unmute = try_unmute && part.contains("-muted");
-    let target_path = folder_path.join(&part);
+    let try_unmute = try_unmute && part.uri.contains("-muted");
+    let target_path = folder_path.join(part.file_name());
+    let min_size = ctx.size_check.min_size(&part);
 
     if try_unmute {
         trace!("trying to download unmuted part: {}", part_url_unmuted);
-        match try_download_part(part_url_unmuted, &target_path, &client).await {
-            Ok(path) => Ok(path),
+        match try_download_part(part_url_unmuted, &target_path, range, min_size, ctx).await {
+            Ok(downloaded) => Ok(downloaded),
             Err(_) => {
                 trace!("failed to download unmuted part. trying muted part");
-                try_download_part(part_url, folder_path, &client).await
+                try_download_part(part_url, &target_path, range, min_size, ctx).await
             }
         }
     } else {
         trace!("not trying to unmute: {}", part_url);
-        try_download_part(part_url, &target_path, &client).await
+        try_download_part(part_url, &target_path, range, min_size, ctx).await
+    }
+}
+/// Checks if a part was already downloaded completely by a previous run.
+///
+/// A part counts as downloaded if the file exists, is not empty and its size
+/// matches the Content-Length of the remote part (if the server reports one).
+/// For parts that might have been unmuted, both the muted and unmuted
+/// sizes are accepted.
+pub async fn is_part_downloaded(
+    part: &PlaylistPart,
+    base_url: &str,
+    folder_path: &Path,
+    try_unmute: bool,
+    client: &ReqwestClient,
+    retry: &RetryPolicy,
+    size_check: PartSizeCheck,
+) -> bool {
+    let size = match fs::metadata(folder_path.join(part.file_name())).await {
+        Ok(metadata) if metadata.is_file() && metadata.len() > 0 => metadata.len(),
+        _ => return false,
+    };
+    if size < size_check.min_size(part) {
+        debug!(
+            "part {} is only {} bytes, downloading it again",
+            part.uri, size
+        );
+        return false;
     }
+    if let Some(range) = part.byte_range {
+        return size == range.length;
+    }
+    let mut urls = vec![part.url(base_url)];
+    if try_unmute && part.uri.contains("-muted") {
+        urls.push(part.unmuted_url(base_url));
+    }
+    let mut any_length_known = false;
+    for url in urls {
+        match get_remote_size(url, client, retry).await {
+            Some(remote_size) if remote_size == size => return true,
+            Some(_) => any_length_known = true,
+            None => {}
+        }
+    }
+    if any_length_known {
+        debug!(
+            "part {} exists but has the wrong size, downloading it again",
+            part.uri
+        );
+    }
+    !any_length_known
 }
+
+/// Gets the Content-Length of a remote file with a HEAD request.
+pub async fn get_remote_size(
+    url: String,
+    client: &ReqwestClient,
+    retry: &RetryPolicy,
+) -> Option<u64> {
+    let request = client.head(url).build().ok()?;
+    let response = retry.execute(client, request).await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// How often a part is downloaded again if the download was incomplete or timed out.
+const MAX_PART_DOWNLOAD_ATTEMPTS: usize = 3;
+
 pub async fn try_download_part(
     url: String,
     target_path: &Path,
-    client: &ReqwestClient,
+    range: Option<ByteRange>,
+    min_size: u64,
+    ctx: &PartDownloadContext,
+) -> StdResult<(PathBuf, ManifestEntry), DownloadFileError> {
+    if target_path.is_dir() {
+        return Err(DownloadFileError::TargetIsADirectory(
+            target_path.to_path_buf(),
+        ));
+    }
+    let mut attempt = 1;
+    loop {
+        let throttled = AtomicU64::new(0);
+        let download = try_download_part_once(&url, target_path, range, ctx, &throttled);
+        let result = with_part_timeout(download, &url, ctx.part_timeout, &throttled).await;
+        let result = match result {
+            Ok((path, entry)) => check_part_size(&url, path, min_size)
+                .await
+                .map(|path| (path, entry)),
+            result => result,
+        };
+        match result {
+            Err(
+                err @ (DownloadFileError::IncompleteDownload { .. }
+                | DownloadFileError::Timeout { .. }
+                | DownloadFileError::PartTooSmall { .. }),
+            ) if attempt < MAX_PART_DOWNLOAD_ATTEMPTS => {
+                warn!("{} (attempt {}), trying again", err, attempt);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Runs a download attempt of a part with the part timeout.
+///
+/// The time the bandwidth limiter held the download back (in nanoseconds in
+/// `throttled`) does not count, so a low speed limit does not time out parts.
+async fn with_part_timeout<T>(
+    download: impl Future<Output = StdResult<T, DownloadFileError>>,
+    url: &str,
+    timeout: Duration,
+    throttled: &AtomicU64,
+) -> StdResult<T, DownloadFileError> {
+    tokio::pin!(download);
+    let mut deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        tokio::select! {
+            result = &mut download => return result,
+            _ = tokio::time::sleep_until(deadline) => {
+                let waited = Duration::from_nanos(throttled.swap(0, Ordering::Relaxed));
+                if waited.is_zero() {
+                    return Err(DownloadFileError::Timeout {
+                        url: url.to_string(),
+                        timeout,
+                    });
+                }
+                deadline += waited;
+            }
+        }
+    }
+}
+
+/// Removes the downloaded part again if it is too small to be complete.
+async fn check_part_size(
+    url: &str,
+    path: PathBuf,
+    min_size: u64,
 ) -> StdResult<PathBuf, DownloadFileError> {
-    let request = client
-        .get(url)
+    let size = fs::metadata(&path)
+        .await
+        .map_err(DownloadFileError::Read)?
+        .len();
+    if size >= min_size {
+        return Ok(path);
+    }
+    fs::remove_file(&path)
+        .await
+        .map_err(DownloadFileError::Filesystem)?;
+    Err(DownloadFileError::PartTooSmall {
+        url: url.to_string(),
+        size,
+        min_size,
+    })
+}
+
+async fn try_download_part_once(
+    url: &str,
+    target_path: &Path,
+    range: Option<ByteRange>,
+    ctx: &PartDownloadContext,
+    throttled: &AtomicU64,
+) -> StdResult<(PathBuf, ManifestEntry), DownloadFileError> {
+    let client = &ctx.client;
+    let mut request = client.get(url);
+    if let Some(range) = range {
+        request = request.header(reqwest::header::RANGE, range.header_value());
+    }
+    let request = request
         .build()
         .map_err(DownloadFileError::DownloadReqwest)?;
-    let mut response = client
-        .execute_with_backoff(request)
+    let mut response = ctx
+        .retry
+        .execute(client, request)
         .await
-        .map_err(DownloadFileError::DownloadBackoff)?;
+        .map_err(DownloadFileError::DownloadReqwest)?;
+    if response.status() == reqwest::StatusCode::FORBIDDEN {
+        return Err(DownloadFileError::Forbidden(url.to_string()));
+    }
+    // the body of an error would otherwise end up in the video as a part
+    if !response.status().is_success() {
+        return Err(DownloadFileError::PartStatus {
+            url: url.to_string(),
+            status: response.status(),
+        });
+    }
+    let expected_size = response.content_length();
 
-    let mut file = fs::File::create(target_path)
+    // the part only gets its real name once it is complete, so a crash or
+    // timeout can't leave a truncated part that looks like a downloaded one
+    let temp_path = temp_part_path(target_path);
+    let result = write_part(
+        &mut response,
+        url,
+        &temp_path,
+        expected_size,
+        ctx,
+        throttled,
+    )
+    .await;
+    let entry = match result {
+        Ok(entry) => entry,
+        Err(err) => {
+            if let Err(remove_err) = fs::remove_file(&temp_path).await {
+                if remove_err.kind() != std::io::ErrorKind::NotFound {
+                    warn!(
+                        "Could not remove the temporary part {:?}: {:?}",
+                        temp_path, remove_err
+                    );
+                }
+            }
+            return Err(err);
+        }
+    };
+    fs::rename(&temp_path, target_path)
         .await
-        .map_err(DownloadFileError::FileCreation)?;
+        .map_err(DownloadFileError::Filesystem)?;
+    Ok((target_path.to_path_buf(), entry))
+}
 
-    while let Some(chunk) = response
-        .chunk()
+/// Streams the response into the file and checks that it is complete.
+///
+/// The part is hashed while it is written, for the manifest of the parts.
+/// The time spent waiting for the bandwidth limiter is added to `throttled`.
+async fn write_part(
+    response: &mut reqwest::Response,
+    url: &str,
+    path: &Path,
+    expected_size: Option<u64>,
+    ctx: &PartDownloadContext,
+    throttled: &AtomicU64,
+) -> StdResult<ManifestEntry, DownloadFileError> {
+    let mut file = fs::File::create(path)
         .await
-        .map_err(DownloadFileError::DownloadReqwest)?
-    {
+        .map_err(DownloadFileError::FileCreation)?;
+
+    let mut hasher = PartHasher::new();
+    let mut written: u64 = 0;
+    loop {
+        let chunk = tokio::time::timeout(ctx.stall_timeout, response.chunk())
+            .await
+            .map_err(|_| DownloadFileError::Timeout {
+                url: url.to_string(),
+                timeout: ctx.stall_timeout,
+            })?
+            .map_err(DownloadFileError::DownloadReqwest)?;
+        let Some(chunk) = chunk else {
+            break;
+        };
         file.write_all(&chunk)
             .await
             .map_err(DownloadFileError::Filesystem)?;
+        hasher.update(&chunk);
+        written += chunk.len() as u64;
+        metrics().bytes_downloaded.inc_by(chunk.len() as u64);
+        ctx.budget.consume(chunk.len() as u64);
+        let waited = ctx.limiter.consume(chunk.len() as u64).await;
+        throttled.fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+    }
+    file.flush().await.map_err(DownloadFileError::Filesystem)?;
+
+    if let Some(expected) = expected_size {
+        if expected != written {
+            return Err(DownloadFileError::IncompleteDownload {
+                expected,
+                got: written,
+                url: url.to_string(),
+            });
+        }
+    }
+    Ok(ManifestEntry {
+        size: written,
+        hash: hasher.finish(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes parts with different, made up content and returns them in order.
+    fn write_parts(folder: &Path, count: usize, size: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|index| {
+                let path = folder.join(format!("{}.ts", index));
+                let content: Vec<u8> = (0..size).map(|i| (i * 31 + index * 7) as u8).collect();
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn concatenated(files: &[PathBuf]) -> Vec<u8> {
+        files
+            .iter()
+            .flat_map(|file| std::fs::read(file).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn faststart_is_only_passed_to_ffmpeg_if_enabled() {
+        assert_eq!(copy_args(true), ["-c", "copy", "-movflags", "+faststart"]);
+        assert_eq!(copy_args(false), ["-c", "copy"]);
+    }
+
+    #[test]
+    fn faststart_only_applies_to_mp4() {
+        let mut settings = TwitchDownloaderConfig::default();
+        assert!(settings.faststart);
+        assert!(settings.use_faststart());
+        settings.output_container = OutputContainer::Mkv;
+        assert!(!settings.use_faststart());
+        settings.output_container = OutputContainer::Mp4;
+        settings.faststart = false;
+        assert!(!settings.use_faststart());
+    }
+
+    /// Creates the files in the folder and returns the names of all files in it afterwards.
+    async fn resume_point_with(files: &[&str]) -> (Result<ResumePoint>, Vec<String>) {
+        let folder = tempfile::tempdir().unwrap();
+        for file in files {
+            std::fs::write(folder.path().join(file), b"leftover").unwrap();
+        }
+        let resume_point = determine_resume_point(folder.path()).await;
+        let mut left: Vec<String> = std::fs::read_dir(folder.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        (resume_point, left)
+    }
+
+    #[tokio::test]
+    async fn a_new_download_creates_the_part_folder() {
+        let folder = tempfile::tempdir().unwrap();
+        let parts_folder = folder.path().join("1");
+
+        let resume_point = determine_resume_point(&parts_folder).await.unwrap();
+
+        assert_eq!(resume_point, ResumePoint::DownloadParts);
+        assert!(parts_folder.is_dir());
+    }
+
+    #[tokio::test]
+    async fn a_file_in_place_of_the_part_folder_is_an_error() {
+        let folder = tempfile::tempdir().unwrap();
+        let parts_folder = folder.path().join("1");
+        std::fs::write(&parts_folder, b"not a folder").unwrap();
+
+        let result = determine_resume_point(&parts_folder).await;
+
+        assert!(matches!(
+            result,
+            Err(DownloaderError::File(
+                DownloadFileError::TargetFolderIsNotADirectory(_)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn an_empty_part_folder_downloads_the_parts() {
+        let (resume_point, left) = resume_point_with(&[]).await;
+        assert_eq!(resume_point.unwrap(), ResumePoint::DownloadParts);
+        assert!(left.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unfinished_parts_are_removed() {
+        let (resume_point, left) = resume_point_with(&["0.ts", "1.ts", "2.ts.tmp"]).await;
+        assert_eq!(resume_point.unwrap(), ResumePoint::DownloadParts);
+        assert_eq!(left, ["0.ts", "1.ts"]);
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_conversion_of_the_parts_is_removed() {
+        let (resume_point, left) =
+            resume_point_with(&["0.ts", "1.ts", "parts.txt", "video.mp4", "video.mkv"]).await;
+        assert_eq!(resume_point.unwrap(), ResumePoint::DownloadParts);
+        assert_eq!(left, ["0.ts", "1.ts"]);
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_combination_downloads_the_parts_again() {
+        let (resume_point, left) = resume_point_with(&["1.ts", "2.ts", "video.ts"]).await;
+        assert_eq!(resume_point.unwrap(), ResumePoint::DownloadParts);
+        assert_eq!(left, ["1.ts", "2.ts"]);
+    }
+
+    #[tokio::test]
+    async fn combined_parts_are_converted() {
+        let (resume_point, left) = resume_point_with(&["video.ts", "download_info.json"]).await;
+        assert_eq!(resume_point.unwrap(), ResumePoint::ConvertTs);
+        assert_eq!(left, ["download_info.json", "video.ts"]);
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_conversion_of_the_combined_parts_is_redone() {
+        let (resume_point, left) = resume_point_with(&["video.ts", "video.mp4"]).await;
+        assert_eq!(resume_point.unwrap(), ResumePoint::ConvertTs);
+        assert_eq!(left, ["video.ts"]);
+    }
+
+    /// The order the parts were combined in before the playlist order was
+    /// kept, by the number in their names.
+    fn legacy_part_order(files: &mut [PathBuf]) {
+        files.sort_by_key(|path| {
+            let number = path
+                .file_stem()
+                .unwrap()
+                .to_string_lossy()
+                .replace("-muted", "")
+                .replace("-unmuted", "");
+            number.parse::<u32>().unwrap()
+        });
+    }
+
+    #[tokio::test]
+    async fn combining_in_playlist_order_gives_the_same_video_as_sorting_by_name() {
+        let folder = tempfile::tempdir().unwrap();
+        let parts: Vec<PlaylistPart> = (0..12)
+            .map(|index| match index {
+                3 | 7 => format!("{}-muted.ts", index),
+                index => format!("{}.ts", index),
+            })
+            .map(|uri| playlist_part(&uri))
+            .collect();
+        let files: Vec<PathBuf> = parts
+            .iter()
+            .enumerate()
+            .map(|(index, part)| {
+                let path = folder.path().join(part.file_name());
+                let content: Vec<u8> = (0..4096).map(|i| (i * 13 + index * 29) as u8).collect();
+                std::fs::write(&path, content).unwrap();
+                path.canonicalize().unwrap()
+            })
+            .collect();
+        verify_parts(&files, &parts, folder.path(), PartSizeCheck::default()).unwrap();
+        let mut legacy_files: Vec<PathBuf> = std::fs::read_dir(folder.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path().canonicalize().unwrap())
+            .collect();
+        legacy_part_order(&mut legacy_files);
+        let target = folder.path().join("video.ts");
+
+        combine_parts_to_single_ts(&files, &target, true, 1000)
+            .await
+            .unwrap();
+
+        assert_eq!(legacy_files, files);
+        assert_eq!(std::fs::read(&target).unwrap(), concatenated(&legacy_files));
+    }
+
+    fn playlist_part(uri: &str) -> PlaylistPart {
+        PlaylistPart {
+            uri: uri.to_string(),
+            duration: 10.0,
+            byte_range: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn stray_files_in_the_part_folder_are_removed_instead_of_combined() {
+        let folder = tempfile::tempdir().unwrap();
+        let parts: Vec<PlaylistPart> = ["1094734-7.ts", "123.ts", "456-muted.ts"]
+            .into_iter()
+            .map(playlist_part)
+            .collect();
+        for part in &parts {
+            std::fs::write(folder.path().join(part.file_name()), b"part").unwrap();
+        }
+        for garbage in [".DS_Store", "123.ts~", "notes.txt", "abc.ts"] {
+            std::fs::write(folder.path().join(garbage), b"garbage").unwrap();
+        }
+
+        let removed = remove_unexpected_files(folder.path(), &parts)
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 4);
+        let files: Vec<PathBuf> = parts
+            .iter()
+            .map(|part| folder.path().join(part.file_name()).canonicalize().unwrap())
+            .collect();
+        verify_parts(&files, &parts, folder.path(), PartSizeCheck::default()).unwrap();
+        let mut left: Vec<String> = std::fs::read_dir(folder.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["1094734-7.ts", "123.ts", "456-muted.ts"]);
+    }
+
+    #[test]
+    fn parts_out_of_playlist_order_are_rejected() {
+        let folder = tempfile::tempdir().unwrap();
+        let parts: Vec<PlaylistPart> = ["123.ts", "456-muted.ts"]
+            .into_iter()
+            .map(playlist_part)
+            .collect();
+        let mut files: Vec<PathBuf> = parts
+            .iter()
+            .map(|part| {
+                let path = folder.path().join(part.file_name());
+                std::fs::write(&path, b"part").unwrap();
+                path.canonicalize().unwrap()
+            })
+            .collect();
+        files.reverse();
+
+        let result = verify_parts(&files, &parts, folder.path(), PartSizeCheck::default());
+
+        assert!(matches!(
+            result,
+            Err(DownloadFileError::MissingParts {
+                expected: 2,
+                got: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn finds_the_files_of_a_finished_split() {
+        let folder = tempfile::tempdir().unwrap();
+        let video = folder.path().join("1.mp4");
+        assert!(existing_split_files(&video).is_empty());
+
+        let chunks: Vec<PathBuf> = (1..=3).map(|i| split_file_path(&video, i)).collect();
+        for chunk in &chunks {
+            std::fs::write(chunk, b"chunk").unwrap();
+        }
+        std::fs::write(folder.path().join("1_part5.mp4"), b"unrelated").unwrap();
+        assert_eq!(chunks[1], folder.path().join("1_part2.mp4"));
+        assert_eq!(existing_split_files(&video), chunks);
+
+        // the split did not finish while the video is still there
+        std::fs::write(&video, b"video").unwrap();
+        assert!(existing_split_files(&video).is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_buffered_fallback_appends_the_parts_unchanged() {
+        let folder = tempfile::tempdir().unwrap();
+        // parts that don't fill the buffer evenly
+        let parts = write_parts(folder.path(), 5, 10_000);
+        let expected = concatenated(&parts);
+        let target = folder.path().join("video.ts");
+
+        append_parts(&parts, &target, false, 4096, false)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), expected);
+        assert!(parts.iter().all(|part| !part.exists()));
+    }
+
+    #[tokio::test]
+    async fn copy_file_range_appends_the_parts_unchanged() {
+        let folder = tempfile::tempdir().unwrap();
+        let parts = write_parts(folder.path(), 5, 10_000);
+        let expected = concatenated(&parts);
+        let target = folder.path().join("video.ts");
+
+        combine_parts_to_single_ts(&parts, &target, true, 4096)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), expected);
+        assert!(parts.iter().all(|part| part.exists()));
+    }
+
+    /// How the parts were combined before the buffer was configurable.
+    #[cfg(feature = "bench")]
+    async fn combine_with_tokio_copy(files: &[PathBuf], target: &Path) {
+        let mut target = BufWriter::new(fs::File::create(target).await.unwrap());
+        for file in files {
+            let mut file = fs::File::open(file).await.unwrap();
+            tokio::io::copy(&mut file, &mut target).await.unwrap();
+        }
+        target.flush().await.unwrap();
+    }
+
+    /// Compares the old way of combining the parts to the current one.
+    ///
+    /// Run with `cargo test --release --features bench combine_benchmark -- --nocapture`.
+    #[cfg(feature = "bench")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn combine_benchmark() {
+        const PARTS: usize = 200;
+        const PART_SIZE: usize = 2 * 1024 * 1024;
+        let folder = tempfile::tempdir().unwrap();
+        let parts = write_parts(folder.path(), PARTS, PART_SIZE);
+        let old_target = folder.path().join("old.ts");
+        let new_target = folder.path().join("new.ts");
+
+        let start = std::time::Instant::now();
+        combine_with_tokio_copy(&parts, &old_target).await;
+        let old = start.elapsed();
+        let start = std::time::Instant::now();
+        combine_parts_to_single_ts(&parts, &new_target, true, 1024 * 1024)
+            .await
+            .unwrap();
+        let new = start.elapsed();
+
+        println!(
+            "combined {} parts of {}: tokio::io::copy took {:?}, the reused buffer took {:?}",
+            PARTS,
+            format_bytes(PART_SIZE as u64),
+            old,
+            new
+        );
+        let size = |path: &Path| std::fs::metadata(path).unwrap().len();
+        assert_eq!(size(&old_target), size(&new_target));
+    }
+
+    fn part_download_context() -> PartDownloadContext {
+        PartDownloadContext {
+            client: reqwest::Client::new().into(),
+            retry: RetryPolicy {
+                max_attempts: 1,
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+            },
+            limiter: Arc::new(BandwidthLimiter::new(0)),
+            budget: Arc::new(ByteBudget::new(None)),
+            part_timeout: Duration::from_secs(10),
+            stall_timeout: Duration::from_secs(10),
+            size_check: PartSizeCheck::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_part_that_was_not_unmuted_is_downloaded_muted() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/vod/1.ts"))
+            .respond_with(ResponseTemplate::new(403))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/vod/1-muted.ts"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"muted part".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let folder = tempfile::tempdir().unwrap();
+
+        let (path, entry) = download_part(
+            playlist_part("1-muted.ts"),
+            format!("{}/vod/", server.uri()),
+            folder.path(),
+            true,
+            part_download_context(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(path, folder.path().join("1-muted.ts"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"muted part");
+        assert_eq!(entry.size, 10);
+        assert!(!temp_part_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn the_unmuted_part_is_preferred() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/vod/1.ts"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"unmuted part".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/vod/1-muted.ts"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"muted part".to_vec()))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let folder = tempfile::tempdir().unwrap();
+
+        let (path, _) = download_part(
+            playlist_part("1-muted.ts"),
+            format!("{}/vod/", server.uri()),
+            folder.path(),
+            true,
+            part_download_context(),
+        )
+        .await
+        .unwrap();
+
+        // the part keeps the name from the playlist, so it is found again on a resume
+        assert_eq!(path, folder.path().join("1-muted.ts"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"unmuted part");
+    }
+
+    #[tokio::test]
+    async fn a_part_is_not_written_over_a_directory() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir(folder.path().join("0.ts")).unwrap();
+
+        let result = download_part(
+            playlist_part("0.ts"),
+            "http://127.0.0.1:9/vod/".to_string(),
+            folder.path(),
+            false,
+            part_download_context(),
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(DownloadFileError::TargetIsADirectory(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_part_timeout_does_not_count_the_bandwidth_limit() {
+        let throttled = AtomicU64::new(0);
+        let download = async {
+            throttled.fetch_add(
+                Duration::from_millis(300).as_nanos() as u64,
+                Ordering::Relaxed,
+            );
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(())
+        };
+        let result = with_part_timeout(download, "0.ts", Duration::from_millis(100), &throttled);
+        assert!(result.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_part_that_is_not_throttled_times_out() {
+        let throttled = AtomicU64::new(0);
+        let download = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(())
+        };
+        let result = with_part_timeout(download, "0.ts", Duration::from_millis(100), &throttled);
+        assert!(matches!(
+            result.await,
+            Err(DownloadFileError::Timeout { .. })
+        ));
     }
-    Ok(target_path.to_path_buf())
 }
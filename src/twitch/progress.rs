@@ -0,0 +1,293 @@
+use crate::prelude::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// What the download of a video is currently doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadPhase {
+    FetchingPlaylist,
+    DownloadingParts,
+    /// Appending the parts to a single file and converting it.
+    Combining,
+    /// Converting the parts that an earlier run already combined.
+    Converting,
+}
+
+/// A snapshot of the download progress of a video.
+///
+/// The counts are only filled in during [`DownloadPhase::DownloadingParts`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// The twitch id of the video.
+    pub video_id: Arc<str>,
+    pub phase: DownloadPhase,
+    pub parts_done: u64,
+    pub parts_total: u64,
+    /// Including the parts that were already downloaded by an earlier run.
+    pub bytes_downloaded: u64,
+    /// The estimated size of the whole video, if the part sizes could be sampled.
+    pub bytes_estimated: Option<u64>,
+}
+
+impl DownloadProgress {
+    /// Sends the start of a phase without any counts.
+    ///
+    /// Unlike the snapshots during the download, this waits for the receiver
+    /// instead of being dropped, since the phases are rare and consumers
+    /// rely on seeing every one of them.
+    pub async fn report_phase(
+        sender: Option<&mpsc::Sender<DownloadProgress>>,
+        video_id: &str,
+        phase: DownloadPhase,
+    ) {
+        let Some(sender) = sender else {
+            return;
+        };
+        let _ = sender
+            .send(DownloadProgress {
+                video_id: video_id.into(),
+                phase,
+                parts_done: 0,
+                parts_total: 0,
+                bytes_downloaded: 0,
+                bytes_estimated: None,
+            })
+            .await;
+    }
+}
+
+/// The number of reports the throughput for the ETA is averaged over.
+const THROUGHPUT_WINDOW: usize = 6;
+
+/// Keeps track of how many parts of a video have been downloaded.
+///
+/// This is shared between all part download futures, so everything in here
+/// has to work without locking.
+#[derive(Debug)]
+pub struct PartProgress {
+    video_id: Arc<str>,
+    total_parts: u64,
+    done_parts: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    /// The size of the parts that were already present when the download started.
+    present_bytes: u64,
+    /// The estimated size of the whole video, if the part sizes could be sampled.
+    estimated_total_bytes: Option<u64>,
+    start: Instant,
+}
+
+impl PartProgress {
+    pub fn new(
+        video_id: &str,
+        total_parts: u64,
+        already_done_parts: u64,
+        already_downloaded_bytes: u64,
+        estimated_total_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            video_id: video_id.into(),
+            total_parts,
+            done_parts: AtomicU64::new(already_done_parts),
+            downloaded_bytes: AtomicU64::new(0),
+            present_bytes: already_downloaded_bytes,
+            estimated_total_bytes,
+            start: Instant::now(),
+        }
+    }
+
+    /// Marks a single part as done and adds its size to the downloaded bytes.
+    pub fn part_done(&self, bytes: u64) {
+        self.done_parts.fetch_add(1, Ordering::Relaxed);
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn done_parts(&self) -> u64 {
+        self.done_parts.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes per second since the download started.
+    pub fn bytes_per_second(&self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        self.downloaded_bytes() as f64 / elapsed
+    }
+
+    /// The estimated time until all parts are downloaded.
+    ///
+    /// Uses the estimated size of the video if it is known and the remaining
+    /// part count otherwise.
+    fn eta(&self, throughput: Throughput) -> Option<Duration> {
+        let remaining_parts = self.total_parts.saturating_sub(self.done_parts());
+        if remaining_parts == 0 {
+            return Some(Duration::ZERO);
+        }
+        if let Some(total) = self.estimated_total_bytes {
+            let remaining = total.saturating_sub(self.present_bytes + self.downloaded_bytes());
+            if remaining > 0 && throughput.bytes_per_second > 0.0 {
+                return Some(Duration::from_secs_f64(
+                    remaining as f64 / throughput.bytes_per_second,
+                ));
+            }
+        }
+        if throughput.parts_per_second > 0.0 {
+            return Some(Duration::from_secs_f64(
+                remaining_parts as f64 / throughput.parts_per_second,
+            ));
+        }
+        None
+    }
+
+    fn log(&self, throughput: Throughput) {
+        let done = self.done_parts();
+        let percent = if self.total_parts == 0 {
+            100
+        } else {
+            done * 100 / self.total_parts
+        };
+        let size = match self.estimated_total_bytes {
+            Some(total) => format!(
+                "{} / ~{}",
+                format_bytes(self.present_bytes + self.downloaded_bytes()),
+                format_bytes(total)
+            ),
+            None => format_bytes(self.present_bytes + self.downloaded_bytes()),
+        };
+        let eta = self
+            .eta(throughput)
+            .map(|eta| format_duration(eta.as_secs()))
+            .unwrap_or_else(|| "unknown".to_string());
+        info!(
+            "downloaded {}/{} parts ({}%), {}, {}/s, ETA {}",
+            done,
+            self.total_parts,
+            percent,
+            size,
+            format_bytes(throughput.bytes_per_second as u64),
+            eta
+        );
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            "finished downloading {} parts: {} in {:?} (average {}/s)",
+            self.done_parts(),
+            format_bytes(self.downloaded_bytes()),
+            self.start.elapsed(),
+            format_bytes(self.bytes_per_second() as u64)
+        );
+    }
+
+    pub fn snapshot(&self) -> DownloadProgress {
+        DownloadProgress {
+            video_id: self.video_id.clone(),
+            phase: DownloadPhase::DownloadingParts,
+            parts_done: self.done_parts(),
+            parts_total: self.total_parts,
+            bytes_downloaded: self.present_bytes + self.downloaded_bytes(),
+            bytes_estimated: self.estimated_total_bytes,
+        }
+    }
+
+    /// Spawns a task that reports the progress every `interval` until it is aborted.
+    ///
+    /// The progress is logged if `log` is set and sent to the sender if one is
+    /// given. Snapshots are dropped if the receiver can't keep up, so the
+    /// download is never slowed down.
+    pub fn spawn_reporter(
+        self: Arc<Self>,
+        interval: Duration,
+        log: bool,
+        sender: Option<mpsc::Sender<DownloadProgress>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut window = ThroughputWindow::default();
+            window.add(&self);
+            let mut interval = tokio::time::interval(interval);
+            // the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let throughput = window.add(&self);
+                if log {
+                    self.log(throughput);
+                }
+                if let Some(sender) = &sender {
+                    let _ = sender.try_send(self.snapshot());
+                }
+            }
+        })
+    }
+}
+
+/// The download speed over the last few reports.
+#[derive(Debug, Clone, Copy, Default)]
+struct Throughput {
+    bytes_per_second: f64,
+    parts_per_second: f64,
+}
+
+/// The last few progress samples, to calculate a rolling average of the throughput.
+#[derive(Debug, Default)]
+struct ThroughputWindow {
+    /// (time, downloaded bytes, done parts)
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl ThroughputWindow {
+    /// Adds the current progress and returns the throughput since the oldest sample.
+    fn add(&mut self, progress: &PartProgress) -> Throughput {
+        let now = Instant::now();
+        let bytes = progress.downloaded_bytes();
+        let parts = progress.done_parts();
+        self.samples.push_back((now, bytes, parts));
+        if self.samples.len() > THROUGHPUT_WINDOW + 1 {
+            self.samples.pop_front();
+        }
+        let (first_time, first_bytes, first_parts) = self.samples[0];
+        let elapsed = now.duration_since(first_time).as_secs_f64();
+        if elapsed <= 0.0 {
+            return Throughput::default();
+        }
+        Throughput {
+            bytes_per_second: (bytes - first_bytes) as f64 / elapsed,
+            parts_per_second: (parts - first_parts) as f64 / elapsed,
+        }
+    }
+}
+
+/// Formats a byte count in a human readable way (e.g. `1.2 GB`).
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration in seconds as `hh:mm:ss`.
+pub fn format_duration(seconds: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
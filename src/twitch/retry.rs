@@ -0,0 +1,140 @@
+use crate::config::TwitchDownloaderConfig;
+use crate::prelude::*;
+use std::time::Duration;
+use twba_reqwest_backoff::ReqwestClient;
+
+/// How often and how fast requests to twitch are retried.
+///
+/// Retries requests that failed or got a server error. The requests are sent
+/// without the backoff of [`ReqwestClient::execute_with_backoff`], so they are
+/// not retried twice. A `429 Too Many Requests` is returned as it is, the
+/// callers decide how to wait for the rate limit.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &TwitchDownloaderConfig) -> Self {
+        Self {
+            max_attempts: config.retry_max_attempts.max(1),
+            initial_delay: Duration::from_millis(config.retry_initial_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+        }
+    }
+
+    /// Executes the request, retrying it with an exponential delay.
+    ///
+    /// Requests with a body that can't be cloned are only tried once.
+    pub async fn execute(
+        &self,
+        client: &ReqwestClient,
+        request: reqwest::Request,
+    ) -> StdResult<reqwest::Response, reqwest::Error> {
+        let mut request = request;
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            let next_request = if attempt < self.max_attempts {
+                request.try_clone()
+            } else {
+                None
+            };
+            let Some(next_request) = next_request else {
+                return reqwest::Client::execute(client, request).await;
+            };
+            match reqwest::Client::execute(client, request).await {
+                Ok(response) if !response.status().is_server_error() => return Ok(response),
+                Ok(response) => debug!(
+                    "request to {} failed with status {} (attempt {}/{})",
+                    response.url(),
+                    response.status(),
+                    attempt,
+                    self.max_attempts
+                ),
+                Err(err) => debug!(
+                    "request failed (attempt {}/{}): {:?}",
+                    attempt, self.max_attempts, err
+                ),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(self.max_delay);
+            attempt += 1;
+            request = next_request;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_once_per_attempt() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client: ReqwestClient = reqwest::Client::new().into();
+        let request = client.get(server.uri()).build().unwrap();
+
+        let response = policy(3).execute(&client, request).await.unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_response_after_the_last_attempt() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let client: ReqwestClient = reqwest::Client::new().into();
+        let request = client.get(server.uri()).build().unwrap();
+
+        let response = policy(2).execute(&client, request).await.unwrap();
+
+        assert_eq!(
+            response.status(),
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_a_rate_limited_request() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client: ReqwestClient = reqwest::Client::new().into();
+        let request = client.get(server.uri()).build().unwrap();
+
+        let response = policy(3).execute(&client, request).await.unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    }
+}
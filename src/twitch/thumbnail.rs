@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwitchVideoThumbnailResponse {
+    pub data: VideoThumbnailResponseData,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VideoThumbnailResponseData {
+    pub video: Option<VideoThumbnailResponseDataVideo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VideoThumbnailResponseDataVideo {
+    #[serde(rename = "previewThumbnailURL")]
+    pub preview_thumbnail_url: Option<String>,
+}
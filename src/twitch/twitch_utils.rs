@@ -1,8 +1,8 @@
-use crate::errors::{MalformedPlaylistError, PlaylistParseError};
+use crate::errors::{MalformedPlaylistError, PlaylistParseError, VideoIdError};
 use crate::prelude::StdResult;
 use crate::prelude::*;
 use chrono::{NaiveDateTime, Utc};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// Converts a twitch date string to a chrono::DateTime<Utc>
 ///
@@ -17,15 +17,183 @@ pub fn convert_twitch_date(date: &str) -> StdResult<chrono::DateTime<Utc>, Playl
         .map_err(PlaylistParseError::InvalidTimeFormat)
 }
 
-pub fn parse_playlist(
-    playlist: String,
-) -> StdResult<(Option<usize>, HashMap<String, f32>), MalformedPlaylistError> {
+/// A single segment of a media playlist.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PlaylistPart {
+    /// The uri of the segment, relative to the playlist.
+    pub uri: String,
+    /// The duration of the segment in seconds.
+    pub duration: f32,
+    /// Only set if the segment is just a part of the file at the uri.
+    pub byte_range: Option<ByteRange>,
+}
+
+/// A range of bytes inside a file, as specified by `#EXT-X-BYTERANGE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: u64,
+}
+
+impl ByteRange {
+    /// The value for the http `Range` header.
+    pub fn header_value(&self) -> String {
+        format!("bytes={}-{}", self.offset, self.offset + self.length - 1)
+    }
+}
+
+impl PlaylistPart {
+    /// The name of the file the part is saved as.
+    ///
+    /// Segments that only contain a range of a file get the offset appended,
+    /// since multiple of them share the same uri (e.g. `1234_5000.ts`).
+    /// A query string of the uri is not part of the name.
+    pub fn file_name(&self) -> String {
+        let uri = self.uri.split('?').next().unwrap_or(&self.uri);
+        match self.byte_range {
+            None => uri.to_string(),
+            Some(range) => match uri.rsplit_once('.') {
+                Some((stem, extension)) => format!("{}_{}.{}", stem, range.offset, extension),
+                None => format!("{}_{}", uri, range.offset),
+            },
+        }
+    }
+
+    /// The full url of the part.
+    pub fn url(&self, base_url: &str) -> String {
+        part_url(base_url, &self.uri)
+    }
+
+    /// The full url of the unmuted version of a muted part.
+    pub fn unmuted_url(&self, base_url: &str) -> String {
+        part_url(base_url, &self.uri.replace("-muted", ""))
+    }
+}
+
+/// Gets the id of a video from the id itself or from a url of it, like
+/// `https://www.twitch.tv/videos/123456789?t=1h2m`.
+///
+/// Clips (`clips.twitch.tv/<slug>`, `twitch.tv/<user>/clip/<slug>` or just
+/// the slug) are rejected with [`VideoIdError::Clip`], which contains the slug.
+pub fn parse_video_id(input: &str) -> StdResult<String, VideoIdError> {
+    let input = input.trim();
+    let invalid = || VideoIdError::Invalid(input.to_string());
+    let clip = |slug: &str| VideoIdError::Clip {
+        input: input.to_string(),
+        slug: slug.to_string(),
+    };
+    let is_id = |id: &str| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit());
+    if is_id(input) {
+        return Ok(input.to_string());
+    }
+    if !input.contains(['/', '.']) && !input.is_empty() {
+        return Err(clip(input));
+    }
+    let url = input
+        .strip_prefix("https://")
+        .or_else(|| input.strip_prefix("http://"))
+        .unwrap_or(input);
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    let mut segments = url.split('/').filter(|segment| !segment.is_empty());
+    let host = segments.next().unwrap_or_default().to_ascii_lowercase();
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("m."))
+        .unwrap_or(&host);
+    let path: Vec<&str> = segments.collect();
+    match (host, path.as_slice()) {
+        ("twitch.tv", ["videos", id]) | ("twitch.tv", [_, "video", id]) if is_id(*id) => {
+            Ok(id.to_string())
+        }
+        ("twitch.tv", [_, "clip", slug]) | ("clips.twitch.tv", [slug]) => Err(clip(*slug)),
+        _ => Err(invalid()),
+    }
+}
+
+/// The url the parts of a media playlist are relative to.
+///
+/// The query of the playlist url is kept, since the CDN can require its
+/// signature for the parts as well.
+pub fn playlist_base_url(playlist_url: &str) -> StdResult<String, MalformedPlaylistError> {
+    let mut url =
+        reqwest::Url::parse(playlist_url).map_err(|_| MalformedPlaylistError::InvalidUrl)?;
+    url.path_segments_mut()
+        .map_err(|_| MalformedPlaylistError::InvalidUrl)?
+        .pop()
+        .push("");
+    Ok(url.to_string())
+}
+
+/// Resolves the uri of a part against the base url of its playlist.
+///
+/// The query of the base url is carried over if the uri has none.
+pub fn part_url(base_url: &str, uri: &str) -> String {
+    let Ok(base) = reqwest::Url::parse(base_url) else {
+        return format!("{}{}", base_url, uri);
+    };
+    match base.join(uri) {
+        Ok(mut url) => {
+            if url.query().is_none() {
+                url.set_query(base.query());
+            }
+            url.to_string()
+        }
+        Err(_) => format!("{}{}", base_url, uri),
+    }
+}
+
+/// Parses the value of a `#EXT-X-BYTERANGE:<length>[@<offset>]` tag.
+///
+/// If no offset is given, the range starts right after the previous range of the same uri.
+fn parse_byte_range(
+    value: &str,
+    previous_end: Option<u64>,
+) -> StdResult<ByteRange, PlaylistParseError> {
+    let value = value.trim();
+    let (length, offset) = match value.split_once('@') {
+        Some((length, offset)) => (
+            length,
+            Some(
+                offset
+                    .parse()
+                    .map_err(|_| PlaylistParseError::InvalidByteRange(value.to_string()))?,
+            ),
+        ),
+        None => (value, None),
+    };
+    let length: u64 = length
+        .parse()
+        .map_err(|_| PlaylistParseError::InvalidByteRange(value.to_string()))?;
+    if length == 0 {
+        return Err(PlaylistParseError::InvalidByteRange(value.to_string()));
+    }
+    Ok(ByteRange {
+        length,
+        offset: offset.or(previous_end).unwrap_or(0),
+    })
+}
+
+/// The information parsed from a media playlist.
+#[derive(Debug, Clone)]
+pub struct MediaPlaylist {
+    /// When the video was streamed.
+    pub streamed_at: Option<chrono::DateTime<Utc>>,
+    /// The age of the video in hours.
+    pub age: Option<usize>,
+    /// The parts of the video in the order of the playlist.
+    pub parts: Vec<PlaylistPart>,
+}
+
+pub fn parse_playlist(playlist: String) -> StdResult<MediaPlaylist, MalformedPlaylistError> {
     info!("Parsing playlist");
     const STREAMED_DATE_IDENT: &str = "#ID3-EQUIV-TDTG:";
 
+    let mut streamed_at = None;
     let mut age = None;
-    let mut parts = HashMap::new();
-    dbg!(&playlist);
+    let mut parts = vec![];
+    let mut file_names = HashSet::new();
+    let mut range_ends: HashMap<String, u64> = HashMap::new();
+    let mut ended = false;
     let mut lines = playlist.lines();
     loop {
         let line = lines.next();
@@ -35,71 +203,880 @@ pub fn parse_playlist(
             break;
         }
         let line = line.unwrap();
+        if line.trim() == "#EXT-X-ENDLIST" {
+            ended = true;
+            continue;
+        }
         if let Some(date) = line.strip_prefix(STREAMED_DATE_IDENT) {
             let date = date.trim();
             let date: chrono::DateTime<Utc> = convert_twitch_date(date)?;
             let now = Utc::now();
             let duration = now.signed_duration_since(date);
+            streamed_at = Some(date);
             age = Some(duration.num_hours() as usize);
             continue;
         }
         if let Some(part_duration) = line.strip_prefix("#EXTINF:") {
-            let mut line = lines.next().ok_or(PlaylistParseError::Eof)?;
-            if line.starts_with("#EXT-X-BYTERANGE:") {
-                warn!("Found byterange, ignoring the line and moving on");
-                line = lines.next().ok_or(PlaylistParseError::Eof)?;
-            }
+            // there might be other tags (e.g. #EXT-X-PROGRAM-DATE-TIME) before the uri
+            let mut byte_range = None;
+            let line = loop {
+                let line = lines.next().ok_or(PlaylistParseError::Eof)?.trim();
+                if let Some(range) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+                    byte_range = Some(range);
+                } else if line.starts_with('#') || line.is_empty() {
+                    trace!("skipping tag between #EXTINF and uri: {}", line);
+                } else {
+                    break line;
+                }
+            };
+            let uri = line.to_string();
+            let byte_range = match byte_range {
+                Some(range) => {
+                    let range = parse_byte_range(range, range_ends.get(&uri).copied())?;
+                    range_ends.insert(uri.clone(), range.offset + range.length);
+                    Some(range)
+                }
+                None => None,
+            };
 
             let part_duration: f32 = part_duration.trim_matches(',').parse().unwrap_or(0.0);
 
-            parts.insert(line.trim().to_string(), part_duration);
+            let part = PlaylistPart {
+                uri,
+                duration: part_duration,
+                byte_range,
+            };
+            // a segment that is listed twice is only downloaded once
+            if file_names.insert(part.file_name()) {
+                parts.push(part);
+            } else {
+                debug!("skipping duplicate segment: {}", part.uri);
+            }
         } else {
             //ignore everything but content lines
             continue;
         }
     }
-    dbg!(&parts.len());
-    Ok((age, parts))
+    debug!(
+        bytes = playlist.len(),
+        segments = parts.len(),
+        duration = parts.iter().map(|part| part.duration).sum::<f32>(),
+        age_hours = age,
+        ended,
+        first = parts.first().map(|part| part.uri.as_str()),
+        last = parts.last().map(|part| part.uri.as_str()),
+        "parsed the playlist"
+    );
+    if !ended {
+        // twitch is still appending segments, so the download would be incomplete
+        info!(
+            "the playlist has no end marker yet, {} segments are available so far",
+            parts.len()
+        );
+        return Err(MalformedPlaylistError::StillProcessing {
+            segments: parts.len(),
+        });
+    }
+    Ok(MediaPlaylist {
+        streamed_at,
+        age,
+        parts,
+    })
+}
+
+/// Gets the error out of a json response of usher, which it sends instead of
+/// the master playlist for sub-only or region-blocked videos, like
+/// `[{"error":"Manifest is restricted","error_code":"vod_manifest_restricted",...}]`.
+pub fn parse_usher_error(content: &str) -> MalformedPlaylistError {
+    #[derive(serde::Deserialize)]
+    struct UsherError {
+        error_code: Option<String>,
+        error: Option<String>,
+    }
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum UsherErrors {
+        List(Vec<UsherError>),
+        Single(UsherError),
+    }
+    let error = match serde_json::from_str::<UsherErrors>(content) {
+        Ok(UsherErrors::List(errors)) => errors.into_iter().next(),
+        Ok(UsherErrors::Single(error)) => Some(error),
+        Err(_) => None,
+    };
+    match error {
+        Some(error) => MalformedPlaylistError::Restricted {
+            code: error.error_code.unwrap_or_else(|| "unknown".to_string()),
+            message: error.error.unwrap_or_default(),
+        },
+        None => MalformedPlaylistError::Restricted {
+            code: "unknown".to_string(),
+            message: content.chars().take(200).collect(),
+        },
+    }
 }
 
-#[tracing::instrument(skip(playlist))]
-pub fn get_playlist_from_quality_list(playlist: String, quality: &str) -> Result<String> {
-    trace!("Parsing playlist:\n{}", playlist);
+/// A single quality (rendition) of a video from the master playlist.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct QualityInfo {
+    /// The name of the quality (e.g. `1080p60` or `chunked`).
+    pub name: String,
+    /// The resolution as width and height.
+    pub resolution: Option<(u32, u32)>,
+    pub frame_rate: Option<f32>,
+    /// The bandwidth in bits per second.
+    pub bandwidth: Option<u64>,
+    /// The url of the media playlist of this quality.
+    pub url: String,
+}
 
-    let mut qualties = HashMap::new();
+impl QualityInfo {
+    /// The height of the video, from the resolution or otherwise from the
+    /// name (e.g. `1080` for `1080p60 (source)`).
+    pub fn height(&self) -> Option<u32> {
+        self.resolution
+            .map(|(_, height)| height)
+            .or_else(|| parse_quality_name(&self.name).map(|(height, _)| height))
+    }
 
-    let mut highest_quality = String::new();
-    let test: Vec<&str> = playlist.lines().collect();
-    for (i, line) in test.iter().enumerate() {
-        if !line.contains("#EXT-X-MEDIA") {
-            continue;
+    /// The frame rate of the video, from the attributes or otherwise from the name.
+    pub fn fps(&self) -> Option<f32> {
+        self.frame_rate
+            .or_else(|| parse_quality_name(&self.name).and_then(|(_, fps)| fps))
+    }
+}
+
+/// Parses the height and frame rate out of a quality name like `1080p60`,
+/// `720p` or `1080p60 (source)`.
+fn parse_quality_name(name: &str) -> Option<(u32, Option<f32>)> {
+    let name = name.split_whitespace().next()?;
+    let (height, fps) = name.split_once('p')?;
+    let height = height.parse().ok()?;
+    let fps = if fps.is_empty() {
+        None
+    } else {
+        Some(fps.parse().ok()?)
+    };
+    Some((height, fps))
+}
+
+/// A requested quality like `1080p60`, `720p` or `<=720p`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct QualityRequest {
+    height: u32,
+    fps: Option<f32>,
+    /// Only qualities with at most the requested height match (`<=720p`).
+    at_most: bool,
+}
+
+impl QualityRequest {
+    fn parse(quality: &str) -> Option<Self> {
+        let quality = quality.trim();
+        let (quality, at_most) = match quality.strip_prefix("<=") {
+            Some(quality) => (quality.trim(), true),
+            None => (quality, false),
+        };
+        let (height, fps) = parse_quality_name(quality)?;
+        Some(Self {
+            height,
+            fps,
+            at_most,
+        })
+    }
+
+    /// Finds the closest variant, preferring an equal or lower resolution.
+    ///
+    /// Within a resolution, the frame rate closest to the requested one (at
+    /// most it, if possible) is used, or the highest if none was requested.
+    fn closest<'a>(&self, variants: &'a [QualityInfo]) -> Option<&'a QualityInfo> {
+        let with_height = variants
+            .iter()
+            .filter_map(|variant| Some((variant.height()?, variant)));
+        let lower = with_height
+            .clone()
+            .filter(|(height, _)| *height <= self.height)
+            .map(|(height, _)| height)
+            .max();
+        let height = match lower {
+            Some(height) => height,
+            None if self.at_most => return None,
+            None => with_height.clone().map(|(height, _)| height).min()?,
+        };
+        let fps_score = |variant: &QualityInfo| -> (bool, i64) {
+            let fps = variant.fps().unwrap_or(30.0);
+            match self.fps {
+                Some(requested) => (
+                    fps <= requested + 0.5,
+                    -((fps - requested).abs() * 100.0) as i64,
+                ),
+                None => (true, (fps * 100.0) as i64),
+            }
+        };
+        with_height
+            .filter(|(variant_height, _)| *variant_height == height)
+            .map(|(_, variant)| variant)
+            .max_by_key(|variant| (fps_score(variant), variant.bandwidth.unwrap_or(0)))
+    }
+}
+
+/// Parses an attribute list like `NAME="1080p60",AUTOSELECT=YES` into its key value pairs.
+///
+/// Quoted values may contain commas, the quotes are removed.
+pub fn parse_attributes(attributes: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut rest = attributes.trim();
+    while !rest.is_empty() {
+        let Some((key, value_and_rest)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, next) = if let Some(quoted) = value_and_rest.strip_prefix('"') {
+            match quoted.split_once('"') {
+                Some((value, next)) => (value, next),
+                None => (quoted, ""),
+            }
+        } else {
+            match value_and_rest.split_once(',') {
+                Some((value, next)) => (value, next),
+                None => (value_and_rest, ""),
+            }
+        };
+        result.insert(key.trim().to_string(), value.to_string());
+        rest = next.trim_start_matches(',').trim_start();
+    }
+    result
+}
+
+/// Parses the master playlist into all available qualities.
+///
+/// Every `#EXT-X-STREAM-INF` is paired with the uri on the following line and
+/// gets its name from the `#EXT-X-MEDIA` with the matching group id.
+///
+/// Fails with [`MalformedPlaylistError::Restricted`] if usher sent an error
+/// instead and with [`MalformedPlaylistError::NotAPlaylist`] for anything else
+/// that does not start with `#EXTM3U`.
+pub fn parse_master_playlist(
+    playlist: &str,
+) -> StdResult<Vec<QualityInfo>, MalformedPlaylistError> {
+    trace!("Parsing master playlist:\n{}", playlist);
+    let content = playlist.trim_start();
+    if content.starts_with('[') || content.starts_with('{') {
+        return Err(parse_usher_error(content));
+    }
+    if !content.starts_with("#EXTM3U") {
+        return Err(MalformedPlaylistError::NotAPlaylist {
+            start: content.chars().take(200).collect(),
+        });
+    }
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut variants = vec![];
+    let mut lines = playlist.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if let Some(attributes) = line.strip_prefix("#EXT-X-MEDIA:") {
+            let attributes = parse_attributes(attributes);
+            if let (Some(group), Some(name)) = (attributes.get("GROUP-ID"), attributes.get("NAME"))
+            {
+                names.entry(group.clone()).or_insert_with(|| name.clone());
+            }
+        } else if let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            let attributes = parse_attributes(attributes);
+            let url = lines
+                .by_ref()
+                .find(|line| !line.is_empty() && !line.starts_with('#'))
+                .ok_or(MalformedPlaylistError::MissingUri)?;
+            let group = attributes.get("VIDEO");
+            let name = group
+                .and_then(|group| names.get(group))
+                .or(group)
+                .cloned()
+                .or_else(|| attributes.get("RESOLUTION").cloned())
+                .unwrap_or_else(|| url.to_string());
+            variants.push(QualityInfo {
+                name,
+                resolution: attributes.get("RESOLUTION").and_then(|resolution| {
+                    let (width, height) = resolution.split_once('x')?;
+                    Some((width.parse().ok()?, height.parse().ok()?))
+                }),
+                frame_rate: attributes.get("FRAME-RATE").and_then(|x| x.parse().ok()),
+                bandwidth: attributes.get("BANDWIDTH").and_then(|x| x.parse().ok()),
+                url: url.to_string(),
+            });
         }
+    }
+    if variants.is_empty() {
+        return Err(MalformedPlaylistError::NoQualities);
+    }
+    Ok(variants)
+}
 
-        let found_quality = line.split("NAME=\"").collect::<Vec<&str>>()[1]
-            .split('"')
-            .collect::<Vec<&str>>()[0];
+/// Selects the first available quality of the preferences.
+///
+/// `max` stands for the highest available quality. A preference matches a
+/// quality with exactly its name, or otherwise the closest quality by
+/// resolution and frame rate (e.g. `720p` matches `720p60`, `1080p60` matches
+/// `936p60` if there is no 1080p and `<=720p` never matches anything higher).
+/// Falls back to the highest quality if none of the preferences match.
+pub fn select_quality<'a>(
+    variants: &'a [QualityInfo],
+    preferences: &[String],
+) -> StdResult<&'a QualityInfo, MalformedPlaylistError> {
+    // twitch lists the highest quality first, the bandwidth is only used if it is known
+    let highest = variants
+        .iter()
+        .enumerate()
+        .max_by_key(|(i, variant)| (variant.bandwidth.unwrap_or(0), std::cmp::Reverse(*i)))
+        .map(|(_, variant)| variant)
+        .ok_or(MalformedPlaylistError::NoQualities)?;
+    for quality in preferences {
+        if quality == "max" {
+            return Ok(highest);
+        }
+        if let Some(variant) = variants.iter().find(|variant| &variant.name == quality) {
+            return Ok(variant);
+        }
+        let closest = QualityRequest::parse(quality).and_then(|request| request.closest(variants));
+        if let Some(variant) = closest {
+            debug!(
+                "using quality {} for the requested {}",
+                variant.name, quality
+            );
+            return Ok(variant);
+        }
+    }
+    if !preferences.is_empty() {
+        warn!(
+            "None of the given qualities were found ({:?}), using highest quality: {}",
+            preferences, highest.name
+        );
+    }
+    Ok(highest)
+}
 
-        if qualties.get(found_quality).is_some() {
-            continue;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_id_of_a_video_from_every_url_shape() {
+        let inputs = [
+            "123456789",
+            " 123456789\n",
+            "https://www.twitch.tv/videos/123456789",
+            "https://twitch.tv/videos/123456789",
+            "http://twitch.tv/videos/123456789",
+            "www.twitch.tv/videos/123456789",
+            "twitch.tv/videos/123456789/",
+            "https://m.twitch.tv/videos/123456789",
+            "https://WWW.Twitch.tv/videos/123456789",
+            "https://www.twitch.tv/videos/123456789?t=1h2m3s",
+            "https://www.twitch.tv/videos/123456789?filter=archives&sort=time",
+            "https://www.twitch.tv/videos/123456789#chat",
+            "https://www.twitch.tv/somestreamer/video/123456789",
+            "https://m.twitch.tv/somestreamer/video/123456789?t=10s",
+        ];
+        for input in inputs {
+            assert_eq!(
+                parse_video_id(input).ok().as_deref(),
+                Some("123456789"),
+                "{:?}",
+                input
+            );
         }
-        if qualties.is_empty() {
-            // the first one is the highest quality
-            highest_quality = found_quality.to_string();
+    }
+
+    #[test]
+    fn a_clip_is_rejected_with_its_slug() {
+        let inputs = [
+            "AwkwardHelplessSalamanderSwiftRage",
+            "https://clips.twitch.tv/AwkwardHelplessSalamanderSwiftRage",
+            "https://clips.twitch.tv/AwkwardHelplessSalamanderSwiftRage?tt_medium=redt",
+            "https://www.twitch.tv/somestreamer/clip/AwkwardHelplessSalamanderSwiftRage",
+            "https://m.twitch.tv/somestreamer/clip/AwkwardHelplessSalamanderSwiftRage#top",
+        ];
+        for input in inputs {
+            match parse_video_id(input) {
+                Err(VideoIdError::Clip { slug, .. }) => {
+                    assert_eq!(slug, "AwkwardHelplessSalamanderSwiftRage", "{:?}", input)
+                }
+                result => panic!("{:?} was parsed as {:?}", input, result),
+            }
         }
+    }
 
-        let url = test[i + 2];
-        qualties.insert(found_quality, url);
+    #[test]
+    fn anything_else_is_not_a_video_id() {
+        let inputs = [
+            "",
+            "https://www.twitch.tv/somestreamer",
+            "https://www.twitch.tv/videos/",
+            "https://www.twitch.tv/videos/abc",
+            "https://www.youtube.com/videos/123456789",
+            "https://www.twitch.tv/somestreamer/videos/123456789",
+        ];
+        for input in inputs {
+            assert!(
+                matches!(parse_video_id(input), Err(VideoIdError::Invalid(_))),
+                "{:?} was accepted",
+                input
+            );
+        }
     }
-    if let Some(quality) = qualties.get(quality) {
-        Ok(quality.to_string())
-    } else {
-        warn!(
-            "Given quality not found ({}), using highest quality: {}",
-            quality, highest_quality
+
+    /// A media playlist that lists ranges of a few larger files instead of one file per part.
+    const BYTERANGE_PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:4
+#EXT-X-TARGETDURATION:10
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-MEDIA-SEQUENCE:0
+#EXTINF:10.000,
+#EXT-X-BYTERANGE:1000@0
+0.ts
+#EXTINF:10.000,
+#EXT-X-BYTERANGE:1500
+0.ts
+#EXTINF:10.000,
+#EXT-X-BYTERANGE:700
+0.ts
+#EXTINF:4.500,
+#EXT-X-BYTERANGE:800@0
+1.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn parses_the_byte_ranges_of_the_segments() {
+        let playlist = parse_playlist(BYTERANGE_PLAYLIST.to_string()).unwrap();
+        let ranges: Vec<(&str, Option<ByteRange>)> = playlist
+            .parts
+            .iter()
+            .map(|part| (part.uri.as_str(), part.byte_range))
+            .collect();
+        let range = |length, offset| Some(ByteRange { length, offset });
+        assert_eq!(
+            ranges,
+            [
+                ("0.ts", range(1000, 0)),
+                ("0.ts", range(1500, 1000)),
+                ("0.ts", range(700, 2500)),
+                ("1.ts", range(800, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_of_the_same_file_are_saved_separately() {
+        let playlist = parse_playlist(BYTERANGE_PLAYLIST.to_string()).unwrap();
+        let names: Vec<String> = playlist.parts.iter().map(PlaylistPart::file_name).collect();
+        assert_eq!(names, ["0_0.ts", "0_1000.ts", "0_2500.ts", "1_0.ts"]);
+        let headers: Vec<String> = playlist
+            .parts
+            .iter()
+            .filter_map(|part| part.byte_range)
+            .map(|range| range.header_value())
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                "bytes=0-999",
+                "bytes=1000-2499",
+                "bytes=2500-3199",
+                "bytes=0-799"
+            ]
+        );
+    }
+
+    #[test]
+    fn an_invalid_byte_range_is_an_error() {
+        for range in ["abc", "0@0", "100@x", ""] {
+            let playlist = format!(
+                "#EXTM3U\n#EXTINF:10.000,\n#EXT-X-BYTERANGE:{}\n0.ts\n#EXT-X-ENDLIST\n",
+                range
+            );
+            let result = parse_playlist(playlist);
+            assert!(
+                matches!(
+                    result,
+                    Err(MalformedPlaylistError::Parse(
+                        PlaylistParseError::InvalidByteRange(_)
+                    ))
+                ),
+                "{:?} was accepted",
+                range
+            );
+        }
+    }
+
+    /// The start of a media playlist with twitch specific tags, some of them
+    /// between the `#EXTINF` and the uri of a segment.
+    const TAGGED_PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:10
+#ID3-EQUIV-TDTG:2023-10-07T23:33:29
+#EXT-X-PLAYLIST-TYPE:EVENT
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-TWITCH-ELAPSED-SECS:0.000
+#EXT-X-TWITCH-TOTAL-SECS:30.000
+#EXT-X-PROGRAM-DATE-TIME:2023-10-07T21:26:49.123Z
+#EXTINF:10.000,
+#EXT-X-PROGRAM-DATE-TIME:2023-10-07T21:26:49.123Z
+0.ts
+#EXTINF:10.000,
+#EXT-X-DATERANGE:ID=\"source-1696714019\",CLASS=\"twitch-stream-source\",START-DATE=\"2023-10-07T21:26:59.123Z\",END-ON-NEXT=YES,X-TV-TWITCH-STREAM-SOURCE=\"live\"
+#EXT-X-TWITCH-DISCONTINUITY
+
+1-muted.ts
+#EXTINF:10.000,
+2.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn skips_the_tags_between_a_segment_and_its_uri() {
+        let playlist = parse_playlist(TAGGED_PLAYLIST.to_string()).unwrap();
+        let uris: Vec<&str> = playlist
+            .parts
+            .iter()
+            .map(|part| part.uri.as_str())
+            .collect();
+        assert_eq!(uris, ["0.ts", "1-muted.ts", "2.ts"]);
+        assert!(playlist.parts.iter().all(|part| part.duration == 10.0));
+        assert_eq!(
+            playlist.streamed_at,
+            Some(convert_twitch_date("2023-10-07T23:33:29").unwrap())
+        );
+    }
+
+    #[test]
+    fn a_segment_without_an_uri_is_an_error() {
+        let playlist = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXTINF:10.000,
+0.ts
+#EXTINF:10.000,
+#EXT-X-PROGRAM-DATE-TIME:2023-10-07T21:26:59.123Z
+";
+        let result = parse_playlist(playlist.to_string());
+        assert!(matches!(
+            result,
+            Err(MalformedPlaylistError::Parse(PlaylistParseError::Eof))
+        ));
+    }
+
+    #[test]
+    fn a_playlist_without_an_end_is_still_processing() {
+        let playlist = TAGGED_PLAYLIST.replace("#EXT-X-ENDLIST\n", "");
+        let result = parse_playlist(playlist);
+        assert!(matches!(
+            result,
+            Err(MalformedPlaylistError::StillProcessing { segments: 3 })
+        ));
+    }
+
+    /// A media playlist of a highlight, whose segments are not named by their index.
+    const HIGHLIGHT_PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:10
+#ID3-EQUIV-TDTG:2023-11-02T18:04:11
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-TWITCH-ELAPSED-SECS:0.000
+#EXT-X-TWITCH-TOTAL-SECS:27.433
+#EXTINF:10.000,
+highlight-1964257281-0.ts
+#EXTINF:10.000,
+highlight-1964257281-1-muted.ts
+#EXTINF:7.433,
+highlight-1964257281-2.ts
+#EXT-X-ENDLIST
+";
+
+    /// A media playlist of an uploaded video, whose segments have hashed names.
+    const UPLOAD_PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:4
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-MEDIA-SEQUENCE:0
+#EXTINF:4.000,
+f9c1d1e0a3b14e02b0c9d6b7e35a28c4-00042.ts
+#EXTINF:4.000,
+0b2a7d35e6c94f08a1c3e5d7f9b1a2c3-00043.ts
+#EXTINF:2.120,
+7d1e4a9c2b3f4e5d8c6b7a0f1e2d3c4b-00044.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn keeps_the_segments_of_a_highlight_in_playlist_order() {
+        let playlist = parse_playlist(HIGHLIGHT_PLAYLIST.to_string()).unwrap();
+        let parts: Vec<(String, f32)> = playlist
+            .parts
+            .iter()
+            .map(|part| (part.file_name(), part.duration))
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ("highlight-1964257281-0.ts".to_string(), 10.0),
+                ("highlight-1964257281-1-muted.ts".to_string(), 10.0),
+                ("highlight-1964257281-2.ts".to_string(), 7.433),
+            ]
+        );
+        assert_eq!(
+            playlist.parts[1].unmuted_url("https://example.com/highlight/"),
+            "https://example.com/highlight/highlight-1964257281-1.ts"
+        );
+    }
+
+    #[test]
+    fn keeps_the_segments_of_an_upload_in_playlist_order() {
+        let playlist = parse_playlist(UPLOAD_PLAYLIST.to_string()).unwrap();
+        let names: Vec<String> = playlist.parts.iter().map(PlaylistPart::file_name).collect();
+        assert_eq!(
+            names,
+            [
+                "f9c1d1e0a3b14e02b0c9d6b7e35a28c4-00042.ts",
+                "0b2a7d35e6c94f08a1c3e5d7f9b1a2c3-00043.ts",
+                "7d1e4a9c2b3f4e5d8c6b7a0f1e2d3c4b-00044.ts",
+            ]
+        );
+        assert_eq!(playlist.age, None);
+    }
+
+    #[test]
+    fn the_base_url_of_a_playlist_without_a_query() {
+        let base_url = playlist_base_url(
+            "https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/chunked/index-dvr.m3u8",
+        )
+        .unwrap();
+        assert_eq!(
+            base_url,
+            "https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/chunked/"
+        );
+        assert_eq!(
+            part_url(&base_url, "12-muted.ts"),
+            "https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/chunked/12-muted.ts"
+        );
+    }
+
+    #[test]
+    fn the_query_of_a_playlist_is_kept_for_the_parts() {
+        let base_url = playlist_base_url(
+            "https://vod-secure.twitch.tv/abc123_somestreamer_41234567890_1696721609/chunked/index-dvr.m3u8?sig=0123abcd&token=%7B%22vod_id%22%3A1%7D",
+        )
+        .unwrap();
+        assert_eq!(
+            base_url,
+            "https://vod-secure.twitch.tv/abc123_somestreamer_41234567890_1696721609/chunked/?sig=0123abcd&token=%7B%22vod_id%22%3A1%7D"
         );
-        Ok(qualties
-            .get(highest_quality.as_str())
-            .ok_or(MalformedPlaylistError::NoQualities)?
-            .to_string())
+        assert_eq!(
+            part_url(&base_url, "0.ts"),
+            "https://vod-secure.twitch.tv/abc123_somestreamer_41234567890_1696721609/chunked/0.ts?sig=0123abcd&token=%7B%22vod_id%22%3A1%7D"
+        );
+        // a part with its own query keeps only that one
+        assert_eq!(
+            part_url(&base_url, "0.ts?start=5"),
+            "https://vod-secure.twitch.tv/abc123_somestreamer_41234567890_1696721609/chunked/0.ts?start=5"
+        );
+    }
+
+    #[test]
+    fn the_query_of_a_part_is_not_in_its_file_name() {
+        let part = PlaylistPart {
+            uri: "0.ts?start=5".to_string(),
+            duration: 10.0,
+            byte_range: None,
+        };
+        assert_eq!(part.file_name(), "0.ts");
+    }
+
+    #[test]
+    fn an_invalid_playlist_url_has_no_base_url() {
+        assert!(matches!(
+            playlist_base_url("index-dvr.m3u8"),
+            Err(MalformedPlaylistError::InvalidUrl)
+        ));
+    }
+
+    /// A master playlist as usher sends it for a normal VOD.
+    const MASTER_PLAYLIST: &str = r#"#EXTM3U
+#EXT-X-TWITCH-INFO:ORIGIN="s3",B="false",REGION="EU",USER-IP="203.0.113.7",SERVING-ID="3c0d5f6e1a2b4c8d9e0f1a2b3c4d5e6f",CLUSTER="cloudfront_vod",USER-COUNTRY="DE",MANIFEST-CLUSTER="cloudfront_vod"
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="chunked",NAME="1080p60",AUTOSELECT=NO,DEFAULT=NO
+#EXT-X-STREAM-INF:BANDWIDTH=8534030,CODECS="avc1.64002A,mp4a.40.2",RESOLUTION=1920x1080,VIDEO="chunked",FRAME-RATE=60.000
+https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/chunked/index-dvr.m3u8
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="720p60",NAME="720p60",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=3422999,CODECS="avc1.4D401F,mp4a.40.2",RESOLUTION=1280x720,VIDEO="720p60",FRAME-RATE=60.000
+https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/720p60/index-dvr.m3u8
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="480p30",NAME="480p",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=1427999,CODECS="avc1.4D401F,mp4a.40.2",RESOLUTION=852x480,VIDEO="480p30",FRAME-RATE=30.000
+https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/480p30/index-dvr.m3u8
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="360p30",NAME="360p",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=630000,CODECS="avc1.4D401F,mp4a.40.2",RESOLUTION=640x360,VIDEO="360p30",FRAME-RATE=30.000
+https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/360p30/index-dvr.m3u8
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="160p30",NAME="160p",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=230000,CODECS="avc1.4D400C,mp4a.40.2",RESOLUTION=284x160,VIDEO="160p30",FRAME-RATE=30.000
+https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/160p30/index-dvr.m3u8
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="audio_only",NAME="Audio Only",AUTOSELECT=NO,DEFAULT=NO
+#EXT-X-STREAM-INF:BANDWIDTH=160000,CODECS="mp4a.40.2",VIDEO="audio_only"
+https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/audio_only/index-dvr.m3u8
+"#;
+
+    /// A newer master playlist, that lists all renditions before the streams
+    /// and names the source `1080p60 (source)`.
+    const GROUPED_MASTER_PLAYLIST: &str = r#"#EXTM3U
+#EXT-X-TWITCH-INFO:ORIGIN="s3",B="false",REGION="NA",USER-IP="198.51.100.23",SERVING-ID="0a1b2c3d4e5f60718293a4b5c6d7e8f9",CLUSTER="cloudfront_vod",USER-COUNTRY="US",MANIFEST-CLUSTER="cloudfront_vod"
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="chunked",NAME="1080p60 (source)",AUTOSELECT=NO,DEFAULT=NO
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="936p60",NAME="936p60",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="480p30",NAME="480p",AUTOSELECT=YES,DEFAULT=YES
+
+#EXT-X-STREAM-INF:BANDWIDTH=4200000,CODECS="avc1.4D401F,mp4a.40.2",RESOLUTION=1664x936,VIDEO="936p60",FRAME-RATE=60.000
+https://d1m7jfoe9zdc1j.cloudfront.net/def456_otherstreamer_40987654321_1700000000/936p60/index-dvr.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=6500000,CODECS="avc1.64002A,mp4a.40.2",RESOLUTION=1920x1080,VIDEO="chunked",FRAME-RATE=60.000
+https://d1m7jfoe9zdc1j.cloudfront.net/def456_otherstreamer_40987654321_1700000000/chunked/index-dvr.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1427999,CODECS="avc1.4D401F,mp4a.40.2",RESOLUTION=852x480,VIDEO="480p30",FRAME-RATE=30.000
+https://d1m7jfoe9zdc1j.cloudfront.net/def456_otherstreamer_40987654321_1700000000/480p30/index-dvr.m3u8
+"#;
+
+    #[test]
+    fn parses_every_quality_of_a_master_playlist() {
+        let qualities = parse_master_playlist(MASTER_PLAYLIST).unwrap();
+        let names: Vec<&str> = qualities
+            .iter()
+            .map(|quality| quality.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["1080p60", "720p60", "480p", "360p", "160p", "Audio Only"]
+        );
+        assert_eq!(
+            qualities[0],
+            QualityInfo {
+                name: "1080p60".to_string(),
+                resolution: Some((1920, 1080)),
+                frame_rate: Some(60.0),
+                bandwidth: Some(8534030),
+                url: "https://d2nvs31859zcd8.cloudfront.net/abc123_somestreamer_41234567890_1696721609/chunked/index-dvr.m3u8".to_string(),
+            }
+        );
+        assert_eq!(qualities[2].fps(), Some(30.0));
+        let audio = &qualities[5];
+        assert_eq!(audio.resolution, None);
+        assert_eq!(audio.frame_rate, None);
+        assert!(audio.url.ends_with("/audio_only/index-dvr.m3u8"));
+    }
+
+    #[test]
+    fn pairs_the_streams_with_their_names_in_any_order() {
+        let qualities = parse_master_playlist(GROUPED_MASTER_PLAYLIST).unwrap();
+        let streams: Vec<(&str, &str)> = qualities
+            .iter()
+            .map(|quality| {
+                let group = quality.url.rsplit('/').nth(1).unwrap();
+                (quality.name.as_str(), group)
+            })
+            .collect();
+        assert_eq!(
+            streams,
+            [
+                ("936p60", "936p60"),
+                ("1080p60 (source)", "chunked"),
+                ("480p", "480p30")
+            ]
+        );
+    }
+
+    #[test]
+    fn a_master_playlist_without_streams_has_no_qualities() {
+        let result = parse_master_playlist("#EXTM3U\n#EXT-X-TWITCH-INFO:ORIGIN=\"s3\"\n");
+        assert!(matches!(result, Err(MalformedPlaylistError::NoQualities)));
+    }
+
+    #[test]
+    fn a_stream_without_an_uri_is_an_error() {
+        let result = parse_master_playlist(
+            "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=230000,RESOLUTION=284x160,VIDEO=\"160p30\"\n",
+        );
+        assert!(matches!(result, Err(MalformedPlaylistError::MissingUri)));
+    }
+
+    #[test]
+    fn a_master_playlist_needs_the_extm3u_header() {
+        let result = parse_master_playlist("<html><body>502 Bad Gateway</body></html>");
+        assert!(matches!(
+            result,
+            Err(MalformedPlaylistError::NotAPlaylist { start }) if start.starts_with("<html>")
+        ));
+    }
+
+    #[test]
+    fn an_usher_error_is_restricted() {
+        let result = parse_master_playlist(
+            r#"[{"url":"https://usher.ttvnw.net/vod/1","error":"Manifest is restricted","type":"error","error_code":"vod_manifest_restricted"}]"#,
+        );
+        assert!(matches!(
+            result,
+            Err(MalformedPlaylistError::Restricted { code, message })
+                if code == "vod_manifest_restricted" && message == "Manifest is restricted"
+        ));
+    }
+
+    /// The name of the quality that is selected for the preferences.
+    fn selected(playlist: &str, preferences: &[&str]) -> String {
+        let qualities = parse_master_playlist(playlist).unwrap();
+        let preferences: Vec<String> = preferences.iter().map(|p| p.to_string()).collect();
+        select_quality(&qualities, &preferences)
+            .unwrap()
+            .name
+            .clone()
+    }
+
+    #[test]
+    fn max_selects_the_quality_with_the_highest_bandwidth() {
+        assert_eq!(selected(MASTER_PLAYLIST, &["max"]), "1080p60");
+        // the source is not listed first here
+        assert_eq!(
+            selected(GROUPED_MASTER_PLAYLIST, &["max"]),
+            "1080p60 (source)"
+        );
+    }
+
+    #[test]
+    fn an_exact_name_is_selected() {
+        assert_eq!(selected(MASTER_PLAYLIST, &["720p60"]), "720p60");
+        assert_eq!(selected(MASTER_PLAYLIST, &["480p"]), "480p");
+        assert_eq!(selected(MASTER_PLAYLIST, &["Audio Only"]), "Audio Only");
+    }
+
+    #[test]
+    fn a_quality_is_matched_by_its_resolution_and_frame_rate() {
+        assert_eq!(selected(MASTER_PLAYLIST, &["1080p"]), "1080p60");
+        assert_eq!(selected(MASTER_PLAYLIST, &["720p"]), "720p60");
+        assert_eq!(selected(MASTER_PLAYLIST, &["160p30"]), "160p");
+        assert_eq!(
+            selected(GROUPED_MASTER_PLAYLIST, &["1080p60"]),
+            "1080p60 (source)"
+        );
+        assert_eq!(selected(GROUPED_MASTER_PLAYLIST, &["936p"]), "936p60");
+    }
+
+    #[test]
+    fn a_missing_resolution_selects_the_next_lower_one() {
+        assert_eq!(selected(MASTER_PLAYLIST, &["936p60"]), "720p60");
+        assert_eq!(selected(MASTER_PLAYLIST, &["1440p60"]), "1080p60");
+        assert_eq!(selected(GROUPED_MASTER_PLAYLIST, &["720p60"]), "480p");
+        // nothing is lower, so the lowest is the closest
+        assert_eq!(selected(MASTER_PLAYLIST, &["144p"]), "160p");
+    }
+
+    #[test]
+    fn at_most_never_selects_a_higher_resolution() {
+        assert_eq!(selected(MASTER_PLAYLIST, &["<=720p"]), "720p60");
+        assert_eq!(selected(GROUPED_MASTER_PLAYLIST, &["<=900p"]), "480p");
+        assert_eq!(selected(MASTER_PLAYLIST, &["<=144p", "360p"]), "360p");
+    }
+
+    #[test]
+    fn the_highest_quality_is_used_if_nothing_matches() {
+        assert_eq!(selected(MASTER_PLAYLIST, &["<=144p"]), "1080p60");
+        assert_eq!(selected(MASTER_PLAYLIST, &["source"]), "1080p60");
+        assert_eq!(selected(MASTER_PLAYLIST, &[]), "1080p60");
     }
 }
@@ -0,0 +1,177 @@
+//! Downloads a whole video from a fake twitch: the access token from GQL, the
+//! master playlist from usher and the media playlist and its parts from the CDN.
+//!
+//! The parts are generated and combined with ffmpeg, so the test is skipped
+//! if ffmpeg is not installed.
+use std::path::Path;
+use std::process::Command;
+use tokio_util::sync::CancellationToken;
+use twba_backup_config::get_default_builder;
+use twba_downloader::twitch::DownloadOptions;
+use twba_downloader::{DownloaderConfig, TwitchClient};
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const VIDEO_ID: &str = "123456789";
+const PLAYLIST_PATH: &str = "/abc_123456789/chunked";
+
+fn has_ffmpeg() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Generates the parts of a short test video and returns their content and
+/// duration in order.
+fn generate_parts(folder: &Path) -> Vec<(Vec<u8>, f32)> {
+    let status = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args([
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=duration=6:size=160x120:rate=10",
+        ])
+        .args(["-c:v", "mpeg4", "-g", "10"])
+        .args(["-f", "hls", "-hls_time", "2", "-hls_list_size", "0"])
+        .arg("-hls_segment_filename")
+        .arg(folder.join("%d.ts"))
+        .arg(folder.join("generated.m3u8"))
+        .status()
+        .unwrap();
+    assert!(status.success(), "ffmpeg could not generate the parts");
+    let playlist = std::fs::read_to_string(folder.join("generated.m3u8")).unwrap();
+    let mut parts = vec![];
+    let mut lines = playlist.lines();
+    while let Some(line) = lines.next() {
+        if let Some(duration) = line.strip_prefix("#EXTINF:") {
+            let uri = lines.next().unwrap();
+            let duration = duration.trim_end_matches(',').parse().unwrap();
+            parts.push((std::fs::read(folder.join(uri)).unwrap(), duration));
+        }
+    }
+    parts
+}
+
+fn master_playlist(server: &MockServer) -> String {
+    format!(
+        "#EXTM3U\n\
+         #EXT-X-TWITCH-INFO:ORIGIN=\"s3\",B=\"false\",REGION=\"EU\",USER-IP=\"127.0.0.1\"\n\
+         #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"120p10 (source)\",AUTOSELECT=YES,DEFAULT=YES\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=100000,RESOLUTION=160x120,VIDEO=\"chunked\",FRAME-RATE=10.000\n\
+         {}{}/index-dvr.m3u8\n",
+        server.uri(),
+        PLAYLIST_PATH
+    )
+}
+
+/// A media playlist like twitch sends it, with the uris of the parts.
+///
+/// The stream is from just now, so the muted parts are tried unmuted first.
+fn media_playlist(parts: &[(&str, f32)]) -> String {
+    let now = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S");
+    let mut playlist = format!(
+        "#EXTM3U\n\
+         #EXT-X-VERSION:3\n\
+         #EXT-X-TARGETDURATION:2\n\
+         #ID3-EQUIV-TDTG:{}\n\
+         #EXT-X-PLAYLIST-TYPE:EVENT\n\
+         #EXT-X-MEDIA-SEQUENCE:0\n\
+         #EXT-X-TWITCH-ELAPSED-SECS:0.000\n",
+        now
+    );
+    for (uri, duration) in parts {
+        playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", duration, uri));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+#[tokio::test]
+async fn downloads_a_video_from_twitch() {
+    if !has_ffmpeg() {
+        eprintln!("ffmpeg is not installed, skipping the test");
+        return;
+    }
+    let fixtures = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let parts = generate_parts(fixtures.path());
+    assert_eq!(parts.len(), 3);
+    let uris = ["0.ts", "1-muted.ts", "2.ts"];
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(body_string_contains(VIDEO_ID))
+        .respond_with(ResponseTemplate::new(200).set_body_string(
+            r#"{"data":{"videoPlaybackAccessToken":{"value":"{\"vod_id\":123456789}","signature":"abcdef"}}}"#,
+        ))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/vod/{}", VIDEO_ID)))
+        .respond_with(ResponseTemplate::new(200).set_body_string(master_playlist(&server)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let listed: Vec<(&str, f32)> = uris
+        .iter()
+        .zip(&parts)
+        .map(|(uri, (_, duration))| (*uri, *duration))
+        .collect();
+    Mock::given(method("GET"))
+        .and(path(format!("{}/index-dvr.m3u8", PLAYLIST_PATH)))
+        .respond_with(ResponseTemplate::new(200).set_body_string(media_playlist(&listed)))
+        .mount(&server)
+        .await;
+    for (uri, (content, _)) in uris.iter().zip(&parts) {
+        Mock::given(method("GET"))
+            .and(path(format!("{}/{}", PLAYLIST_PATH, uri)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(content.clone()))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+    // the part was not unmuted by twitch, so only the muted one is there
+    Mock::given(method("GET"))
+        .and(path(format!("{}/1.ts", PLAYLIST_PATH)))
+        .respond_with(ResponseTemplate::new(403))
+        .expect(1..)
+        .mount(&server)
+        .await;
+
+    let conf = get_default_builder().load().unwrap();
+    let mut settings = DownloaderConfig::default();
+    settings.min_free_space_bytes = 0;
+    settings.twitch.gql_url = format!("{}/gql", server.uri());
+    settings.twitch.usher_url = server.uri();
+    settings.twitch.api_requests_per_minute = 0;
+    settings.twitch.min_part_size_bytes = 0;
+    settings.twitch.retry_initial_delay_ms = 10;
+    let parts_folder = settings.parts_folder(output.path(), "1");
+    let client = TwitchClient::new(conf, settings).unwrap();
+
+    let downloaded = client
+        .download_video(
+            "1",
+            VIDEO_ID,
+            "max",
+            output.path(),
+            &CancellationToken::new(),
+            DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(downloaded.path, output.path().join("1.mp4"));
+    assert!(downloaded.path.is_file());
+    assert_eq!(downloaded.info.quality, "120p10 (source)");
+    assert_eq!(downloaded.info.part_count, 3);
+    assert!(downloaded.gaps.is_empty());
+    assert!(downloaded.chunks.is_empty());
+    let measured = downloaded.measured_duration.unwrap();
+    assert!((measured - 6.0).abs() < 0.5, "measured {}s", measured);
+    assert!(!parts_folder.exists());
+}